@@ -0,0 +1,122 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cache::sharded_cache::ShardedCache;
+use cache::key::Key;
+use cache::value::Value;
+use cache::storage_structure::CacheStorageStructure;
+use cache::replacement_policy::CacheReplacementPolicy;
+
+const KEYS: usize = 10000;
+const DURATION: Duration = Duration::from_secs(2);
+
+/**
+ * Measure set throughput while preloading the cache, the memory overhead per item once loaded, and
+ * get throughput with 1, 2, 4, ... up to `max_threads` threads reading random keys, and once more
+ * with `max_threads` readers while another thread keeps setting keys.  Running it with
+ * `--shards 1` gives the single lock baseline to compare with, and with different `--storage`
+ * structures compares those.
+ */
+pub fn run<T, R>(cache: &ShardedCache<T, R>, max_threads: usize)
+    where T: CacheStorageStructure + Send + Sync, R: CacheReplacementPolicy + Send + Sync
+{
+    let start = Instant::now();
+    for i in 0..KEYS {
+        let key = Key::new(format!("key:{}", i));
+        let _ = cache.shard(&key).set(key, Value::new(format!("value:{}", i)));
+    }
+    let elapsed = start.elapsed();
+
+    let (mut bytes, mut payload_bytes, mut items) = (0, 0, 0);
+    for shard in cache.lock_all() {
+        bytes += shard.storage_structure.memory_usage() + shard.replacement_policy.memory_usage();
+        payload_bytes += shard.storage_structure.size();
+        items += shard.storage_structure.indices().len();
+    }
+
+    println!("{} shards, {} keys", cache.shard_count(), KEYS);
+    println!("{:>12.0} sets/s", KEYS as f64 / seconds(elapsed));
+    println!("{:>12} bytes for {} items, {} bytes of overhead per item", bytes, items, (bytes - payload_bytes) / items.max(1));
+
+    let mut threads = 1;
+    loop {
+        let (gets, _) = measure(cache, threads, false);
+        println!("{:>3} threads {:>12.0} gets/s", threads, gets);
+
+        if threads >= max_threads {
+            break;
+        }
+        threads = (threads * 2).min(max_threads);
+    }
+
+    let (gets, sets) = measure(cache, max_threads, true);
+    println!("{:>3} threads {:>12.0} gets/s while a writer sets {:.0} keys/s", max_threads, gets, sets);
+}
+
+/**
+ * Gets per second with `threads` threads reading random keys, and sets per second of one more
+ * thread overwriting random keys meanwhile if `writer` is true
+ */
+fn measure<T, R>(cache: &ShardedCache<T, R>, threads: usize, writer: bool) -> (f64, f64)
+    where T: CacheStorageStructure + Send + Sync, R: CacheReplacementPolicy + Send + Sync
+{
+    let ops = AtomicUsize::new(0);
+    let sets = AtomicUsize::new(0);
+    let done = AtomicBool::new(false);
+
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for id in 0..threads {
+            let (ops, done) = (&ops, &done);
+            scope.spawn(move || {
+                let mut random = random(id);
+                let mut count = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let key = Key::new(format!("key:{}", random() % KEYS));
+                    let _ = cache.get(key);
+                    count += 1;
+                }
+                ops.fetch_add(count, Ordering::Relaxed);
+            });
+        }
+
+        if writer {
+            let (sets, done) = (&sets, &done);
+            scope.spawn(move || {
+                let mut random = random(threads);
+                let mut count = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let i = random() % KEYS;
+                    let key = Key::new(format!("key:{}", i));
+                    let _ = cache.shard(&key).set(key, Value::new(format!("value:{}", i)));
+                    count += 1;
+                }
+                sets.fetch_add(count, Ordering::Relaxed);
+            });
+        }
+
+        thread::sleep(DURATION);
+        done.store(true, Ordering::Relaxed);
+    });
+    let elapsed = seconds(start.elapsed());
+
+    (ops.load(Ordering::Relaxed) as f64 / elapsed, sets.load(Ordering::Relaxed) as f64 / elapsed)
+}
+
+/**
+ * A xorshift generator for thread `id`, cheap enough not to dominate the measurement
+ */
+fn random(id: usize) -> impl FnMut() -> usize {
+    let mut seed = 0x9e3779b97f4a7c15u64 ^ (id as u64 + 1);
+    move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed as usize
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
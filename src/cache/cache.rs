@@ -1,9 +1,21 @@
 use cache::key::Key;
 use cache::value::Value;
 use cache::data_entry::DataEntry;
-use cache::storage_structure::CacheStorageStructure;
+use cache::storage_structure::{CacheStorageStructure, StorageReader};
 use cache::replacement_policy::CacheReplacementPolicy;
 use cache::error::CacheError;
+use cache::slab::SlabAllocator;
+use cache::read_buffer::{Access, ReadBuffer};
+use cache::time;
+use cache::extstore::ExtStore;
+use cache::compression::{Compressor, DATA_TYPE_RAW};
+use cache::lease::{Leases, LeaseRead};
+use cache::tags::TagIndex;
+use cache::prefix::PrefixFlushes;
+use cache::watch::{Watchers, EventKind};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub struct CacheMetrics {
     pub evictions: u64,
@@ -13,16 +25,104 @@ pub struct CacheMetrics {
     pub miss_count_get: u64,
     pub miss_count_set: u64,
     pub miss_count_delete: u64,
+    pub rejections: u64,
+    pub leases_granted: u64,
+    pub lease_waits: u64,
+    pub lease_stale_hits: u64,
+    pub lease_rejections: u64,
+    pub stale_hits: u64,
+    pub revalidations: u64,
+    pub tag_invalidations: u64,
+    pub prefix_flushes: u64,
+    pub prefix_reclaimed: u64,
+}
+
+/**
+ * A key found by a scan together with its metadata
+ */
+pub struct ScanItem {
+    pub key: String,
+    pub size: usize,
+    pub ttl: Option<u64>,
+    pub last_access: u64,
+}
+
+/**
+ * Serves gets of a cache without its lock when the storage structure can be read without it, see
+ * `Cache::optimistic_reader`.  The accesses go into the read buffer of the cache like those of
+ * `Cache::read`.
+ */
+pub struct OptimisticReader {
+    storage: StorageReader,
+    reads: Arc<ReadBuffer>,
+    prefixes_flushed: Arc<AtomicBool>,
+}
+
+pub enum OptimisticRead {
+    Found(DataEntry),
+    Missing,
+    NeedsLock,
+}
+
+impl OptimisticReader {
+    /**
+     * Look up a key like `Cache::read`.  Entries that take more than the storage structure to
+     * serve, values in the disk tier or compressed ones, and every key while flushed prefixes are
+     * pending are left for the cache to read under its lock.
+     */
+    pub fn read(&self, key: &Key) -> OptimisticRead {
+        if self.prefixes_flushed.load(Ordering::Acquire) {
+            return OptimisticRead::NeedsLock;
+        }
+
+        let now = time::now();
+        match self.storage.get(key) {
+            Some((_, ref entry)) if entry.location.is_some() || entry.data_type != DATA_TYPE_RAW => OptimisticRead::NeedsLock,
+            Some((index, entry)) if !entry.is_expired(now) => {
+                record_hit(&self.reads, index, entry.len(), &entry, now);
+                OptimisticRead::Found(entry)
+            },
+            _ => {
+                self.reads.misses.fetch_add(1, Ordering::Relaxed);
+                OptimisticRead::Missing
+            }
+        }
+    }
+
+    pub fn needs_drain(&self) -> bool {
+        self.reads.needs_drain()
+    }
+}
+
+fn record_hit(reads: &ReadBuffer, index: usize, size: usize, entry: &DataEntry, now: u64) {
+    reads.record(Access {
+        index,
+        key: entry.key.clone(),
+        size,
+        cost: entry.cost,
+        time: now,
+    });
+    reads.hits.fetch_add(1, Ordering::Relaxed);
 }
 
 pub struct Cache<T, R> {
     pub capacity: usize,
-    pub item_lifetime: u64,
+    pub item_lifetime: u64, // Default ttl in seconds for values set without one, 0 never expires
+    pub stale_grace: u64, // Seconds expired entries are still served as stale while they are recomputed
     pub max_key_len: usize,
     pub max_val_len: usize,
     pub storage_structure: T,
     pub replacement_policy: R,
+    pub slabs: Option<SlabAllocator>,
     pub metrics: CacheMetrics,
+    pub reads: Arc<ReadBuffer>,
+    pub ext: Option<Arc<ExtStore>>, // Disk tier for large values evicted from memory
+    pub compressor: Option<Arc<Compressor>>, // Compresses large values as they are stored
+    pub leases: Leases, // Leases handed out on misses to fill the missing keys
+    pub tags: TagIndex, // Keys of the entries carrying each tag
+    pub prefixes: PrefixFlushes, // Prefixes flushed since the entries under them were last reclaimed
+    pub watchers: Arc<Watchers>, // Connections watching the keys of the cache change
+    pub policy: String, // Name of the replacement policy, saved so that it survives a restart
 }
 
 impl CacheMetrics {
@@ -35,46 +135,346 @@ impl CacheMetrics {
             miss_count_get: 0,
             miss_count_set: 0,
             miss_count_delete: 0,
+            rejections: 0,
+            leases_granted: 0,
+            lease_waits: 0,
+            lease_stale_hits: 0,
+            lease_rejections: 0,
+            stale_hits: 0,
+            revalidations: 0,
+            tag_invalidations: 0,
+            prefix_flushes: 0,
+            prefix_reclaimed: 0,
         }
     }
+
+    /**
+     * Add the counts of another set of metrics to these
+     */
+    pub fn merge(&mut self, other: &CacheMetrics) {
+        self.evictions += other.evictions;
+        self.hit_count_get += other.hit_count_get;
+        self.hit_count_set += other.hit_count_set;
+        self.hit_count_delete += other.hit_count_delete;
+        self.miss_count_get += other.miss_count_get;
+        self.miss_count_set += other.miss_count_set;
+        self.miss_count_delete += other.miss_count_delete;
+        self.rejections += other.rejections;
+        self.leases_granted += other.leases_granted;
+        self.lease_waits += other.lease_waits;
+        self.lease_stale_hits += other.lease_stale_hits;
+        self.lease_rejections += other.lease_rejections;
+        self.stale_hits += other.stale_hits;
+        self.revalidations += other.revalidations;
+        self.tag_invalidations += other.tag_invalidations;
+        self.prefix_flushes += other.prefix_flushes;
+        self.prefix_reclaimed += other.prefix_reclaimed;
+    }
 }
 
 impl <T: CacheStorageStructure, R: CacheReplacementPolicy> Cache<T, R> {
     pub fn new(capacity: usize, storage_structure: T, replacement_policy: R) -> Cache<T, R> {
         Cache {
                 capacity: capacity,
-                item_lifetime: 60 * 1000,
+                item_lifetime: 0,
+                stale_grace: 0,
                 max_key_len: 256,
                 max_val_len: 512,
                 storage_structure: storage_structure,
                 // replacement_policy: LRU::new(),
                 // replacement_policy: Clock::new(),
                 replacement_policy: replacement_policy,
-                metrics: CacheMetrics::new()
+                slabs: None,
+                metrics: CacheMetrics::new(),
+                reads: Arc::new(ReadBuffer::new()),
+                ext: None,
+                compressor: None,
+                leases: Leases::new(10),
+                tags: TagIndex::new(),
+                prefixes: PrefixFlushes::new(),
+                watchers: Arc::new(Watchers::new(1024)),
+                policy: String::new(),
         }
     }
 
+    /**
+     * A cache whose memory is managed by a slab allocator.  The memory limit of the allocator
+     * replaces the capacity and each item only evicts items from its own slab class.
+     */
+    pub fn with_slabs(slabs: SlabAllocator, storage_structure: T, replacement_policy: R) -> Cache<T, R> {
+        let mut cache = Cache::new(slabs.memory_limit, storage_structure, replacement_policy);
+        cache.slabs = Some(slabs);
+        cache
+    }
+
+    /**
+     * A reader that serves gets without the lock around the cache, if the storage structure can be
+     * read without it
+     */
+    pub fn optimistic_reader(&self) -> Option<OptimisticReader> {
+        self.storage_structure.reader().map(|storage| OptimisticReader {
+            storage,
+            reads: self.reads.clone(),
+            prefixes_flushed: self.prefixes.pending_flag(),
+        })
+    }
+
     pub fn get(&mut self, key: Key) -> Option<DataEntry> {
-        match self.storage_structure.get(key) {
-            Some((index, entry)) => {
-                self.replacement_policy.update(index);
-                self.metrics.hit_count_get += 1;
+        let entry = self.read(key);
+        self.drain_reads();
+        entry
+    }
+
+    /**
+     * Look up a key without exclusive access to the cache.  The access is recorded in the read
+     * buffer and only reaches the replacement policy once the buffer is drained.  Expired entries
+     * are treated as missing and left for eviction or the next set to reclaim.  A value in the
+     * disk tier is read back, and if that fails the entry is treated as missing too.  Compressed
+     * values are handed out decompressed.
+     */
+    pub fn read(&self, key: Key) -> Option<DataEntry> {
+        self.lookup(key, 0)
+    }
+
+    /**
+     * Look up a key like `read`, but still find entries that expired less than `stale_grace`
+     * seconds ago.  Whether the entry found is stale is up to the caller to check.
+     */
+    pub fn read_stale(&self, key: Key) -> Option<DataEntry> {
+        self.lookup(key, self.stale_grace)
+    }
+
+    fn lookup(&self, key: Key, grace: u64) -> Option<DataEntry> {
+        let now = time::now();
+
+        // The policy knows the entry by the size it has in memory, not that of a value read back
+        let found = match self.storage_structure.get(key) {
+            Some((index, entry)) if !entry.is_expired(now.saturating_sub(grace)) && !self.prefixes.is_flushed(&entry) => {
+                let size = entry.len();
+                match (entry.location.is_some(), self.ext.as_ref()) {
+                    (true, Some(ext)) => match self.load_ext(entry) {
+                        Ok(entry) => {
+                            ext.metrics.hits.fetch_add(1, Ordering::Relaxed);
+                            Some((index, size, entry))
+                        },
+                        Err(_) => {
+                            ext.metrics.misses.fetch_add(1, Ordering::Relaxed);
+                            None
+                        }
+                    },
+                    _ => Some((index, size, entry)),
+                }
+            },
+            _ => None,
+        };
+        let found = found.and_then(|(index, size, entry)| self.decompress(entry).ok().map(|entry| (index, size, entry)));
+
+        match found {
+            Some((index, size, entry)) => {
+                record_hit(&self.reads, index, size, &entry, now);
                 Some(entry)
             },
             None => {
-                self.metrics.miss_count_get += 1;
+                self.reads.misses.fetch_add(1, Ordering::Relaxed);
                 None
             }
         }
     }
 
+    /**
+     * The entry with its value as it was set, read back from the disk tier and decompressed
+     */
+    pub fn materialize(&self, entry: DataEntry) -> Result<DataEntry, CacheError> {
+        self.load_ext(entry).and_then(|entry| self.decompress(entry))
+    }
+
+    /**
+     * The entry with its value decompressed if it was stored compressed.  A value that fails to
+     * decompress is treated as missing and its entry left for the next writer to remove.
+     */
+    pub fn decompress(&self, mut entry: DataEntry) -> Result<DataEntry, CacheError> {
+        if entry.data_type == DATA_TYPE_RAW {
+            return Ok(entry);
+        }
+
+        let compressor = match self.compressor {
+            Some(ref compressor) => compressor,
+            None => return Err(CacheError::KeyNotFound),
+        };
+
+        match compressor.decompress(&entry.value, entry.data_type) {
+            Ok(value) => {
+                entry.value = value;
+                entry.data_type = DATA_TYPE_RAW;
+                Ok(entry)
+            },
+            Err(_) => {
+                self.reads.record_corrupt(entry);
+                Err(CacheError::KeyNotFound)
+            }
+        }
+    }
+
+    /**
+     * The entry with its value read back from the disk tier if it was moved there
+     */
+    pub fn load_ext(&self, mut entry: DataEntry) -> Result<DataEntry, CacheError> {
+        let (location, ext) = match (entry.location, self.ext.as_ref()) {
+            (Some(location), Some(ext)) => (location, ext),
+            _ => return Ok(entry),
+        };
+
+        match ext.read(location, &entry.key) {
+            Ok(mut value) => {
+                value.cas = entry.value.cas;
+                entry.value = value;
+                entry.location = None;
+                Ok(entry)
+            },
+            Err(_) => Err(CacheError::KeyNotFound),
+        }
+    }
+
+    /**
+     * Replay the accesses recorded by reads into the replacement policy.  An entry may have been
+     * removed, or its slot reused by another key, since it was read, such accesses are skipped.
+     */
+    pub fn drain_reads(&mut self) {
+        for access in self.reads.drain() {
+            let mut current = false;
+            self.storage_structure.update_index(access.index, &mut |entry| {
+                if entry.key == access.key {
+                    entry.last_access = entry.last_access.max(access.time);
+                    current = true;
+                }
+            });
+            if !current {
+                continue;
+            }
+
+            self.replacement_policy.update(access.index, &access.key, access.size, access.cost);
+            if let Some(ref mut slabs) = self.slabs {
+                slabs.touch(access.index);
+            }
+        }
+
+        // Only remove a corrupt entry if it was not set again since it was read
+        for corrupt in self.reads.drain_corrupt() {
+            if let Some((index, entry)) = self.storage_structure.get(corrupt.key.clone()) {
+                if entry.data_type == corrupt.data_type && entry.value == corrupt.value {
+                    self.replacement_policy.remove(index);
+                    self.remove_evicted(index);
+                }
+            }
+        }
+
+        self.metrics.hit_count_get += self.reads.hits.swap(0, Ordering::Relaxed);
+        self.metrics.miss_count_get += self.reads.misses.swap(0, Ordering::Relaxed);
+    }
+
+    /**
+     * Look up a key, handing out a lease to fill it if it is missing and nobody else holds one
+     */
+    pub fn lease_get(&mut self, key: Key) -> LeaseRead {
+        let now = time::now();
+        let found = self.read_stale(key.clone());
+        self.drain_reads();
+
+        // An entry in its grace period is handed out as stale to those not filling it
+        let expired = match found {
+            Some(entry) => if entry.is_expired(now) { Some(entry) } else { return LeaseRead::Hit(entry) },
+            None => None,
+        };
+
+        match self.leases.acquire(&key) {
+            Ok(token) => {
+                self.metrics.leases_granted += 1;
+                LeaseRead::Granted(token)
+            },
+            Err(stale) => match stale.and_then(|stale| self.decompress(*stale).ok()).or(expired) {
+                Some(stale) => {
+                    self.metrics.lease_stale_hits += 1;
+                    LeaseRead::Stale(stale)
+                },
+                None => {
+                    self.metrics.lease_waits += 1;
+                    LeaseRead::Wait
+                }
+            }
+        }
+    }
+
+    /**
+     * Count a stale entry being served and decide whether the reader is the one to recompute it.
+     * The first reader gets a lease token to set it with, everyone else None until the entry is
+     * set or the lease runs out.
+     */
+    pub fn revalidate(&mut self, key: &Key) -> Option<u64> {
+        self.metrics.stale_hits += 1;
+        match self.leases.acquire(key) {
+            Ok(token) => {
+                self.metrics.revalidations += 1;
+                Some(token)
+            },
+            Err(_) => None,
+        }
+    }
+
+    /**
+     * Store an entry filling a missing key, only if `token` is still a valid lease on the key
+     */
+    pub fn insert_leased(&mut self, entry: DataEntry, token: u64) -> Result<(), CacheError> {
+        if !self.leases.redeem(&entry.key, token) {
+            self.metrics.lease_rejections += 1;
+            return Err(CacheError::LeaseInvalid);
+        }
+        self.insert(entry)
+    }
+
     pub fn set(&mut self, key: Key, value: Value) -> Result<(), CacheError> {
-        let entry = DataEntry::new(key.clone(), value);
+        let ttl = self.item_lifetime;
+        self.insert(DataEntry::new(key, value).expires_in(ttl))
+    }
 
-        // Retrieve the current size of the entry in the cache
-        let current_elem_size = match self.storage_structure.get(key) {
-            Some((_, curr_entry)) => curr_entry.len(),
-            None => 0,
+    /**
+     * Store an entry, replacing any entry with the same key
+     */
+    pub fn insert(&mut self, entry: DataEntry) -> Result<(), CacheError> {
+        self.drain_reads();
+
+        if entry.key.len() > self.max_key_len || entry.tags.iter().any(|tag| tag.len() > self.max_key_len) {
+            return Err(CacheError::KeyTooLarge);
+        }
+
+        if entry.value.len() > self.max_val_len {
+            return Err(CacheError::ValueTooLarge);
+        }
+
+        let mut entry = entry;
+        entry.generation = self.prefixes.generation();
+
+        // The compressed value is what takes up memory and counts against the capacity
+        if let Some(ref compressor) = self.compressor {
+            if entry.data_type == DATA_TYPE_RAW {
+                if let Some((value, data_type)) = compressor.compress(&entry.value) {
+                    entry.value = value;
+                    entry.data_type = data_type;
+                }
+            }
+        }
+
+        let key = entry.key.clone();
+        let cost = entry.cost;
+
+        // Refuse anything that could never fit rather than flushing the cache trying to make room
+        if entry.memory_usage() > self.capacity {
+            return Err(CacheError::ValueTooLarge);
+        }
+
+        // Retrieve the current index and memory usage of the entry in the cache
+        let (current_index, current_elem_size) = match self.storage_structure.get(key.clone()) {
+            Some((index, curr_entry)) => (Some(index), curr_entry.memory_usage()),
+            None => (None, 0),
         };
 
         if current_elem_size == 0 {
@@ -83,51 +483,771 @@ impl <T: CacheStorageStructure, R: CacheReplacementPolicy> Cache<T, R> {
             self.metrics.hit_count_set += 1;
         }
 
-        // Evict until there is sufficient space
-        loop {
-            if self.storage_structure.size() + entry.len() - current_elem_size <= self.capacity {
-                break;
-            }
-
-            try!(self.evict_next());
-            self.metrics.evictions += 1;
+        // A new key that needs space to be made must first be admitted by the replacement policy
+        if current_elem_size == 0 && self.memory_usage() + entry.memory_usage() > self.capacity && !self.replacement_policy.admit(&entry.key) {
+            self.metrics.rejections += 1;
+            return Err(CacheError::AdmissionRejected);
         }
 
+        let chunks = if self.slabs.is_some() {
+            Some(self.reserve_chunk(entry.memory_usage(), current_index)?)
+        } else {
+            // Evict until there is sufficient space
+            loop {
+                if self.memory_usage() + entry.memory_usage() - current_elem_size <= self.capacity {
+                    break;
+                }
+
+                self.make_room()?;
+            }
+            None
+        };
+
         // Set the value in the cache
-        let (index, _) = self.storage_structure.set(entry);
+        let size = entry.len();
+        let tags = entry.tags.clone();
+        let (index, replaced) = self.storage_structure.set(entry);
+        if let Some(replaced) = replaced {
+            self.discard(&replaced);
+        }
+        self.tags.add(&key, &tags);
+        self.leases.release(&key);
+        self.watchers.publish(EventKind::Set, &key);
+        if let (Some(slabs), Some((class, count))) = (self.slabs.as_mut(), chunks) {
+            slabs.insert(index, class, count);
+        }
         // Update replacement policy
-        self.replacement_policy.update(index);
+        self.replacement_policy.update(index, &key, size, cost);
+
+        // The bookkeeping for the new entry may have grown the structures past the capacity
+        if self.slabs.is_none() {
+            while self.memory_usage() > self.capacity && self.make_room().is_ok() {}
+        }
         
         Ok(())
     }
 
     pub fn remove(&mut self, key: Key) {
+        self.drain_reads();
+
+        let leased = key.clone();
         match self.storage_structure.remove(key) {
-            Some((index, _)) => {
+            Some((index, removed)) => {
+                self.discard(&removed);
+                self.watchers.publish(EventKind::Delete, &leased);
+                // Values on disk are gone with their space, only those in memory can be stale
+                let stale = if removed.location.is_none() { Some(removed) } else { None };
+                self.leases.invalidate(&leased, stale);
                 self.replacement_policy.remove(index);
+                if let Some(ref mut slabs) = self.slabs {
+                    slabs.release(index);
+                }
                 self.metrics.hit_count_delete += 1;
             },
             None => {
+                self.leases.invalidate(&leased, None);
                 self.metrics.miss_count_delete += 1;
             },
         };
     }
 
-    pub fn contains(&mut self, key: Key) -> bool {
-        self.storage_structure.contains(key)
+    /**
+     * Remove every entry carrying the tag, returning how many were removed
+     */
+    pub fn invalidate_tag(&mut self, tag: &str) -> usize {
+        let keys = self.tags.keys(tag);
+        for key in keys.iter() {
+            self.remove(key.clone());
+        }
+
+        self.metrics.tag_invalidations += keys.len() as u64;
+        keys.len()
+    }
+
+    /**
+     * Remove every entry
+     */
+    pub fn flush(&mut self) {
+        self.drain_reads();
+        self.leases.clear();
+        self.tags.clear();
+
+        for index in self.storage_structure.indices() {
+            if let Some(removed) = self.remove_index(index) {
+                self.watchers.publish(EventKind::Delete, &removed.key);
+            }
+        }
+        self.prefixes.clear();
+    }
+
+    /**
+     * Remove every entry whose key starts with the prefix.  The entries are only marked as flushed,
+     * they are treated as missing from now on and left for eviction or `reclaim_flushed`.
+     */
+    pub fn flush_prefix(&mut self, prefix: &str) {
+        self.prefixes.flush(prefix);
+        self.leases.clear_prefix(prefix);
+    }
+
+    /**
+     * Remove the entries flushed by prefix that are still in memory, returning how many were
+     * removed
+     */
+    pub fn reclaim_flushed(&mut self) -> usize {
+        if !self.prefixes.pending() {
+            return 0;
+        }
+        self.drain_reads();
+
+        let mut reclaimed = 0;
+        for index in self.storage_structure.indices() {
+            let flushed = match self.storage_structure.get_index(index) {
+                Some((_, entry)) => self.prefixes.is_flushed(&entry),
+                None => false,
+            };
+            if flushed && self.remove_index(index).is_some() {
+                reclaimed += 1;
+            }
+        }
+
+        self.prefixes.clear();
+        self.metrics.prefix_reclaimed += reclaimed as u64;
+        reclaimed
+    }
+
+    /**
+     * Whether the entry was flushed by a prefix of its key after it was stored
+     */
+    pub fn is_flushed(&self, entry: &DataEntry) -> bool {
+        self.prefixes.is_flushed(entry)
     }
 
-    fn evict_next(&mut self) -> Result<(), CacheError> {
+    fn remove_index(&mut self, index: usize) -> Option<DataEntry> {
+        let (_, removed) = self.storage_structure.remove_index(index)?;
+        self.discard(&removed);
+        self.replacement_policy.remove(index);
+        if let Some(ref mut slabs) = self.slabs {
+            slabs.release(index);
+        }
+        Some(removed)
+    }
+
+    /**
+     * Swap in a different replacement policy.  The new policy is seeded with every entry that is
+     * currently cached in the eviction order of the old policy, so the next victims stay the same,
+     * followed by any entries the old policy did not report.  Other access history is not carried
+     * over.
+     */
+    pub fn set_replacement_policy(&mut self, mut replacement_policy: R) {
+        self.drain_reads();
+
+        let mut order = self.replacement_policy.eviction_order();
+        let seeded: HashSet<usize> = order.iter().cloned().collect();
+        order.extend(self.storage_structure.indices().into_iter().filter(|index| !seeded.contains(index)));
+
+        for index in order {
+            if let Some((_, entry)) = self.storage_structure.get_index(index) {
+                replacement_policy.update(index, &entry.key, entry.len(), entry.cost);
+            }
+        }
+
+        self.replacement_policy = replacement_policy;
+    }
+
+    /**
+     * Change the capacity, evicting entries until the cache fits.  With a slab allocator the
+     * memory limit only bounds the pages assigned from now on, pages already assigned stay.
+     */
+    pub fn resize(&mut self, capacity: usize) {
+        self.drain_reads();
+        self.capacity = capacity;
+
+        match self.slabs {
+            Some(ref mut slabs) => slabs.memory_limit = capacity,
+            None => {
+                while self.memory_usage() > self.capacity && self.make_room().is_ok() {}
+            }
+        }
+    }
+
+    /**
+     * Memory used by the cache in bytes: the entries including their headers, and the bookkeeping
+     * of the storage structure, replacement policy, slab allocator, read buffer, tag index and
+     * flushed prefixes, and the leases with their stale values
+     */
+    pub fn memory_usage(&self) -> usize {
+        let slabs = match self.slabs {
+            Some(ref slabs) => slabs.memory_usage(),
+            None => 0,
+        };
+
+        self.storage_structure.memory_usage() + self.replacement_policy.memory_usage() + slabs + self.reads.memory_usage() + self.tags.memory_usage() + self.prefixes.memory_usage() + self.leases.memory_usage()
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        match self.storage_structure.get(key) {
+            Some((_, entry)) => !entry.is_expired(time::now()) && !self.prefixes.is_flushed(&entry),
+            None => false,
+        }
+    }
+
+    /**
+     * Returns up to `limit` live keys starting with `prefix` in key order, resuming after the key
+     * `cursor` if given, along with the cursor to continue from if there may be more keys
+     */
+    pub fn scan(&self, prefix: &str, limit: usize, cursor: Option<&str>) -> (Vec<ScanItem>, Option<String>) {
+        let after = cursor.map(|cursor| Key::new(String::from(cursor)));
+        let entries = self.storage_structure.scan(prefix, after.as_ref(), limit);
+
+        let next = match entries.last() {
+            Some((_, entry)) if entries.len() >= limit => Some(entry.key.item.clone()),
+            _ => None,
+        };
+
+        let now = time::now();
+        let items = entries.into_iter()
+            .filter(|(_, entry)| !entry.is_expired(now) && !self.prefixes.is_flushed(entry))
+            .map(|(_, entry)| ScanItem {
+                size: entry.len(),
+                ttl: entry.ttl(now),
+                last_access: entry.last_access,
+                key: entry.key.item,
+            })
+            .collect();
+
+        (items, next)
+    }
+
+    /**
+     * Reserve the chunks for an item of `size` bytes, evicting the least recently used items of its
+     * slab class until enough are free.  The chunks held by the entry being replaced are only
+     * released once the new chunks are secured so a failed set leaves the old value intact.
+     */
+    fn reserve_chunk(&mut self, size: usize, current_index: Option<usize>) -> Result<(usize, usize), CacheError> {
+        let (class, chunks) = self.slabs.as_ref().unwrap().class_for(size);
+
+        loop {
+            let slabs = self.slabs.as_mut().unwrap();
+            if slabs.reserve(class, chunks) {
+                break;
+            }
+
+            match slabs.victim(class) {
+                Some(index) => {
+                    if let Some((_, removed)) = self.storage_structure.remove_index(index) {
+                        self.tags.remove(&removed.key, &removed.tags);
+                        self.watchers.publish(evicted(&removed), &removed.key);
+                    }
+                    self.replacement_policy.remove(index);
+                    slabs.release(index);
+                    slabs.classes[class].evictions += 1;
+                    self.metrics.evictions += 1;
+                },
+                None => return Err(CacheError::OutOfMemory),
+            }
+        }
+
+        if let (Some(slabs), Some(index)) = (self.slabs.as_mut(), current_index) {
+            slabs.release(index);
+        }
+
+        Ok((class, chunks))
+    }
+
+    /**
+     * Move a page of memory from slab class `src` to `dst`, evicting the least recently used items
+     * of `src` until a whole page of its chunks is free
+     */
+    pub fn reassign_slab(&mut self, src: usize, dst: usize) -> Result<(), CacheError> {
+        self.drain_reads();
+
+        {
+            let slabs = match self.slabs {
+                Some(ref slabs) => slabs,
+                None => return Err(CacheError::BadSlabClass),
+            };
+
+            if src == dst || src >= slabs.classes.len() || dst >= slabs.classes.len() || slabs.classes[src].pages == 0 {
+                return Err(CacheError::BadSlabClass);
+            }
+        }
+
+        loop {
+            let slabs = self.slabs.as_mut().unwrap();
+            if slabs.move_page(src, dst) {
+                return Ok(());
+            }
+
+            match slabs.victim(src) {
+                Some(index) => {
+                    if let Some((_, removed)) = self.storage_structure.remove_index(index) {
+                        self.tags.remove(&removed.key, &removed.tags);
+                        self.watchers.publish(evicted(&removed), &removed.key);
+                    }
+                    self.replacement_policy.remove(index);
+                    slabs.release(index);
+                    slabs.classes[src].evictions += 1;
+                    self.metrics.evictions += 1;
+                },
+                None => return Err(CacheError::EvictionFailure),
+            }
+        }
+    }
+
+    /**
+     * Run one round of slab automove, moving at most a single page
+     */
+    pub fn slab_automove(&mut self) {
+        let candidate = match self.slabs {
+            Some(ref mut slabs) => slabs.automove_candidate(),
+            None => None,
+        };
+
+        if let Some((src, dst)) = candidate {
+            let _ = self.reassign_slab(src, dst);
+        }
+    }
+
+    /**
+     * Free some memory, shedding the oldest stale value kept for a lease before evicting an entry
+     */
+    fn make_room(&mut self) -> Result<(), CacheError> {
+        if self.leases.shed_stale() {
+            return Ok(());
+        }
+
+        // A value moved to the disk tier is counted in the tier's metrics, not as an eviction
+        if let Eviction::Removed = self.evict_next()? {
+            self.metrics.evictions += 1;
+        }
+        Ok(())
+    }
+
+    fn evict_next(&mut self) -> Result<Eviction, CacheError> {
         // Disasociate the index from the replacement policy
-        match self.replacement_policy.evict_next() {
-            Ok(evict_index) => {
-                // Remove the index from the cache
-                match self.storage_structure.remove_index(evict_index) {
-                    Some((_, _)) => Ok(()),
-                    None => Err(CacheError::EvictionFailure)
+        let evict_index = self.replacement_policy.evict_next()?;
+
+        let entry = match self.storage_structure.get_index(evict_index) {
+            Some((_, entry)) => entry,
+            None => return Err(CacheError::EvictionFailure),
+        };
+
+        // A large value moves to the disk tier, the entry stays with only its key and the location
+        // of the value and competes for memory like any other entry
+        if let Some(ext) = self.ext.clone() {
+            if entry.location.is_none() && entry.value.len() >= ext.min_item_size && !entry.is_expired(time::now()) && !self.prefixes.is_flushed(&entry) {
+                if let Ok(location) = ext.write(&entry.key, &entry.value) {
+                    let (key, cost) = (entry.key.clone(), entry.cost);
+
+                    let mut value = Value::from_bytes(Vec::new());
+                    value.cas = entry.value.cas;
+                    let mut stub = entry;
+                    stub.value = value;
+                    stub.location = Some(location);
+
+                    let size = stub.len();
+                    let (index, _) = self.storage_structure.set(stub);
+                    self.replacement_policy.update(index, &key, size, cost);
+                    return Ok(Eviction::Moved);
                 }
-            },
-            Err(err) => Err(err)
+            }
+        }
+
+        // Remove the index from the cache
+        if self.remove_evicted(evict_index) {
+            Ok(Eviction::Removed)
+        } else {
+            Err(CacheError::EvictionFailure)
+        }
+    }
+
+    /**
+     * Remove the entry at an index that was evicted, releasing everything it held and publishing the
+     * eviction to watchers.  The index must already be out of the replacement policy.
+     */
+    fn remove_evicted(&mut self, index: usize) -> bool {
+        let removed = match self.storage_structure.remove_index(index) {
+            Some((_, removed)) => removed,
+            None => return false,
+        };
+
+        if let Some(slabs) = self.slabs.as_mut() {
+            slabs.release(index);
+        }
+        self.discard(&removed);
+        self.watchers.publish(evicted(&removed), &removed.key);
+        true
+    }
+
+    /**
+     * Let go of what an entry that left the storage structure still holds elsewhere: its value in
+     * the disk tier and its keys in the tag index
+     */
+    fn discard(&mut self, entry: &DataEntry) {
+        self.release_ext(entry);
+        self.tags.remove(&entry.key, &entry.tags);
+    }
+
+    fn release_ext(&self, entry: &DataEntry) {
+        if let (Some(location), Some(ext)) = (entry.location, self.ext.as_ref()) {
+            ext.release(location);
+        }
+    }
+
+    /**
+     * Copy the values this shard has on a page of the disk tier to the current page, so that the
+     * page can be removed.  Entries whose value cannot be copied are removed.
+     */
+    pub fn compact_ext(&mut self, page: u32) {
+        self.drain_reads();
+
+        let ext = match self.ext.clone() {
+            Some(ext) => ext,
+            None => return,
+        };
+
+        for index in self.storage_structure.indices() {
+            let (key, location) = match self.storage_structure.get_index(index) {
+                Some((_, DataEntry { key, location: Some(location), .. })) if location.page == page => (key, location),
+                _ => continue,
+            };
+
+            // An entry that cannot be moved is evicted, which releases its old location
+            match ext.read(location, &key).and_then(|value| ext.write(&key, &value)) {
+                Ok(moved) => {
+                    ext.release(location);
+                    ext.metrics.bytes_compacted.fetch_add(moved.len as u64, Ordering::Relaxed);
+                    self.storage_structure.update_index(index, &mut |entry| entry.location = Some(moved));
+                },
+                Err(_) => {
+                    self.replacement_policy.remove(index);
+                    if self.remove_evicted(index) {
+                        self.metrics.evictions += 1;
+                    }
+                },
+            }
+        }
+    }
+}
+
+/**
+ * What became of the entry picked for eviction: removed from the cache, or still in the cache with
+ * its value moved to the disk tier
+ */
+enum Eviction {
+    Removed,
+    Moved,
+}
+
+/**
+ * How an entry leaving to make room is reported, expired entries are only noticed to have expired
+ * once they are reclaimed
+ */
+fn evicted(entry: &DataEntry) -> EventKind {
+    if entry.is_expired(time::now()) { EventKind::Expire } else { EventKind::Evict }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use cache::storage_structure::HashStorageStructure;
+    use cache::replacement_policy::LRU;
+    use cache::compression::Algorithm;
+    use std::time::Duration;
+
+    pub fn cache(capacity: usize) -> Cache<HashStorageStructure, LRU> {
+        Cache::new(capacity, HashStorageStructure::new(), LRU::new())
+    }
+
+    pub fn key(name: &str) -> Key {
+        Key::new(name.to_string())
+    }
+
+    pub fn entry(name: &str, value: &str) -> DataEntry {
+        DataEntry::new(key(name), Value::new(value.to_string()))
+    }
+
+    #[test]
+    fn refuses_items_over_the_size_limits() {
+        let mut cache = cache(1024 * 1024);
+        cache.max_key_len = 4;
+        cache.max_val_len = 8;
+
+        assert!(matches!(cache.set(key("toolong"), Value::new("v".to_string())), Err(CacheError::KeyTooLarge)));
+        assert!(matches!(cache.set(key("k"), Value::new("too large".to_string())), Err(CacheError::ValueTooLarge)));
+        assert!(cache.set(key("k"), Value::new("v".to_string())).is_ok());
+        assert!(cache.contains(key("k")));
+    }
+    #[test]
+    fn memory_usage_stays_within_the_capacity() {
+        let mut cache = cache(16 * 1024);
+        for i in 0..200 {
+            assert!(cache.set(key(&format!("k{}", i)), Value::new("v".repeat(100))).is_ok());
+            assert!(cache.memory_usage() <= cache.capacity);
+        }
+
+        assert!(cache.metrics.evictions > 0);
+        assert!(cache.contains(key("k199")));
+        assert!(!cache.contains(key("k0")));
+
+        // Something that could never fit is refused without flushing the cache
+        cache.max_val_len = 32 * 1024;
+        assert!(matches!(cache.set(key("big"), Value::new("v".repeat(16 * 1024))), Err(CacheError::ValueTooLarge)));
+        assert!(cache.contains(key("k199")));
+    }
+    #[test]
+    fn reads_reach_the_policy_once_drained() {
+        let mut cache = cache(1024 * 1024);
+        assert!(cache.set(key("a"), Value::new("v".to_string())).is_ok());
+        assert!(cache.set(key("b"), Value::new("v".to_string())).is_ok());
+        let (a, _) = cache.storage_structure.get(key("a")).unwrap();
+
+        assert!(cache.read(key("a")).is_some());
+        assert!(cache.read(key("missing")).is_none());
+        assert_eq!(cache.replacement_policy.eviction_order()[0], a);
+
+        cache.drain_reads();
+        assert_ne!(cache.replacement_policy.eviction_order()[0], a);
+        assert_eq!(cache.metrics.hit_count_get, 1);
+        assert_eq!(cache.metrics.miss_count_get, 1);
+    }
+    #[test]
+    fn a_new_policy_keeps_the_eviction_order() {
+        let mut cache = cache(1024 * 1024);
+        for name in &["a", "b", "c"] {
+            assert!(cache.set(key(name), Value::new("v".to_string())).is_ok());
+        }
+        assert!(cache.get(key("a")).is_some());
+        let (b, _) = cache.storage_structure.get(key("b")).unwrap();
+
+        cache.set_replacement_policy(LRU::new());
+        assert_eq!(cache.replacement_policy.evict_next().ok(), Some(b));
+    }
+    #[test]
+    fn expired_entries_are_treated_as_missing() {
+        let mut cache = cache(1024 * 1024);
+        let mut expired = DataEntry::new(key("old"), Value::new("v".to_string()));
+        expired.expires_at = 1;
+        assert!(cache.insert(expired).is_ok());
+        assert!(cache.insert(DataEntry::new(key("new"), Value::new("v".to_string())).expires_in(60)).is_ok());
+
+        assert!(cache.get(key("old")).is_none());
+        assert!(!cache.contains(key("old")));
+        let entry = cache.get(key("new")).unwrap();
+        assert!(entry.ttl(time::now()).unwrap() <= 60);
+
+        let (items, _) = cache.scan("", 10, None);
+        assert_eq!(items.iter().map(|item| item.key.as_str()).collect::<Vec<_>>(), vec!["new"]);
+    }
+    #[test]
+    fn evicted_large_values_move_to_the_disk_tier() {
+        let path = env::temp_dir().join(format!("memcached-{}-ext-cache", process::id())).to_string_lossy().into_owned();
+        let mut cache = cache(16 * 1024);
+        cache.ext = Some(Arc::new(ExtStore::open(&path, 256, 64 * 1024, 1024 * 1024).unwrap()));
+        cache.max_val_len = 1024;
+
+        for i in 0..20 {
+            assert!(cache.set(key(&format!("k{}", i)), Value::new(format!("{:0>1000}", i))).is_ok());
+        }
+        let ext = cache.ext.clone().unwrap();
+        assert!(ext.metrics.items_written.load(Ordering::Relaxed) > 0);
+
+        // The entry stays in memory with only its key and where its value is on disk
+        let stub = cache.storage_structure.indices().into_iter()
+            .filter_map(|index| cache.storage_structure.get_index(index))
+            .map(|(_, entry)| entry)
+            .find(|entry| entry.location.is_some())
+            .unwrap();
+        assert_eq!(stub.value.len(), 0);
+        let value = cache.get(stub.key.clone()).unwrap().value;
+        assert_eq!(value, Value::new(format!("{:0>1000}", &stub.key.item[1..])));
+        assert_eq!(ext.metrics.hits.load(Ordering::Relaxed), 1);
+
+        // Only the entries that left the cache count as evictions, not the values moved to disk
+        assert_eq!(cache.storage_structure.indices().len() as u64 + cache.metrics.evictions, 20);
+        fs::remove_dir_all(&path).unwrap();
+    }
+    #[test]
+    fn entries_whose_value_cannot_be_compacted_are_evicted() {
+        let path = env::temp_dir().join(format!("memcached-{}-ext-compact", process::id())).to_string_lossy().into_owned();
+        let mut cache = cache(16 * 1024);
+        cache.ext = Some(Arc::new(ExtStore::open(&path, 256, 64 * 1024, 1024 * 1024).unwrap()));
+        cache.max_val_len = 1024;
+
+        for i in 0..20 {
+            assert!(cache.set(key(&format!("k{}", i)), Value::new(format!("{:0>1000}", i))).is_ok());
+        }
+        let stubs: Vec<Key> = cache.storage_structure.indices().into_iter()
+            .filter_map(|index| cache.storage_structure.get_index(index))
+            .filter(|(_, entry)| entry.location.map(|location| location.page) == Some(0))
+            .map(|(_, entry)| entry.key)
+            .collect();
+        assert!(!stubs.is_empty());
+        let subscription = Watchers::subscribe(&cache.watchers, "", vec![EventKind::Evict]);
+
+        // The page is gone so none of its values can be read back
+        let (ext, evictions) = (cache.ext.clone().unwrap(), cache.metrics.evictions);
+        ext.remove_page(0).unwrap();
+        cache.compact_ext(0);
+
+        for stub in stubs.iter() {
+            assert!(cache.storage_structure.get(stub.clone()).is_none());
+        }
+        assert_eq!(cache.metrics.evictions, evictions + stubs.len() as u64);
+        assert_eq!(subscription.subscriber.wait(Duration::from_millis(0)).len(), stubs.len());
+        fs::remove_dir_all(&path).unwrap();
+    }
+    #[test]
+    fn values_are_stored_compressed_and_read_back_as_set() {
+        let mut cache = cache(1024 * 1024);
+        cache.compressor = Some(Arc::new(Compressor::new(Algorithm::Zstd, 64)));
+        let value = Value::new("compressible ".repeat(30));
+        assert!(cache.set(key("k"), value.clone()).is_ok());
+
+        let (_, stored) = cache.storage_structure.get(key("k")).unwrap();
+        assert_ne!(stored.data_type, DATA_TYPE_RAW);
+        assert!(stored.value.len() < value.len());
+        assert_eq!(cache.get(key("k")).unwrap().value, value);
+    }
+    #[test]
+    fn values_that_fail_to_decompress_are_missing_and_removed() {
+        let mut cache = cache(1024 * 1024);
+        let compressor = Arc::new(Compressor::new(Algorithm::Zstd, 64));
+        cache.compressor = Some(compressor.clone());
+        assert!(cache.set(key("k"), Value::new("compressible ".repeat(30))).is_ok());
+
+        let (index, stored) = cache.storage_structure.get(key("k")).unwrap();
+        let mut bytes = stored.value.chunks.concat();
+        bytes.pop();
+        cache.storage_structure.update_index(index, &mut |entry| entry.value = Value::from_bytes(bytes.clone()));
+
+        assert!(cache.get(key("k")).is_none());
+        assert_eq!(compressor.metrics.decompress_errors.load(Ordering::Relaxed), 1);
+        assert!(cache.storage_structure.get(key("k")).is_none());
+        assert!(cache.get(key("k")).is_none());
+        assert_eq!(compressor.metrics.decompress_errors.load(Ordering::Relaxed), 1);
+    }
+    #[test]
+    fn only_the_lease_holder_fills_a_missing_key() {
+        let mut cache = cache(1024 * 1024);
+        let token = match cache.lease_get(key("k")) {
+            LeaseRead::Granted(token) => token,
+            _ => panic!("the first miss should be granted a lease"),
+        };
+        assert!(matches!(cache.lease_get(key("k")), LeaseRead::Wait));
+
+        let entry = entry("k", "v1");
+        assert!(matches!(cache.insert_leased(entry.clone(), token + 1), Err(CacheError::LeaseInvalid)));
+        assert!(cache.insert_leased(entry.clone(), token).is_ok());
+        assert!(matches!(cache.insert_leased(entry, token), Err(CacheError::LeaseInvalid)));
+        assert!(matches!(cache.lease_get(key("k")), LeaseRead::Hit(_)));
+
+        // After a delete the value is handed out as stale while it is filled again
+        cache.remove(key("k"));
+        assert!(matches!(cache.lease_get(key("k")), LeaseRead::Granted(_)));
+        match cache.lease_get(key("k")) {
+            LeaseRead::Stale(stale) => assert_eq!(stale.value, Value::new("v1".to_string())),
+            _ => panic!("the deleted value should be handed out as stale"),
+        }
+        assert_eq!(cache.metrics.lease_rejections, 2);
+        assert_eq!(cache.metrics.lease_stale_hits, 1);
+    }
+
+    #[test]
+    fn stale_values_are_shed_before_entries_are_evicted() {
+        let mut cache = cache(16 * 1024);
+        assert!(cache.set(key("old"), Value::new("v".repeat(500))).is_ok());
+        cache.remove(key("old"));
+        assert!(cache.leases.memory_usage() > 500);
+
+        assert!(cache.set(key("a"), Value::new("v".repeat(500))).is_ok());
+        while cache.leases.memory_usage() > 500 {
+            let next = format!("k{}", cache.storage_structure.indices().len());
+            assert!(cache.set(key(&next), Value::new("v".repeat(500))).is_ok());
         }
+        assert_eq!(cache.metrics.evictions, 0);
+        assert!(cache.memory_usage() <= cache.capacity);
     }
-}
\ No newline at end of file
+    #[test]
+    fn expired_entries_are_served_stale_during_the_grace_period() {
+        let mut cache = cache(1024 * 1024);
+        cache.stale_grace = 60;
+        let now = time::now();
+        for &(name, expired) in [("recent", 5), ("old", 100)].iter() {
+            let mut entry = entry(name, "v");
+            entry.expires_at = now - expired;
+            assert!(cache.insert(entry).is_ok());
+        }
+
+        assert!(cache.read(key("recent")).is_none());
+        assert!(cache.read_stale(key("recent")).is_some());
+        assert!(cache.read_stale(key("old")).is_none());
+
+        // One reader recomputes the value, the others are served the stale one meanwhile
+        assert!(cache.revalidate(&key("recent")).is_some());
+        assert!(cache.revalidate(&key("recent")).is_none());
+        match cache.lease_get(key("recent")) {
+            LeaseRead::Stale(entry) => assert!(entry.is_expired(now)),
+            _ => panic!("the expired value should be handed out as stale"),
+        }
+        assert_eq!(cache.metrics.stale_hits, 2);
+        assert_eq!(cache.metrics.revalidations, 1);
+    }
+    #[test]
+    fn invalidating_a_tag_removes_the_entries_carrying_it() {
+        let mut cache = cache(1024 * 1024);
+        for &(name, tags) in [("a", "red,big"), ("b", "red"), ("c", "blue")].iter() {
+            let tags = tags.split(',').map(String::from).collect();
+            assert!(cache.insert(entry(name, "v").with_tags(tags)).is_ok());
+        }
+
+        // Replacing an entry replaces its tags
+        assert!(cache.insert(entry("b", "v").with_tags(vec![String::from("blue")])).is_ok());
+
+        assert_eq!(cache.invalidate_tag("red"), 1);
+        assert!(!cache.contains(key("a")));
+        assert!(cache.contains(key("b")));
+        assert_eq!(cache.invalidate_tag("big"), 0);
+        assert_eq!(cache.invalidate_tag("blue"), 2);
+        assert!(cache.storage_structure.indices().is_empty());
+        assert_eq!(cache.metrics.tag_invalidations, 3);
+    }
+    #[test]
+    fn flushed_prefixes_hide_entries_until_they_are_reclaimed() {
+        let mut cache = cache(1024 * 1024);
+        for name in ["user:1", "user:2", "session:1"].iter() {
+            assert!(cache.set(key(name), Value::new("v".to_string())).is_ok());
+        }
+
+        cache.flush_prefix("user:");
+        assert!(cache.get(key("user:1")).is_none());
+        assert!(!cache.contains(key("user:2")));
+        assert!(cache.contains(key("session:1")));
+
+        assert!(cache.set(key("user:2"), Value::new("v".to_string())).is_ok());
+        assert!(cache.contains(key("user:2")));
+
+        assert_eq!(cache.reclaim_flushed(), 1);
+        assert_eq!(cache.storage_structure.indices().len(), 2);
+        assert!(!cache.prefixes.pending());
+        assert_eq!(cache.reclaim_flushed(), 0);
+    }
+    #[test]
+    fn changes_are_published_to_watchers() {
+        let mut cache = cache(4 * 1024);
+        let subscription = Watchers::subscribe(&cache.watchers, "", EventKind::all());
+        assert!(cache.set(key("a"), Value::new("v".to_string())).is_ok());
+        cache.remove(key("a"));
+        for i in 0..20 {
+            assert!(cache.set(key(&format!("k{}", i)), Value::new("v".repeat(100))).is_ok());
+        }
+
+        let kinds: Vec<EventKind> = subscription.subscriber.wait(Duration::from_millis(0)).into_iter().map(|event| event.kind).collect();
+        assert_eq!(&kinds[..2], &[EventKind::Set, EventKind::Delete][..]);
+        assert_eq!(kinds.iter().filter(|kind| **kind == EventKind::Evict).count() as u64, cache.metrics.evictions);
+        assert!(cache.metrics.evictions > 0);
+    }
+}
@@ -0,0 +1,336 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use lz4_flex;
+use zstd;
+
+use cache::value::{Value, CHUNK_SIZE};
+
+/**
+ * Bits of the data type of an entry, as in the data type field of the binary protocol, that say
+ * how its value is compressed.  A value without any of them is stored as it was set.
+ */
+pub const DATA_TYPE_RAW: u8 = 0x00;
+pub const DATA_TYPE_LZ4: u8 = 0x02;
+pub const DATA_TYPE_ZSTD: u8 = 0x04;
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Algorithm {
+    Lz4,
+    Zstd,
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match name.to_lowercase().as_str() {
+            "lz4" => Some(Algorithm::Lz4),
+            "zstd" => Some(Algorithm::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn data_type(&self) -> u8 {
+        match *self {
+            Algorithm::Lz4 => DATA_TYPE_LZ4,
+            Algorithm::Zstd => DATA_TYPE_ZSTD,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct CompressionMetrics {
+    pub compressed: AtomicU64,
+    pub incompressible: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub compress_nanos: AtomicU64,
+    pub decompressed: AtomicU64,
+    pub decompress_nanos: AtomicU64,
+    pub decompress_errors: AtomicU64,
+}
+
+/**
+ * Compresses values of at least `threshold` bytes as they are stored and decompresses them as
+ * they are read, so clients only ever see the values they set.  A value is only stored compressed
+ * if that makes it smaller.
+ *
+ * Every chunk of a value is compressed on its own into a frame of its compressed length followed
+ * by the compressed bytes, and the frames are chunked again like any other value.  Neither form of
+ * a value is ever held in a single allocation, but a value that is read is decompressed in full
+ * before it is sent.
+ */
+pub struct Compressor {
+    pub algorithm: Algorithm,
+    pub threshold: usize,
+    pub metrics: CompressionMetrics,
+}
+
+impl Compressor {
+    pub fn new(algorithm: Algorithm, threshold: usize) -> Compressor {
+        Compressor {
+            algorithm,
+            threshold,
+            metrics: CompressionMetrics::default(),
+        }
+    }
+
+    /**
+     * The compressed value and its data type, None if the value is too small to bother with or
+     * does not get any smaller
+     */
+    pub fn compress(&self, value: &Value) -> Option<(Value, u8)> {
+        if value.len() < self.threshold {
+            return None;
+        }
+
+        let start = Instant::now();
+        let mut frames = ChunkWriter::new();
+        let mut compressed = true;
+        for chunk in value.chunks.iter() {
+            let frame = match self.algorithm {
+                Algorithm::Lz4 => Some(lz4_flex::compress_prepend_size(chunk)),
+                Algorithm::Zstd => zstd::bulk::compress(chunk, ZSTD_LEVEL).ok(),
+            };
+            match frame {
+                Some(frame) => {
+                    frames.write(&(frame.len() as u32).to_be_bytes());
+                    frames.write(&frame);
+                },
+                None => {
+                    compressed = false;
+                    break;
+                }
+            }
+        }
+        let compressed = if compressed { Some(frames.finish()) } else { None };
+        self.metrics.compress_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+
+        match compressed {
+            Some(compressed) if compressed.len() < value.len() => {
+                self.metrics.compressed.fetch_add(1, Ordering::Relaxed);
+                self.metrics.bytes_in.fetch_add(value.len() as u64, Ordering::Relaxed);
+                self.metrics.bytes_out.fetch_add(compressed.len() as u64, Ordering::Relaxed);
+
+                let mut compressed = compressed;
+                compressed.cas = value.cas;
+                Some((compressed, self.algorithm.data_type()))
+            },
+            _ => {
+                self.metrics.incompressible.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /**
+     * The value a compressed value of the data type was compressed from, decompressed a frame at
+     * a time into chunks of its own
+     */
+    pub fn decompress(&self, value: &Value, data_type: u8) -> Result<Value, String> {
+        let start = Instant::now();
+        let mut frames = ChunkReader::new(&value.chunks);
+        let mut chunks: Vec<Arc<[u8]>> = Vec::new();
+        let mut result = Ok(());
+
+        while !frames.is_empty() {
+            let frame = frames.read(4)
+                .map(|len| u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize)
+                .and_then(|len| frames.read(len));
+            let frame = match frame {
+                Some(frame) => frame,
+                None => {
+                    result = Err(String::from("Truncated compressed frame"));
+                    break;
+                }
+            };
+
+            let decompressed = if data_type & DATA_TYPE_LZ4 != 0 {
+                lz4_flex::decompress_size_prepended(&frame).map_err(|e| e.to_string())
+            } else if data_type & DATA_TYPE_ZSTD != 0 {
+                zstd::decode_all(&frame[..]).map_err(|e| e.to_string())
+            } else {
+                Ok(frame)
+            };
+            match decompressed {
+                Ok(bytes) => chunks.push(Arc::from(bytes)),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.metrics.decompress_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        self.metrics.decompressed.fetch_add(1, Ordering::Relaxed);
+        if result.is_err() {
+            self.metrics.decompress_errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result.map(|()| {
+            let mut decompressed = Value::from_chunks(chunks);
+            decompressed.cas = value.cas;
+            decompressed
+        })
+    }
+
+    pub fn stats(&self) -> Vec<(String, String)> {
+        let bytes_in = self.metrics.bytes_in.load(Ordering::Relaxed);
+        let bytes_out = self.metrics.bytes_out.load(Ordering::Relaxed);
+        let ratio = if bytes_out == 0 { 1.0 } else { bytes_in as f64 / bytes_out as f64 };
+
+        vec![
+            (String::from("compression"), format!("{:?}", self.algorithm).to_lowercase()),
+            (String::from("compression_threshold"), self.threshold.to_string()),
+            (String::from("compressed_items"), self.metrics.compressed.load(Ordering::Relaxed).to_string()),
+            (String::from("incompressible_items"), self.metrics.incompressible.load(Ordering::Relaxed).to_string()),
+            (String::from("compressed_bytes_in"), bytes_in.to_string()),
+            (String::from("compressed_bytes_out"), bytes_out.to_string()),
+            (String::from("compression_ratio"), format!("{:.2}", ratio)),
+            (String::from("compress_time_us"), (self.metrics.compress_nanos.load(Ordering::Relaxed) / 1000).to_string()),
+            (String::from("decompressions"), self.metrics.decompressed.load(Ordering::Relaxed).to_string()),
+            (String::from("decompress_time_us"), (self.metrics.decompress_nanos.load(Ordering::Relaxed) / 1000).to_string()),
+            (String::from("decompress_errors"), self.metrics.decompress_errors.load(Ordering::Relaxed).to_string()),
+        ]
+    }
+}
+
+/**
+ * Builds a value a chunk at a time from bytes written in pieces of any size
+ */
+struct ChunkWriter {
+    chunks: Vec<Arc<[u8]>>,
+    current: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn new() -> ChunkWriter {
+        ChunkWriter {
+            chunks: Vec::new(),
+            current: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let taken = bytes.len().min(CHUNK_SIZE - self.current.len());
+            self.current.extend_from_slice(&bytes[..taken]);
+            bytes = &bytes[taken..];
+
+            if self.current.len() == CHUNK_SIZE {
+                self.chunks.push(Arc::from(self.current.split_off(0)));
+            }
+        }
+    }
+
+    fn finish(mut self) -> Value {
+        if !self.current.is_empty() {
+            self.chunks.push(Arc::from(self.current));
+        }
+        Value::from_chunks(self.chunks)
+    }
+}
+
+/**
+ * Reads the bytes of a chunk chain in pieces that may span chunks
+ */
+struct ChunkReader<'a> {
+    chunks: &'a [Arc<[u8]>],
+    chunk: usize,
+    offset: usize,
+}
+
+impl <'a> ChunkReader<'a> {
+    fn new(chunks: &'a [Arc<[u8]>]) -> ChunkReader<'a> {
+        ChunkReader {
+            chunks,
+            chunk: 0,
+            offset: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        // A chunk is left as soon as it is read to the end
+        self.chunks[self.chunk..].iter().all(|chunk| chunk.is_empty())
+    }
+
+    /**
+     * The next `len` bytes, None if there are fewer left
+     */
+    fn read(&mut self, len: usize) -> Option<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(len);
+        while bytes.len() < len {
+            let chunk = self.chunks.get(self.chunk)?;
+            let taken = (len - bytes.len()).min(chunk.len() - self.offset);
+            bytes.extend_from_slice(&chunk[self.offset..self.offset + taken]);
+            self.offset += taken;
+
+            if self.offset == chunk.len() {
+                self.chunk += 1;
+                self.offset = 0;
+            }
+        }
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Bytes that compress to a bit over a third of their size, so the frames of a large value end up
+     * spanning chunks
+     */
+    fn text(len: usize) -> Vec<u8> {
+        let mut seed: u64 = 1;
+        (0..len).map(|_| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            b"abcdefgh"[(seed >> 61) as usize]
+        }).collect()
+    }
+
+    #[test]
+    fn values_spanning_chunks_round_trip() {
+        for &algorithm in [Algorithm::Lz4, Algorithm::Zstd].iter() {
+            let compressor = Compressor::new(algorithm, 64);
+            let mut value = Value::from_bytes(text(3 * CHUNK_SIZE + 100));
+            value.cas = 7;
+
+            let (compressed, data_type) = compressor.compress(&value).unwrap();
+            assert_eq!(data_type, algorithm.data_type());
+            assert!(compressed.len() < value.len());
+            assert!(compressed.chunks.len() > 1);
+            assert!(compressed.chunks.iter().all(|chunk| chunk.len() <= CHUNK_SIZE));
+
+            let decompressed = compressor.decompress(&compressed, data_type).unwrap();
+            assert_eq!(decompressed, value);
+            assert_eq!(decompressed.chunks.concat(), value.chunks.concat());
+        }
+    }
+
+    #[test]
+    fn small_and_incompressible_values_are_kept_as_they_are() {
+        let compressor = Compressor::new(Algorithm::Lz4, 64);
+        assert!(compressor.compress(&Value::new("a".repeat(63))).is_none());
+
+        let mut seed: u64 = 1;
+        let noise: Vec<u8> = (0..4096).map(|_| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 56) as u8
+        }).collect();
+        assert!(compressor.compress(&Value::from_bytes(noise)).is_none());
+        assert_eq!(compressor.metrics.incompressible.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn a_truncated_value_fails_to_decompress() {
+        let compressor = Compressor::new(Algorithm::Zstd, 64);
+        let (compressed, data_type) = compressor.compress(&Value::new("a".repeat(1000))).unwrap();
+        let mut bytes = compressed.chunks.concat();
+        bytes.pop();
+        assert!(compressor.decompress(&Value::from_bytes(bytes), data_type).is_err());
+        assert_eq!(compressor.metrics.decompress_errors.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,21 +1,93 @@
+use std::mem;
+
 use cache::key::Key;
 use cache::value::Value;
+use cache::memory;
+use cache::time;
+use cache::extstore::ExtLocation;
+use cache::compression::DATA_TYPE_RAW;
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub struct DataEntry {
     pub key: Key,
-    pub value: Value
+    pub value: Value,
+    pub cost: u64,
+    pub flags: u32, // Opaque to the cache, returned to clients with the value
+    pub data_type: u8, // How the value is compressed, see cache::compression
+    pub expires_at: u64, // Unix time in seconds, 0 if the entry never expires
+    pub last_access: u64, // Unix time in seconds
+    pub location: Option<ExtLocation>, // Where the value is if it was moved to the disk tier
+    pub tags: Vec<String>, // Groups of entries the entry is invalidated with
+    pub generation: u64, // Prefix flush generation the entry was stored at, see cache::prefix
 }
 
 impl DataEntry {
     pub fn new(key: Key, value: Value) -> DataEntry {
+        DataEntry::with_cost(key, value, 1)
+    }
+
+    /**
+     * An entry that is `cost` times as expensive to recompute as a regular entry
+     */
+    pub fn with_cost(key: Key, value: Value, cost: u64) -> DataEntry {
         DataEntry { 
             key: key,
-            value: value
+            value,
+            cost,
+            flags: 0,
+            data_type: DATA_TYPE_RAW,
+            expires_at: 0,
+            last_access: time::now(),
+            location: None,
+            tags: Vec::new(),
+            generation: 0,
          }
     }
 
+    /**
+     * Expire the entry `ttl` seconds from now, a ttl of 0 means the entry never expires
+     */
+    pub fn expires_in(mut self, ttl: u64) -> DataEntry {
+        self.expires_at = if ttl == 0 { 0 } else { time::now() + ttl };
+        self
+    }
+
+    pub fn with_tags(mut self, tags: Vec<String>) -> DataEntry {
+        self.tags = tags;
+        self
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+
+    /**
+     * Seconds until the entry expires, None if it never expires
+     */
+    pub fn ttl(&self, now: u64) -> Option<u64> {
+        if self.expires_at == 0 {
+            None
+        } else {
+            Some(self.expires_at.saturating_sub(now))
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.key.len() + self.value.len()
     }
+
+    /**
+     * Heap memory held by the key, value and tag buffers, excluding the entry header itself
+     */
+    pub fn heap_usage(&self) -> usize {
+        let tags: usize = self.tags.iter().map(|tag| memory::allocation(tag.capacity())).sum();
+        memory::allocation(self.key.item.capacity()) + self.value.heap_usage() + memory::vec_usage::<String>(self.tags.capacity()) + tags
+    }
+
+    /**
+     * Memory taken up by the entry, the header as well as the key, value and tag buffers
+     */
+    pub fn memory_usage(&self) -> usize {
+        mem::size_of::<DataEntry>() + self.heap_usage()
+    }
 }
\ No newline at end of file
@@ -1,4 +1,10 @@
 pub enum CacheError {
     EvictionFailure,
     KeyNotFound,
+    AdmissionRejected,
+    KeyTooLarge,
+    ValueTooLarge,
+    OutOfMemory,
+    BadSlabClass,
+    LeaseInvalid,
 }
\ No newline at end of file
@@ -0,0 +1,311 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Error, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use cache::key::Key;
+use cache::value::Value;
+
+/**
+ * Pages with less than this share of their bytes still referenced are compacted
+ */
+const COMPACT_LIVE_RATIO: f64 = 0.5;
+
+/**
+ * Where a value moved to the disk tier is stored: the page, the offset of its record in the page
+ * and the length of the record
+ */
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub struct ExtLocation {
+    pub page: u32,
+    pub offset: u64,
+    pub len: u32,
+}
+
+#[derive(Default)]
+pub struct ExtMetrics {
+    pub items_written: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub compactions: AtomicU64,
+    pub bytes_compacted: AtomicU64,
+}
+
+struct Page {
+    file: File,
+    size: AtomicU64,
+    live: AtomicU64,
+}
+
+struct Writer {
+    page: u32,
+    offset: u64,
+}
+
+/**
+ * The disk tier.  Values of at least `min_item_size` bytes that are evicted from memory are
+ * appended to a log on disk, and their entries stay in memory with only the key and the location
+ * of the value, so a later get still finds them at the cost of a read from disk.
+ *
+ * The log is split into pages, one file each, that are written in turn.  Every page counts the
+ * bytes of the values that are still referenced, values that are deleted, replaced or evicted
+ * from memory altogether only leave garbage behind.  Once most of a page that is no longer
+ * written to is garbage its remaining values are copied to the current page and the page is
+ * removed.
+ *
+ * Each record is the key, so a read can check it found the value it was looking for, followed by
+ * the value:
+ *
+ * | key length (u32) | key | value length (u64) | value |
+ */
+pub struct ExtStore {
+    pub min_item_size: usize,
+    pub page_size: u64,
+    pub max_pages: usize,
+    pub metrics: ExtMetrics,
+    path: String,
+    pages: RwLock<BTreeMap<u32, Arc<Page>>>,
+    writer: Mutex<Writer>,
+}
+
+impl ExtStore {
+    /**
+     * Open a disk tier of up to `size` bytes in the directory at path.  Pages left behind by a
+     * previous process are removed, the locations of their values were only ever kept in memory.
+     */
+    pub fn open(path: &str, min_item_size: usize, page_size: u64, size: u64) -> io::Result<ExtStore> {
+        fs::create_dir_all(path)?;
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with("page-") {
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        let store = ExtStore {
+            min_item_size,
+            page_size,
+            max_pages: ((size / page_size) as usize).max(1),
+            metrics: ExtMetrics::default(),
+            path: String::from(path),
+            pages: RwLock::new(BTreeMap::new()),
+            writer: Mutex::new(Writer {
+                page: 0,
+                offset: 0,
+            }),
+        };
+        store.add_page(0)?;
+
+        Ok(store)
+    }
+
+    /**
+     * Append a value to the current page, starting a new page when it is full.  Fails if the
+     * value does not fit in a page or every page is in use.
+     */
+    pub fn write(&self, key: &Key, value: &Value) -> io::Result<ExtLocation> {
+        let len = 4 + key.item.len() + 8 + value.len();
+        if len as u64 > self.page_size {
+            return Err(Error::other("value is larger than a page"));
+        }
+
+        let mut record = Vec::with_capacity(len);
+        record.extend_from_slice(&(key.item.len() as u32).to_be_bytes());
+        record.extend_from_slice(key.item.as_bytes());
+        record.extend_from_slice(&(value.len() as u64).to_be_bytes());
+        for chunk in value.chunks.iter() {
+            record.extend_from_slice(chunk);
+        }
+
+        let mut writer = self.writer.lock().unwrap();
+        if writer.offset + len as u64 > self.page_size {
+            if self.pages.read().unwrap().len() >= self.max_pages {
+                return Err(Error::other("disk tier is full"));
+            }
+
+            let next = writer.page + 1;
+            self.add_page(next)?;
+            writer.page = next;
+            writer.offset = 0;
+        }
+
+        let page = self.page(writer.page)?;
+        page.file.write_all_at(&record, writer.offset)?;
+        page.size.fetch_add(len as u64, Ordering::Relaxed);
+        page.live.fetch_add(len as u64, Ordering::Relaxed);
+
+        let location = ExtLocation {
+            page: writer.page,
+            offset: writer.offset,
+            len: len as u32,
+        };
+        writer.offset += len as u64;
+
+        self.metrics.items_written.fetch_add(1, Ordering::Relaxed);
+        self.metrics.bytes_written.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(location)
+    }
+
+    /**
+     * Read the value of key back from its location
+     */
+    pub fn read(&self, location: ExtLocation, key: &Key) -> io::Result<Value> {
+        let page = self.page(location.page)?;
+
+        let mut record = vec![0; location.len as usize];
+        page.file.read_exact_at(&mut record, location.offset)?;
+
+        let key_len = read_u32(&record, 0) as usize;
+        if 4 + key_len + 8 > record.len() || &record[4..4 + key_len] != key.item.as_bytes() {
+            return Err(Error::new(ErrorKind::InvalidData, "record holds a different key"));
+        }
+
+        let value_len = read_u64(&record, 4 + key_len) as usize;
+        let start = 4 + key_len + 8;
+        if start + value_len != record.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "record has the wrong length"));
+        }
+
+        Ok(Value::from_bytes(record.split_off(start)))
+    }
+
+    /**
+     * Mark the value at location as garbage
+     */
+    pub fn release(&self, location: ExtLocation) {
+        if let Ok(page) = self.page(location.page) {
+            page.live.fetch_sub(location.len as u64, Ordering::Relaxed);
+        }
+    }
+
+    /**
+     * The page that is not being written to with the smallest share of referenced bytes, if that
+     * share is small enough to be worth compacting
+     */
+    pub fn compaction_candidate(&self) -> Option<u32> {
+        let current = self.writer.lock().unwrap().page;
+
+        self.pages.read().unwrap().iter()
+            .filter(|&(&id, _)| id != current)
+            .map(|(&id, page)| {
+                let size = page.size.load(Ordering::Relaxed).max(1);
+                (id, page.live.load(Ordering::Relaxed) as f64 / size as f64)
+            })
+            .filter(|&(_, live)| live < COMPACT_LIVE_RATIO)
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .map(|(id, _)| id)
+    }
+
+    /**
+     * Remove a page once no entry refers to it any more
+     */
+    pub fn remove_page(&self, id: u32) -> io::Result<()> {
+        self.pages.write().unwrap().remove(&id);
+        self.metrics.compactions.fetch_add(1, Ordering::Relaxed);
+        fs::remove_file(self.page_path(id))
+    }
+
+    pub fn stats(&self) -> Vec<(String, String)> {
+        let pages = self.pages.read().unwrap();
+        let bytes: u64 = pages.values().map(|page| page.size.load(Ordering::Relaxed)).sum();
+        let live: u64 = pages.values().map(|page| page.live.load(Ordering::Relaxed)).sum();
+
+        vec![
+            (String::from("ext_pages"), pages.len().to_string()),
+            (String::from("ext_limit_pages"), self.max_pages.to_string()),
+            (String::from("ext_bytes"), bytes.to_string()),
+            (String::from("ext_live_bytes"), live.to_string()),
+            (String::from("ext_items_written"), self.metrics.items_written.load(Ordering::Relaxed).to_string()),
+            (String::from("ext_bytes_written"), self.metrics.bytes_written.load(Ordering::Relaxed).to_string()),
+            (String::from("ext_hits"), self.metrics.hits.load(Ordering::Relaxed).to_string()),
+            (String::from("ext_misses"), self.metrics.misses.load(Ordering::Relaxed).to_string()),
+            (String::from("ext_compactions"), self.metrics.compactions.load(Ordering::Relaxed).to_string()),
+            (String::from("ext_bytes_compacted"), self.metrics.bytes_compacted.load(Ordering::Relaxed).to_string()),
+        ]
+    }
+
+    fn page(&self, id: u32) -> io::Result<Arc<Page>> {
+        match self.pages.read().unwrap().get(&id) {
+            Some(page) => Ok(page.clone()),
+            None => Err(Error::new(ErrorKind::NotFound, "page has been removed")),
+        }
+    }
+
+    fn add_page(&self, id: u32) -> io::Result<()> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(self.page_path(id))?;
+
+        self.pages.write().unwrap().insert(id, Arc::new(Page {
+            file,
+            size: AtomicU64::new(0),
+            live: AtomicU64::new(0),
+        }));
+        Ok(())
+    }
+
+    fn page_path(&self, id: u32) -> String {
+        format!("{}/page-{}", self.path, id)
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_be_bytes(value)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_be_bytes(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn open(name: &str, page_size: u64, size: u64) -> (ExtStore, String) {
+        let path = env::temp_dir().join(format!("memcached-{}-{}", process::id(), name)).to_string_lossy().into_owned();
+        (ExtStore::open(&path, 16, page_size, size).unwrap(), path)
+    }
+
+    fn key(name: &str) -> Key {
+        Key::new(name.to_string())
+    }
+
+    #[test]
+    fn values_are_read_back_from_their_location() {
+        let (ext, path) = open("ext-read", 4096, 4096);
+        let value = Value::new("x".repeat(100));
+        let location = ext.write(&key("k"), &value).unwrap();
+
+        assert_eq!(ext.read(location, &key("k")).unwrap(), value);
+        assert_eq!(ext.read(location, &key("other")).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(ext.write(&key("k"), &Value::new("x".repeat(4096))).is_err());
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn mostly_released_pages_are_compacted() {
+        let (ext, path) = open("ext-compact", 1024, 3 * 1024);
+        let value = Value::new("x".repeat(200));
+        let locations: Vec<ExtLocation> = (0..12).map(|i| ext.write(&key(&format!("k{:02}", i)), &value).unwrap()).collect();
+        assert_eq!(locations[11].page, 2);
+        assert!(ext.write(&key("k12"), &value).is_err());
+
+        // The current page is never a candidate, a page is once most of it is released
+        assert_eq!(ext.compaction_candidate(), None);
+        for location in locations.iter().filter(|location| location.page == 1).skip(1) {
+            ext.release(*location);
+        }
+        assert_eq!(ext.compaction_candidate(), Some(1));
+
+        ext.remove_page(1).unwrap();
+        assert_eq!(ext.read(locations[4], &key("k04")).unwrap_err().kind(), ErrorKind::NotFound);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
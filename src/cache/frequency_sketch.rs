@@ -0,0 +1,88 @@
+use cache::memory;
+
+/**
+ * A Count-Min Sketch used to estimate how often a key has been accessed without retaining the
+ * key itself.  Each of the rows maps a key hash to a small saturating counter and the estimate is
+ * the minimum over all rows, so collisions can only ever overestimate a frequency.
+ *
+ * Counts are aged by halving every counter once the number of recorded accesses reaches the
+ * sample size, which keeps the sketch biased towards the recent history of the workload.
+ */
+pub struct CountMinSketch {
+    table: Vec<u8>,
+    width: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+const DEPTH: usize = 4;
+const MAX_COUNT: u8 = 15;
+const SEEDS: [u64; DEPTH] = [
+    0xc3a5c85c97cb3127,
+    0xb492b66fbe98f273,
+    0x9ae16a3b2f90404f,
+    0xcbf29ce484222325,
+];
+
+impl CountMinSketch {
+    /**
+     * Create a sketch with at least `width` counters per row, aged every `10 * width` accesses
+     */
+    pub fn new(width: usize) -> CountMinSketch {
+        let width = width.max(1).next_power_of_two();
+
+        CountMinSketch {
+            table: vec![0; width * DEPTH],
+            width,
+            additions: 0,
+            sample_size: 10 * width,
+        }
+    }
+
+    /**
+     * Returns the estimated number of accesses for the hash
+     */
+    pub fn frequency(&self, hash: u64) -> u8 {
+        (0..DEPTH).map(|row| self.table[self.slot(row, hash)]).min().unwrap_or(0)
+    }
+
+    /**
+     * Record an access for the hash, aging the sketch once the sample size is reached
+     */
+    pub fn increment(&mut self, hash: u64) {
+        let mut added = false;
+        for row in 0..DEPTH {
+            let slot = self.slot(row, hash);
+            if self.table[slot] < MAX_COUNT {
+                self.table[slot] += 1;
+                added = true;
+            }
+        }
+
+        if added {
+            self.additions += 1;
+            if self.additions >= self.sample_size {
+                self.reset();
+            }
+        }
+    }
+
+    /**
+     * Halve every counter so that stale popularity decays over time
+     */
+    fn reset(&mut self) {
+        for counter in self.table.iter_mut() {
+            *counter >>= 1;
+        }
+        self.additions /= 2;
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        memory::vec_usage::<u8>(self.table.capacity())
+    }
+
+    fn slot(&self, row: usize, hash: u64) -> usize {
+        let mixed = hash.wrapping_mul(SEEDS[row]);
+        row * self.width + ((mixed >> 32) as usize & (self.width - 1))
+    }
+}
@@ -1,4 +1,7 @@
-#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone)]
 pub struct Key {
     // TODO: Vec<u8>
     pub item: String,
@@ -12,4 +15,13 @@ impl Key {
     pub fn len(&self) -> usize {
         self.item.len()
     }
+
+    /**
+     * A 64 bit hash of the key for structures that only retain a fingerprint of the key
+     */
+    pub fn digest(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.item.hash(&mut hasher);
+        hasher.finish()
+    }
 }
\ No newline at end of file
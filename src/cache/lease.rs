@@ -0,0 +1,279 @@
+use std::collections::{HashMap, VecDeque};
+
+use cache::key::Key;
+use cache::data_entry::DataEntry;
+use cache::time;
+use cache::memory;
+
+/**
+ * What a read with a lease found
+ */
+pub enum LeaseRead {
+    Hit(DataEntry), // The key is cached
+    Granted(u64), // A miss, the reader is to fill the key with a set carrying the token
+    Stale(DataEntry), // A miss while another client fills the key, with the value it had before it was deleted
+    Wait, // A miss while another client fills the key, the reader is to retry shortly
+}
+
+struct Lease {
+    token: Option<u64>, // None once the lease was invalidated, the stale value may outlive it
+    stale: Option<Box<DataEntry>>,
+    since: u64,
+}
+
+/**
+ * Leases on missing keys.  The first reader to miss a key gets a token and is the only one whose
+ * set is accepted, everyone else missing the key meanwhile is told to wait or handed the value the
+ * key had before it was deleted.  Deleting the key invalidates its lease, so a value computed
+ * before the delete can not be set after it, and setting the key without a lease ends it.
+ *
+ * Leases and stale values last `duration` seconds.  Keys are queued in the order their leases
+ * were last updated, so those that ran out are always at the front and reclaimed as new ones come
+ * in.  A key that was updated again since is further back in the queue as well and left alone.
+ *
+ * Stale values count towards the memory of the cache, and when it runs out of space the oldest of
+ * them are shed before any cached entry is evicted.
+ */
+pub struct Leases {
+    pub duration: u64,
+    next_token: u64,
+    leases: HashMap<Key, Lease>,
+    updates: VecDeque<(Key, u64)>,
+    usage: usize, // Memory held by the queued keys and the leases with their stale values
+}
+
+impl Leases {
+    pub fn new(duration: u64) -> Leases {
+        Leases {
+            duration,
+            // Tokens handed out before a restart should not match those handed out after it
+            next_token: time::now() << 20,
+            leases: HashMap::new(),
+            updates: VecDeque::new(),
+            usage: 0,
+        }
+    }
+
+    /**
+     * Lease a key that was missed.  The token if the lease is granted, otherwise whatever stale
+     * value the key has.
+     */
+    pub fn acquire(&mut self, key: &Key) -> Result<u64, Option<Box<DataEntry>>> {
+        let now = time::now();
+        self.purge(now);
+
+        if let Some(lease) = self.leases.get(key) {
+            if lease.token.is_some() {
+                return Err(lease.stale.clone());
+            }
+        }
+
+        let stale = self.take(key).and_then(|lease| lease.stale);
+
+        self.next_token += 1;
+        let token = self.next_token;
+        self.update(key, Lease { token: Some(token), stale, since: now });
+        Ok(token)
+    }
+
+    /**
+     * Give up the lease on a key that is about to be set with the token, false if the token is
+     * not that of a valid lease on the key
+     */
+    pub fn redeem(&mut self, key: &Key, token: u64) -> bool {
+        self.purge(time::now());
+
+        let valid = match self.leases.get(key) {
+            Some(lease) => lease.token == Some(token),
+            None => false,
+        };
+        if valid {
+            self.take(key);
+        }
+        valid
+    }
+
+    /**
+     * Invalidate the lease on a deleted key, keeping the value it had to hand out while it is
+     * filled again
+     */
+    pub fn invalidate(&mut self, key: &Key, stale: Option<DataEntry>) {
+        self.purge(time::now());
+
+        let stale = match self.take(key) {
+            Some(lease) => stale.map(Box::new).or(lease.stale),
+            None => stale.map(Box::new),
+        };
+        if stale.is_some() {
+            self.update(key, Lease { token: None, stale, since: time::now() });
+        }
+    }
+
+    /**
+     * End the lease on a key that was set
+     */
+    pub fn release(&mut self, key: &Key) {
+        if !self.leases.is_empty() {
+            self.take(key);
+        }
+    }
+
+    /**
+     * Drop the leases and stale values of every key starting with the prefix
+     */
+    pub fn clear_prefix(&mut self, prefix: &str) {
+        let keys: Vec<Key> = self.leases.keys().filter(|key| key.item.starts_with(prefix)).cloned().collect();
+        for key in keys {
+            self.take(&key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.leases.clear();
+        self.updates.clear();
+        self.usage = 0;
+    }
+
+    /**
+     * Drop the stale value that was kept the longest, false if there is none
+     */
+    pub fn shed_stale(&mut self) -> bool {
+        let mut shed = None;
+        for &(ref key, since) in self.updates.iter() {
+            match self.leases.get(key) {
+                Some(lease) if lease.since == since && lease.stale.is_some() => {
+                    shed = Some(key.clone());
+                    break;
+                },
+                _ => {},
+            }
+        }
+
+        let key = match shed {
+            Some(key) => key,
+            None => return false,
+        };
+
+        // A lease that is still held outlives its stale value
+        let mut lease = self.take(&key).unwrap();
+        if lease.token.is_some() {
+            lease.stale = None;
+            self.usage += lease_usage(&key, &lease);
+            self.leases.insert(key, lease);
+        }
+        true
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        memory::hash_map_usage(&self.leases) + memory::vec_usage::<(Key, u64)>(self.updates.capacity()) + self.usage
+    }
+
+    fn update(&mut self, key: &Key, lease: Lease) {
+        self.usage += memory::allocation(key.item.len()) + lease_usage(key, &lease);
+        self.updates.push_back((key.clone(), lease.since));
+        self.leases.insert(key.clone(), lease);
+    }
+
+    fn take(&mut self, key: &Key) -> Option<Lease> {
+        let lease = self.leases.remove(key);
+        if let Some(ref lease) = lease {
+            self.usage -= lease_usage(key, lease);
+        }
+        lease
+    }
+
+    fn purge(&mut self, now: u64) {
+        while let Some((key, since)) = self.updates.pop_front() {
+            if since + self.duration > now {
+                self.updates.push_front((key, since));
+                break;
+            }
+            self.usage -= memory::allocation(key.item.len());
+
+            let expired = match self.leases.get(&key) {
+                Some(lease) => lease.since == since,
+                None => false,
+            };
+            if expired {
+                self.take(&key);
+            }
+        }
+    }
+}
+
+/**
+ * Memory held by a lease beyond its slot in the map, the key it is stored under and its stale value
+ */
+fn lease_usage(key: &Key, lease: &Lease) -> usize {
+    memory::allocation(key.item.len()) + lease.stale.as_ref().map_or(0, |stale| stale.memory_usage())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::cache::tests::{key, entry};
+
+    #[test]
+    fn only_the_first_miss_gets_a_token() {
+        let mut leases = Leases::new(10);
+        let token = leases.acquire(&key("k")).unwrap();
+        assert!(leases.acquire(&key("k")).unwrap_err().is_none());
+        assert!(leases.acquire(&key("other")).is_ok());
+
+        assert!(!leases.redeem(&key("k"), token + 100));
+        assert!(leases.redeem(&key("k"), token));
+        assert!(!leases.redeem(&key("k"), token));
+
+        let next = leases.acquire(&key("k")).unwrap();
+        assert_ne!(next, token);
+    }
+
+    #[test]
+    fn a_delete_invalidates_the_lease_and_leaves_the_value_stale() {
+        let mut leases = Leases::new(10);
+        let token = leases.acquire(&key("k")).unwrap();
+        leases.invalidate(&key("k"), Some(entry("k", "v")));
+        assert!(!leases.redeem(&key("k"), token));
+
+        // The next miss fills the key while the others are handed the stale value
+        let token = leases.acquire(&key("k")).unwrap();
+        assert_eq!(leases.acquire(&key("k")).unwrap_err().unwrap().key, key("k"));
+        assert!(leases.redeem(&key("k"), token));
+        assert!(leases.acquire(&key("k")).is_ok());
+    }
+
+    #[test]
+    fn leases_run_out() {
+        let mut leases = Leases::new(0);
+        let token = leases.acquire(&key("k")).unwrap();
+        assert!(leases.acquire(&key("k")).is_ok());
+        assert!(!leases.redeem(&key("k"), token));
+    }
+
+    #[test]
+    fn stale_values_count_towards_memory_and_are_shed_first() {
+        let mut leases = Leases::new(10);
+        let empty = leases.memory_usage();
+        assert!(!leases.shed_stale());
+
+        let value = "x".repeat(1000);
+        leases.invalidate(&key("a"), Some(entry("a", &value)));
+        leases.invalidate(&key("b"), Some(entry("b", &value)));
+        let token = leases.acquire(&key("a")).unwrap();
+        let usage = leases.memory_usage();
+        assert!(usage > empty + 2 * 1000);
+
+        // The oldest stale value goes, the lease on its key stays
+        assert!(leases.shed_stale());
+        assert!(leases.memory_usage() < usage - 1000);
+        assert!(leases.acquire(&key("a")).unwrap_err().is_none());
+        assert!(leases.redeem(&key("a"), token));
+        assert!(leases.acquire(&key("b")).is_ok());
+        assert!(leases.acquire(&key("b")).unwrap_err().is_some());
+
+        assert!(leases.shed_stale());
+        assert!(!leases.shed_stale());
+        leases.clear();
+        assert_eq!(leases.usage, 0);
+    }
+}
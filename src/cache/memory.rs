@@ -0,0 +1,64 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
+use std::mem;
+
+use linked_hash_map::LinkedHashMap;
+
+/**
+ * Estimates of how much heap memory the collections used by the cache really take up.  Every
+ * heap allocation is rounded the way glibc malloc rounds chunks: an 8 byte header, 16 byte
+ * alignment and a 32 byte minimum.
+ */
+pub fn allocation(bytes: usize) -> usize {
+    if bytes == 0 {
+        return 0;
+    }
+    ((bytes + 8 + 15) & !15).max(32)
+}
+
+/**
+ * A `Vec` or `VecDeque` with room for `capacity` elements
+ */
+pub fn vec_usage<T>(capacity: usize) -> usize {
+    allocation(capacity * mem::size_of::<T>())
+}
+
+/**
+ * A `HashMap` stores its pairs in a power of two number of buckets, kept at most 7/8 full, with
+ * one control byte per bucket and an extra group of control bytes
+ */
+pub fn hash_map_usage<K: Eq + Hash, V>(map: &HashMap<K, V>) -> usize {
+    table_usage(map.capacity(), mem::size_of::<(K, V)>())
+}
+
+/**
+ * A `HashSet` is a `HashMap` without values
+ */
+pub fn hash_set_usage<T: Eq + Hash>(set: &HashSet<T>) -> usize {
+    table_usage(set.capacity(), mem::size_of::<T>())
+}
+
+/**
+ * A `LinkedHashMap` allocates a node per entry and indexes the nodes with a `HashMap` of pointers
+ */
+pub fn linked_hash_map_usage<K: Eq + Hash, V>(map: &LinkedHashMap<K, V>) -> usize {
+    let node = allocation(2 * mem::size_of::<usize>() + mem::size_of::<(K, V)>());
+    table_usage(map.capacity(), 2 * mem::size_of::<usize>()) + (map.len() + 1) * node
+}
+
+/**
+ * A `BTreeMap` packs up to 11 pairs into each node, which are about two thirds full on average
+ */
+pub fn btree_map_usage<K, V>(map: &BTreeMap<K, V>) -> usize {
+    let node = allocation(11 * mem::size_of::<(K, V)>() + 2 * mem::size_of::<usize>());
+    map.len().div_ceil(7) * node
+}
+
+fn table_usage(capacity: usize, pair: usize) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+
+    let buckets = (capacity * 8 / 7).next_power_of_two();
+    allocation(buckets * (pair + 1) + 16)
+}
@@ -1,7 +1,20 @@
 pub mod cache;
+pub mod sharded_cache;
+pub mod read_buffer;
+pub mod namespace;
 pub mod key;
 pub mod value;
 pub mod data_entry;
 pub mod storage_structure;
 pub mod replacement_policy;
+pub mod frequency_sketch;
+pub mod slab;
+pub mod extstore;
+pub mod compression;
+pub mod lease;
+pub mod tags;
+pub mod prefix;
+pub mod watch;
+pub mod memory;
+pub mod time;
 pub mod error;
\ No newline at end of file
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use cache::sharded_cache::ShardedCache;
+use cache::storage_structure::CacheStorageStructure;
+use cache::replacement_policy::CacheReplacementPolicy;
+
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/**
+ * Builds the cache of a new namespace from its capacity and the name of its replacement policy
+ */
+pub type CacheFactory<T, R> = Box<dyn Fn(usize, &str) -> Result<ShardedCache<T, R>, String> + Send + Sync>;
+
+/**
+ * Builds a replacement policy from its name, None for an unknown policy
+ */
+pub type PolicyFactory<R> = Box<dyn Fn(&str) -> Option<R> + Send + Sync>;
+
+/**
+ * The cache of a namespace, its name and a key within it
+ */
+pub type Route<'a, T, R> = (Arc<ShardedCache<T, R>>, &'a str, &'a str);
+
+/**
+ * The caches of the tenants sharing the server.  Every namespace has its own cache so it only
+ * ever evicts its own entries, with its own capacity, replacement policy, default ttl and metrics.
+ *
+ * A connection works in the default namespace until it selects another one, and a key of the
+ * form `<namespace>:<key>` addresses `<key>` in that namespace whichever one the connection
+ * selected, as long as the namespace exists and `<key>` is not empty.  `<namespace>:` alone stays
+ * a key, or prefix, of the selected namespace.
+ */
+pub struct Namespaces<T, R> {
+    namespaces: RwLock<HashMap<String, Arc<ShardedCache<T, R>>>>,
+    factory: CacheFactory<T, R>,
+    policies: PolicyFactory<R>,
+}
+
+impl <T: CacheStorageStructure, R: CacheReplacementPolicy> Namespaces<T, R> {
+    pub fn new(default: ShardedCache<T, R>, factory: CacheFactory<T, R>, policies: PolicyFactory<R>) -> Namespaces<T, R> {
+        let mut namespaces = HashMap::new();
+        namespaces.insert(String::from(DEFAULT_NAMESPACE), Arc::new(default));
+
+        Namespaces {
+            namespaces: RwLock::new(namespaces),
+            factory,
+            policies,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<ShardedCache<T, R>>> {
+        self.namespaces.read().unwrap().get(name).cloned()
+    }
+
+    /**
+     * The namespace, its name and the key within it addressed by a key used in the namespace
+     * `current`
+     */
+    pub fn route<'a>(&self, current: &'a str, key: &'a str) -> Option<Route<'a, T, R>> {
+        let namespaces = self.namespaces.read().unwrap();
+
+        if let Some(separator) = key.find(':').filter(|separator| separator + 1 < key.len()) {
+            if let Some(cache) = namespaces.get(&key[..separator]) {
+                return Some((cache.clone(), &key[..separator], &key[separator + 1..]));
+            }
+        }
+
+        namespaces.get(current).map(|cache| (cache.clone(), current, key))
+    }
+
+    pub fn create(&self, name: &str, capacity: usize, policy: &str, ttl: u64) -> Result<(), String> {
+        if name.is_empty() || name.contains(':') {
+            return Err(format!("Invalid namespace name {}", name));
+        }
+
+        if self.get(name).is_some() {
+            return Err(format!("Namespace {} already exists", name));
+        }
+
+        let cache = (self.factory)(capacity, policy)?;
+        cache.for_each(|shard| shard.item_lifetime = ttl);
+
+        // Another connection may have created it while the cache was being built
+        let mut namespaces = self.namespaces.write().unwrap();
+        if namespaces.contains_key(name) {
+            return Err(format!("Namespace {} already exists", name));
+        }
+        namespaces.insert(String::from(name), Arc::new(cache));
+        Ok(())
+    }
+
+    pub fn resize(&self, name: &str, capacity: usize) -> Result<(), String> {
+        match self.get(name) {
+            Some(cache) => {
+                cache.resize(capacity);
+                Ok(())
+            },
+            None => Err(format!("Unknown namespace {}", name)),
+        }
+    }
+
+    /**
+     * Switch the replacement policy of every shard of a namespace
+     */
+    pub fn set_policy(&self, name: &str, policy: &str) -> Result<(), String> {
+        let cache = match self.get(name) {
+            Some(cache) => cache,
+            None => return Err(format!("Unknown namespace {}", name)),
+        };
+
+        if (self.policies)(policy).is_none() {
+            return Err(format!("Unknown replacement policy {}", policy));
+        }
+
+        cache.for_each(|shard| {
+            if let Some(replacement_policy) = (self.policies)(policy) {
+                shard.set_replacement_policy(replacement_policy);
+                shard.policy = policy.to_lowercase();
+            }
+        });
+        Ok(())
+    }
+
+    /**
+     * Remove a namespace, its entries are freed once the last request using it completes
+     */
+    pub fn drop_namespace(&self, name: &str) -> Result<(), String> {
+        if name == DEFAULT_NAMESPACE {
+            return Err(String::from("The default namespace cannot be dropped"));
+        }
+
+        match self.namespaces.write().unwrap().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(format!("Unknown namespace {}", name)),
+        }
+    }
+
+    /**
+     * All namespaces ordered by name
+     */
+    pub fn list(&self) -> Vec<(String, Arc<ShardedCache<T, R>>)> {
+        let mut namespaces: Vec<(String, Arc<ShardedCache<T, R>>)> = self.namespaces.read().unwrap()
+            .iter()
+            .map(|(name, cache)| (name.clone(), cache.clone()))
+            .collect();
+        namespaces.sort_by(|a, b| a.0.cmp(&b.0));
+        namespaces
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use cache::cache::Cache;
+    use cache::key::Key;
+    use cache::value::Value;
+    use cache::storage_structure::HashStorageStructure;
+    use cache::replacement_policy::{self, PolicyOptions};
+    use cache::watch::Watchers;
+
+    pub type Policy = Box<dyn CacheReplacementPolicy + Send + Sync>;
+
+    const OPTIONS: PolicyOptions = PolicyOptions { lfu_decay: 0, lfu_log_factor: 0, clock_references: 1 };
+
+    fn sharded(capacity: usize, policy: &str) -> Result<ShardedCache<HashStorageStructure, Policy>, String> {
+        let watchers = Arc::new(Watchers::new(1024));
+        let shards = (0..2).map(|_| {
+            let replacement_policy = replacement_policy::from_name(policy, &OPTIONS)
+                .ok_or_else(|| format!("Unknown replacement policy {}", policy))?;
+            let mut cache = Cache::new(capacity / 2, HashStorageStructure::new(), replacement_policy);
+            cache.policy = policy.to_lowercase();
+            cache.watchers = watchers.clone();
+            Ok(cache)
+        }).collect::<Result<Vec<_>, String>>()?;
+
+        Ok(ShardedCache::new(shards))
+    }
+
+    /**
+     * Namespaces of two shards each, sharing their watchers like the server does, with a default
+     * namespace using LRU
+     */
+    pub fn namespaces(capacity: usize) -> Namespaces<HashStorageStructure, Policy> {
+        let factory: CacheFactory<HashStorageStructure, Policy> = Box::new(|capacity, policy| {
+            sharded(capacity, if policy.is_empty() { "lru" } else { policy })
+        });
+        let policies: PolicyFactory<Policy> = Box::new(|policy| replacement_policy::from_name(policy, &OPTIONS));
+
+        Namespaces::new(sharded(capacity, "lru").unwrap(), factory, policies)
+    }
+
+    #[test]
+    fn set_policy_switches_every_shard_and_keeps_the_entries() {
+        let namespaces = namespaces(1024 * 1024);
+        let cache = namespaces.get(DEFAULT_NAMESPACE).unwrap();
+        for i in 0..10 {
+            let key = Key::new(format!("k{}", i));
+            assert!(cache.shard(&key).set(key.clone(), Value::new(String::from("v"))).is_ok());
+        }
+
+        assert!(namespaces.set_policy(DEFAULT_NAMESPACE, "LFU").is_ok());
+        assert!(namespaces.set_policy(DEFAULT_NAMESPACE, "nope").is_err());
+        assert!(namespaces.set_policy("missing", "lru").is_err());
+
+        cache.for_each(|shard| {
+            assert_eq!(shard.policy, "lfu");
+            assert_eq!(shard.replacement_policy.eviction_order().len(), shard.storage_structure.indices().len());
+        });
+        for i in 0..10 {
+            assert!(cache.get(Key::new(format!("k{}", i))).is_some());
+        }
+    }
+    #[test]
+    fn keys_are_routed_to_existing_namespaces() {
+        let namespaces = namespaces(1024 * 1024);
+        assert!(namespaces.create("tenant", 64 * 1024, "s3-fifo", 30).is_ok());
+        assert!(namespaces.create("tenant", 64 * 1024, "lru", 0).is_err());
+        assert!(namespaces.create("bad:name", 64 * 1024, "lru", 0).is_err());
+        assert!(namespaces.create("other", 64 * 1024, "nope", 0).is_err());
+
+        let tenant = namespaces.get("tenant").unwrap();
+        tenant.for_each(|shard| {
+            assert_eq!(shard.item_lifetime, 30);
+            assert_eq!(shard.policy, "s3-fifo");
+        });
+
+        let (cache, name, key) = namespaces.route(DEFAULT_NAMESPACE, "tenant:k").unwrap();
+        assert!(Arc::ptr_eq(&cache, &tenant));
+        assert_eq!((name, key), ("tenant", "k"));
+
+        // An unknown namespace is part of the key in the current namespace
+        let (_, name, key) = namespaces.route(DEFAULT_NAMESPACE, "missing:k").unwrap();
+        assert_eq!((name, key), (DEFAULT_NAMESPACE, "missing:k"));
+        let (_, name, key) = namespaces.route("tenant", "k").unwrap();
+        assert_eq!((name, key), ("tenant", "k"));
+
+        // Without a key after it the namespace name is a prefix in the current namespace
+        let (_, name, key) = namespaces.route(DEFAULT_NAMESPACE, "tenant:").unwrap();
+        assert_eq!((name, key), (DEFAULT_NAMESPACE, "tenant:"));
+
+        assert!(namespaces.drop_namespace(DEFAULT_NAMESPACE).is_err());
+        assert!(namespaces.drop_namespace("tenant").is_ok());
+        assert!(namespaces.route("tenant", "k").is_none());
+        assert_eq!(namespaces.list().iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), vec![DEFAULT_NAMESPACE]);
+    }
+}
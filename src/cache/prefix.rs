@@ -0,0 +1,136 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use cache::data_entry::DataEntry;
+use cache::memory;
+
+/**
+ * Keys flushed by prefix.  Flushing a prefix only records the generation it was flushed at, every
+ * entry remembers the generation it was stored at, and an entry stored before its key was flushed
+ * is treated as missing when it is next looked at.  Checking an entry takes one lookup per
+ * distinct length of the flushed prefixes.
+ *
+ * The flushed entries stay in memory until they are evicted, replaced or reclaimed.  Once every
+ * one of them is gone the flushed prefixes are forgotten.
+ */
+pub struct PrefixFlushes {
+    generation: u64,
+    flushed: HashMap<String, u64>,
+    lengths: BTreeMap<usize, usize>, // Number of flushed prefixes of each length
+    usage: usize,
+    pending: Arc<AtomicBool>, // Whether any prefix is flushed, for readers without the cache lock
+}
+
+impl PrefixFlushes {
+    pub fn new() -> PrefixFlushes {
+        PrefixFlushes {
+            generation: 0,
+            flushed: HashMap::new(),
+            lengths: BTreeMap::new(),
+            usage: 0,
+            pending: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /**
+     * The generation entries stored now belong to
+     */
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn flush(&mut self, prefix: &str) {
+        self.generation += 1;
+        self.pending.store(true, Ordering::Release);
+        if self.flushed.insert(String::from(prefix), self.generation).is_none() {
+            *self.lengths.entry(prefix.len()).or_insert(0) += 1;
+            self.usage += memory::allocation(prefix.len());
+        }
+    }
+
+    /**
+     * Whether the entry was stored before a prefix of its key was flushed
+     */
+    pub fn is_flushed(&self, entry: &DataEntry) -> bool {
+        if self.flushed.is_empty() {
+            return false;
+        }
+
+        let key = &entry.key.item;
+        for (&len, _) in self.lengths.range(..key.len() + 1) {
+            if !key.is_char_boundary(len) {
+                continue;
+            }
+            match self.flushed.get(&key[..len]) {
+                Some(&generation) if generation > entry.generation => return true,
+                _ => {},
+            }
+        }
+        false
+    }
+
+    /**
+     * Whether any flushed entries may still be around
+     */
+    pub fn pending(&self) -> bool {
+        !self.flushed.is_empty()
+    }
+
+    /**
+     * A flag that stays set while `pending` is true and can be checked from any thread
+     */
+    pub fn pending_flag(&self) -> Arc<AtomicBool> {
+        self.pending.clone()
+    }
+
+    /**
+     * Forget the flushed prefixes once none of the entries they flushed are left.  Generations keep
+     * counting up so entries stored before are never mistaken for newer ones.
+     */
+    pub fn clear(&mut self) {
+        self.flushed.clear();
+        self.lengths.clear();
+        self.usage = 0;
+        self.pending.store(false, Ordering::Release);
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        memory::hash_map_usage(&self.flushed) + memory::btree_map_usage(&self.lengths) + self.usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::cache::tests::entry;
+
+    fn stored_at(key: &str, generation: u64) -> DataEntry {
+        let mut entry = entry(key, "v");
+        entry.generation = generation;
+        entry
+    }
+
+    #[test]
+    fn only_entries_stored_before_the_flush_are_flushed() {
+        let mut prefixes = PrefixFlushes::new();
+        assert!(!prefixes.is_flushed(&stored_at("user:1", 0)));
+
+        prefixes.flush("user:");
+        assert!(prefixes.is_flushed(&stored_at("user:1", 0)));
+        assert!(prefixes.is_flushed(&stored_at("user:", 0)));
+        assert!(!prefixes.is_flushed(&stored_at("users", 0)));
+        assert!(!prefixes.is_flushed(&stored_at("user:1", prefixes.generation())));
+
+        // Flushing again flushes what was stored in between
+        let between = prefixes.generation();
+        prefixes.flush("user:");
+        assert!(prefixes.is_flushed(&stored_at("user:1", between)));
+        assert!(!prefixes.is_flushed(&stored_at("useré", 0)));
+
+        prefixes.clear();
+        assert!(!prefixes.pending());
+        assert!(!prefixes.is_flushed(&stored_at("user:1", 0)));
+        assert_eq!(prefixes.generation(), 2);
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+
+use cache::key::Key;
+use cache::data_entry::DataEntry;
+use cache::memory;
+
+const STRIPES: usize = 16;
+const STRIPE_CAPACITY: usize = 64;
+
+thread_local! {
+    static STRIPE: usize = {
+        let mut hasher = DefaultHasher::new();
+        thread::current().id().hash(&mut hasher);
+        hasher.finish() as usize % STRIPES
+    };
+}
+
+/**
+ * An access seen by the read path that the replacement policy has not been told about yet
+ */
+pub struct Access {
+    pub index: usize,
+    pub key: Key,
+    pub size: usize,
+    pub cost: u64,
+    pub time: u64,
+}
+
+/**
+ * Reads only hold a shared lock on the cache, so instead of updating the replacement policy they
+ * record their access here to be replayed in a batch by the next writer.  Each thread records into
+ * its own stripe, a stripe that is busy or full simply loses the access.  Policies only need an
+ * approximate picture of the accesses so this trades a little accuracy for reads that never wait
+ * on each other.
+ *
+ * Hits and misses are counted here as well since the metrics of the cache need a writer, and entries
+ * found to be corrupt are kept for the writer to remove.
+ */
+pub struct ReadBuffer {
+    stripes: Vec<Mutex<Vec<Access>>>,
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub dropped: AtomicU64,
+    full: AtomicBool,
+    corrupt: Mutex<Vec<DataEntry>>,
+}
+
+impl ReadBuffer {
+    pub fn new() -> ReadBuffer {
+        ReadBuffer {
+            stripes: (0..STRIPES).map(|_| Mutex::new(Vec::new())).collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            full: AtomicBool::new(false),
+            corrupt: Mutex::new(Vec::new()),
+        }
+    }
+
+    /**
+     * Record an access in the stripe of the current thread
+     */
+    pub fn record(&self, access: Access) {
+        let stripe = STRIPE.with(|stripe| *stripe);
+
+        match self.stripes[stripe].try_lock() {
+            Ok(ref mut accesses) if accesses.len() < STRIPE_CAPACITY => {
+                accesses.push(access);
+                if accesses.len() >= STRIPE_CAPACITY {
+                    self.full.store(true, Ordering::Relaxed);
+                }
+            },
+            _ => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /**
+     * Whether a stripe has filled up and the buffer should be drained
+     */
+    pub fn needs_drain(&self) -> bool {
+        self.full.load(Ordering::Relaxed)
+    }
+
+    /**
+     * Take every recorded access, oldest first within each stripe
+     */
+    pub fn drain(&self) -> Vec<Access> {
+        self.full.store(false, Ordering::Relaxed);
+
+        let mut accesses = Vec::new();
+        for stripe in self.stripes.iter() {
+            accesses.append(&mut stripe.lock().unwrap());
+        }
+        accesses
+    }
+
+    /**
+     * Record an entry whose value could not be read back, to be removed by the next writer
+     */
+    pub fn record_corrupt(&self, entry: DataEntry) {
+        self.corrupt.lock().unwrap().push(entry);
+    }
+
+    /**
+     * Take every entry recorded as corrupt
+     */
+    pub fn drain_corrupt(&self) -> Vec<DataEntry> {
+        mem::take(&mut *self.corrupt.lock().unwrap())
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        let stripes: usize = self.stripes.iter().map(|stripe| {
+            let accesses = stripe.lock().unwrap();
+            let keys: usize = accesses.iter().map(|access| memory::allocation(access.key.item.capacity())).sum();
+            memory::vec_usage::<Access>(accesses.capacity()) + keys
+        }).sum();
+
+        memory::vec_usage::<Mutex<Vec<Access>>>(self.stripes.capacity()) + stripes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(index: usize) -> Access {
+        Access {
+            index,
+            key: Key::new(format!("k{}", index)),
+            size: 1,
+            cost: 1,
+            time: 0,
+        }
+    }
+
+    #[test]
+    fn a_full_stripe_drops_accesses_until_drained() {
+        let buffer = ReadBuffer::new();
+        for index in 0..STRIPE_CAPACITY {
+            assert!(!buffer.needs_drain());
+            buffer.record(access(index));
+        }
+        assert!(buffer.needs_drain());
+
+        buffer.record(access(STRIPE_CAPACITY));
+        assert_eq!(buffer.dropped.load(Ordering::Relaxed), 1);
+
+        let drained: Vec<usize> = buffer.drain().iter().map(|access| access.index).collect();
+        assert_eq!(drained, (0..STRIPE_CAPACITY).collect::<Vec<_>>());
+        assert!(!buffer.needs_drain());
+        assert!(buffer.drain().is_empty());
+    }
+}
@@ -1,35 +1,265 @@
-use std::collections::{VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::mem;
 
+use linked_hash_map::LinkedHashMap;
+
+use cache::key::Key;
 use cache::error::CacheError;
+use cache::frequency_sketch::CountMinSketch;
+use cache::memory;
 
 pub trait CacheReplacementPolicy {
-    fn new() -> Self;
-    fn update(&mut self, index: usize);
+    /**
+     * Record an access to the entry at index, `size` is the length of the entry in bytes and
+     * `cost` is the relative cost of fetching the entry again on a miss
+     */
+    fn update(&mut self, index: usize, key: &Key, size: usize, cost: u64);
     fn remove(&mut self, index: usize);
     fn evict_next(&mut self) -> Result<usize, CacheError>;
+
+    /**
+     * Called before space is made for a key that is not yet in the cache.  Returning false rejects
+     * the key instead of evicting existing entries on its behalf.
+     */
+    fn admit(&mut self, _key: &Key) -> bool {
+        true
+    }
+
+    /**
+     * Heap memory used by the bookkeeping of the policy in bytes
+     */
+    fn memory_usage(&self) -> usize;
+
+    /**
+     * The tracked indices roughly in the order they would be evicted, the first victim first.
+     * Replaying updates in this order into a fresh policy gives it a similar state.  An empty
+     * list means the policy cannot tell.
+     */
+    fn eviction_order(&self) -> Vec<usize> {
+        Vec::new()
+    }
+}
+
+impl<P: CacheReplacementPolicy + ?Sized> CacheReplacementPolicy for Box<P> {
+    fn update(&mut self, index: usize, key: &Key, size: usize, cost: u64) {
+        (**self).update(index, key, size, cost)
+    }
+
+    fn remove(&mut self, index: usize) {
+        (**self).remove(index)
+    }
+
+    fn evict_next(&mut self) -> Result<usize, CacheError> {
+        (**self).evict_next()
+    }
+
+    fn admit(&mut self, key: &Key) -> bool {
+        (**self).admit(key)
+    }
+
+    fn memory_usage(&self) -> usize {
+        (**self).memory_usage()
+    }
+
+    fn eviction_order(&self) -> Vec<usize> {
+        (**self).eviction_order()
+    }
+}
+
+/**
+ * Settings of the replacement policies that can be configured.  A `lfu_log_factor` of 0 makes LFU
+ * count every access, anything else switches it to the logarithmic counter with that factor.
+ * `clock_references` is the number of accesses CLOCK remembers per entry.
+ */
+#[derive(Clone, Copy)]
+pub struct PolicyOptions {
+    pub lfu_decay: usize,
+    pub lfu_log_factor: u64,
+    pub clock_references: u8,
+}
+
+/**
+ * Construct a replacement policy from its name, configured by the options that apply to it
+ */
+pub fn from_name(name: &str, options: &PolicyOptions) -> Option<Box<dyn CacheReplacementPolicy + Send + Sync>> {
+    match name.to_lowercase().as_str() {
+        "lru" => Some(Box::new(LRU::new())),
+        "clock" => Some(Box::new(Clock::with_references(options.clock_references))),
+        "clock-pro" => Some(Box::new(ClockPro::new())),
+        "lfu" => {
+            let counter = match options.lfu_log_factor {
+                0 => LFUCounter::Linear,
+                log_factor => LFUCounter::Logarithmic { log_factor },
+            };
+            Some(Box::new(LFU::with_config(counter, options.lfu_decay)))
+        },
+        "w-tinylfu" => Some(Box::new(WTinyLFU::new())),
+        "s3-fifo" => Some(Box::new(S3FIFO::new())),
+        "gdsf" => Some(Box::new(GDSF::new())),
+        _ => None,
+    }
 }
 
 pub struct LRU {
     recently_used: VecDeque<usize>, 
 }
 
+/**
+ * CLOCK over a ring of slots addressed by index.  Each slot holds a reference count of up to
+ * `max_references` (a single reference bit by default) which the hand decrements as it sweeps;
+ * the first resident slot found without references is the victim.  Slots are never shifted, a
+ * removed index simply leaves an empty slot for the hand to skip.
+ */
 pub struct Clock {
     hand: usize,
-    referenced_list: Vec<bool>,
+    referenced_list: Vec<Option<u8>>, // (index, references) where None is an unused index
+    max_references: u8,
+    len: usize,
 }
 
+/**
+ * CLOCK-Pro classifies resident entries as hot or cold and keeps recently evicted cold entries
+ * as non-resident test pages (key hash only).  A cold entry that is referenced again during its
+ * test period is promoted to hot, and a test page hit grows the share of the cache given to cold
+ * entries while test periods that expire unused shrink it again.
+ *
+ * All pages live on a single ring swept by three hands: the cold hand selects victims, the hot
+ * hand demotes unreferenced hot entries and the test hand terminates expired test periods.
+ */
+pub struct ClockPro {
+    pages: Vec<ProPage>,
+    free_pages: Vec<usize>,
+    resident: HashMap<usize, usize>, // (index, page)
+    tests: HashMap<u64, usize>, // (key hash, page)
+    hand_hot: Option<usize>,
+    hand_cold: Option<usize>,
+    hand_test: Option<usize>,
+    count_hot: usize,
+    count_cold: usize,
+    count_test: usize,
+    cold_target: usize,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum PageKind {
+    Hot,
+    Cold,
+    Test,
+}
+
+struct ProPage {
+    hash: u64,
+    index: usize,
+    kind: PageKind,
+    referenced: bool,
+    prev: usize,
+    next: usize,
+}
+
+/**
+ * An LFU.  Indices are grouped into buckets by access count and each bucket is kept in LRU order,
+ * so the victim is the least recently used index of the lowest occupied count.  The buckets are
+ * kept ordered by count so the lowest one is always the first.  Counts are halved every
+ * `decay_period` accesses so that entries which were hot in the past eventually become evictable
+ * again.
+ */
 pub struct LFU {
-    frequency_list: Vec<usize>, // (index, hits)
+    frequencies: HashMap<usize, u64>, // (index, count)
+    buckets: BTreeMap<u64, LinkedHashMap<usize, ()>>, // (count, indices in LRU order)
+    counter: LFUCounter,
+    decay_period: usize,
+    accesses: usize,
+    seed: u64,
 }
 
-impl CacheReplacementPolicy for LRU {
-    fn new() -> Self {
+/**
+ * How an LFU counts accesses.  `Linear` counts every access while `Logarithmic` behaves like the
+ * Redis LFU counter: it starts at 5, saturates at 255 and the probability of an increment shrinks
+ * as the count grows, so only the relative popularity of an entry is retained.
+ */
+#[derive(Clone, Copy)]
+pub enum LFUCounter {
+    Linear,
+    Logarithmic { log_factor: u64 },
+}
+
+/**
+ * S3-FIFO keeps new entries in a small probationary FIFO and only moves them into the main FIFO
+ * if they were accessed again before reaching its head.  Entries evicted from the small FIFO
+ * leave their key hash in a ghost FIFO so that a quickly returning key is inserted straight into
+ * the main FIFO.  The main FIFO is a CLOCK over two frequency bits.
+ */
+pub struct S3FIFO {
+    small: VecDeque<usize>,
+    main: VecDeque<usize>,
+    ghost: LinkedHashMap<u64, ()>,
+    entries: HashMap<usize, S3Entry>,
+    small_percent: usize,
+}
+
+struct S3Entry {
+    hash: u64,
+    frequency: u8,
+}
+
+/**
+ * Greedy-Dual-Size-Frequency weighs how often an entry is used and how expensive it is to fetch
+ * against how much space it takes up.  Each entry has a priority of `L + frequency * cost / size`
+ * and the entry with the lowest priority is evicted, after which `L` is inflated to the priority
+ * of the victim so that entries which are no longer accessed age out over time.  A large value
+ * therefore has to be accessed proportionally more often than a small one to stay cached.
+ */
+pub struct GDSF {
+    entries: HashMap<usize, GDSFEntry>,
+    priorities: BTreeMap<(u64, u64), usize>, // ((priority, sequence), index)
+    inflation: f64,
+    sequence: u64,
+}
+
+struct GDSFEntry {
+    frequency: u64,
+    priority: (u64, u64),
+}
+
+/**
+ * The region of a W-TinyLFU policy that an index currently belongs to.
+ */
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Region {
+    Window,
+    Probation,
+    Protected,
+}
+
+/**
+ * W-TinyLFU keeps new entries in a small admission window LRU and the remainder of the cache in a
+ * segmented LRU (probation and protected).  Entries that overflow the window become candidates for
+ * the main region and only displace the probation victim if the frequency sketch estimates that
+ * the candidate is accessed more often, which protects the main region from one-hit wonders.
+ *
+ * With a window of 0% the policy behaves as plain TinyLFU and rejects new keys at admission time.
+ */
+pub struct WTinyLFU {
+    window: LinkedHashMap<usize, u64>, // (index, key hash)
+    probation: LinkedHashMap<usize, u64>,
+    protected: LinkedHashMap<usize, u64>,
+    candidates: VecDeque<usize>,
+    sketch: CountMinSketch,
+    window_percent: usize,
+    protected_percent: usize,
+    admitted: Option<u64>,
+}
+
+impl LRU {
+    pub fn new() -> LRU {
         LRU { 
             recently_used: VecDeque::new()
         }
     }
+}
 
-    fn update(&mut self, index: usize) {
+impl CacheReplacementPolicy for LRU {
+    fn update(&mut self, index: usize, _key: &Key, _size: usize, _cost: u64) {
         let mut target_index: usize = 0;
         for iter_index in self.recently_used.iter() {
             if *iter_index == index {
@@ -38,7 +268,7 @@ impl CacheReplacementPolicy for LRU {
             target_index += 1;
         }
         self.recently_used.remove(target_index);
-        self.recently_used.push_back(target_index);
+        self.recently_used.push_back(index);
     }
 
     fn remove(&mut self, index: usize) {
@@ -58,87 +288,1062 @@ impl CacheReplacementPolicy for LRU {
             None => Err(CacheError::EvictionFailure)
         }
     }
+
+    fn memory_usage(&self) -> usize {
+        memory::vec_usage::<usize>(self.recently_used.capacity())
+    }
+
+    fn eviction_order(&self) -> Vec<usize> {
+        self.recently_used.iter().cloned().collect()
+    }
 }
 
-impl CacheReplacementPolicy for Clock {
-    fn new() -> Self {
+impl Clock {
+    /**
+     * Create a CLOCK that remembers up to `max_references` accesses per entry
+     */
+    pub fn with_references(max_references: u8) -> Clock {
         Clock {
             hand: 0,
             referenced_list: Vec::new(),
-         }
+            max_references: max_references.max(1),
+            len: 0,
+        }
     }
+}
 
-    fn update(&mut self, index: usize) {
-        if index < self.referenced_list.len() {
-            self.referenced_list[index] = true;
-        } else {
-            self.referenced_list.insert(index, true);
+impl CacheReplacementPolicy for Clock {
+    fn update(&mut self, index: usize, _key: &Key, _size: usize, _cost: u64) {
+        if index >= self.referenced_list.len() {
+            self.referenced_list.resize(index + 1, None);
         }
+
+        self.referenced_list[index] = match self.referenced_list[index] {
+            Some(references) => Some(references.saturating_add(1).min(self.max_references)),
+            None => {
+                self.len += 1;
+                Some(1)
+            }
+        };
     }
 
     fn remove(&mut self, index: usize) {
-        self.referenced_list.remove(index);
+        if let Some(slot) = self.referenced_list.get_mut(index) {
+            if slot.take().is_some() {
+                self.len -= 1;
+            }
+        }
     }
     
     fn evict_next(&mut self) -> Result<usize, CacheError> {        
-        'outer: loop {
-            if self.referenced_list.len() == 0 {
-                return Err(CacheError::EvictionFailure);
+        if self.len == 0 {
+            return Err(CacheError::EvictionFailure);
+        }
+
+        // Every full sweep takes a reference from each entry so this terminates
+        loop {
+            if self.hand >= self.referenced_list.len() {
+                self.hand = 0;
+            }
+
+            let index = self.hand;
+            self.hand += 1;
+
+            match self.referenced_list[index] {
+                Some(0) => {
+                    self.referenced_list[index] = None;
+                    self.len -= 1;
+                    return Ok(index);
+                },
+                Some(references) => {
+                    self.referenced_list[index] = Some(references - 1);
+                },
+                None => {},
             }
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        memory::vec_usage::<Option<u8>>(self.referenced_list.capacity())
+    }
+
+    fn eviction_order(&self) -> Vec<usize> {
+        // The hand reaches slots in ring order and passes over each reference once
+        let len = self.referenced_list.len();
+        let mut order: Vec<(u8, usize)> = (0..len)
+            .map(|offset| (self.hand + offset) % len)
+            .filter_map(|index| self.referenced_list[index].map(|references| (references, index)))
+            .collect();
+        order.sort_by_key(|&(references, _)| references);
+        order.into_iter().map(|(_, index)| index).collect()
+    }
+}
+
+impl ClockPro {
+    pub fn new() -> ClockPro {
+        ClockPro {
+            pages: Vec::new(),
+            free_pages: Vec::new(),
+            resident: HashMap::new(),
+            tests: HashMap::new(),
+            hand_hot: None,
+            hand_cold: None,
+            hand_test: None,
+            count_hot: 0,
+            count_cold: 0,
+            count_test: 0,
+            cold_target: 1,
+        }
+    }
+
+    fn link(&mut self, hash: u64, index: usize, kind: PageKind) -> usize {
+        let page = ProPage {
+            hash,
+            index,
+            kind,
+            referenced: false,
+            prev: 0,
+            next: 0,
+        };
+
+        let id = match self.free_pages.pop() {
+            Some(id) => {
+                self.pages[id] = page;
+                id
+            },
+            None => {
+                self.pages.reserve_exact(1);
+                self.pages.push(page);
+                self.pages.len() - 1
+            }
+        };
+
+        // New pages are placed at the head of the ring, just behind the hot hand
+        match self.hand_hot {
+            Some(head) => {
+                let tail = self.pages[head].prev;
+                self.pages[id].prev = tail;
+                self.pages[id].next = head;
+                self.pages[tail].next = id;
+                self.pages[head].prev = id;
+            },
+            None => {
+                self.pages[id].prev = id;
+                self.pages[id].next = id;
+                self.hand_hot = Some(id);
+                self.hand_cold = Some(id);
+                self.hand_test = Some(id);
+            }
+        }
+
+        match kind {
+            PageKind::Hot => self.count_hot += 1,
+            PageKind::Cold => self.count_cold += 1,
+            PageKind::Test => self.count_test += 1,
+        }
+
+        id
+    }
+
+    fn unlink(&mut self, id: usize) {
+        let (prev, next) = (self.pages[id].prev, self.pages[id].next);
+        let successor = if next == id { None } else { Some(next) };
+
+        self.pages[prev].next = next;
+        self.pages[next].prev = prev;
 
-            for value in self.referenced_list.iter_mut().skip(self.hand) {
-                self.hand += 1;
+        if self.hand_hot == Some(id) {
+            self.hand_hot = successor;
+        }
+        if self.hand_cold == Some(id) {
+            self.hand_cold = successor;
+        }
+        if self.hand_test == Some(id) {
+            self.hand_test = successor;
+        }
+
+        match self.pages[id].kind {
+            PageKind::Hot => self.count_hot -= 1,
+            PageKind::Cold => self.count_cold -= 1,
+            PageKind::Test => {
+                self.count_test -= 1;
+                let hash = self.pages[id].hash;
+                if self.tests.get(&hash) == Some(&id) {
+                    self.tests.remove(&hash);
+                }
+            },
+        }
 
-                if *value {
-                    *value = false;
+        self.free_pages.push(id);
+    }
+
+    fn hot_capacity(&self) -> usize {
+        self.resident.len().saturating_sub(self.cold_target)
+    }
+
+    /**
+     * Clear the reference of the hot page under the hand or demote it to cold if it was not
+     * referenced since the last sweep.  Test pages passed by the hot hand have their test period
+     * terminated.
+     */
+    fn run_hand_hot(&mut self) {
+        let id = match self.hand_hot {
+            Some(id) => id,
+            None => return,
+        };
+
+        match self.pages[id].kind {
+            PageKind::Hot => {
+                if self.pages[id].referenced {
+                    self.pages[id].referenced = false;
                 } else {
-                    break 'outer;
+                    self.pages[id].kind = PageKind::Cold;
+                    self.count_hot -= 1;
+                    self.count_cold += 1;
+                }
+                self.hand_hot = Some(self.pages[id].next);
+            },
+            PageKind::Test => {
+                self.unlink(id);
+                self.cold_target = self.cold_target.saturating_sub(1).max(1);
+            },
+            PageKind::Cold => {
+                self.hand_hot = Some(self.pages[id].next);
+            },
+        }
+    }
+
+    /**
+     * Terminate the test period of the test page under the hand
+     */
+    fn run_hand_test(&mut self) {
+        let id = match self.hand_test {
+            Some(id) => id,
+            None => return,
+        };
+
+        if self.pages[id].kind == PageKind::Test {
+            self.unlink(id);
+            self.cold_target = self.cold_target.saturating_sub(1).max(1);
+        } else {
+            self.hand_test = Some(self.pages[id].next);
+        }
+    }
+
+    /**
+     * Sweep the cold hand until a cold page without a reference is found and turn it into a test
+     * page.  Referenced cold pages are promoted to hot on the way.
+     */
+    fn run_hand_cold(&mut self) -> usize {
+        loop {
+            // Without cold pages the hot hand has to demote one first
+            while self.count_cold == 0 {
+                self.run_hand_hot();
+            }
+
+            let id = self.hand_cold.unwrap();
+            self.hand_cold = Some(self.pages[id].next);
+
+            if self.pages[id].kind != PageKind::Cold {
+                continue;
+            }
+
+            if self.pages[id].referenced {
+                self.pages[id].referenced = false;
+                self.pages[id].kind = PageKind::Hot;
+                self.count_cold -= 1;
+                self.count_hot += 1;
+
+                while self.count_hot > self.hot_capacity() && self.count_cold + self.count_hot > 1 {
+                    self.run_hand_hot();
+                }
+                continue;
+            }
+
+            let (hash, index) = (self.pages[id].hash, self.pages[id].index);
+            self.resident.remove(&index);
+            self.pages[id].kind = PageKind::Test;
+            self.count_cold -= 1;
+            self.count_test += 1;
+
+            if let Some(previous) = self.tests.insert(hash, id) {
+                self.unlink(previous);
+            }
+
+            // Only remember as many test pages as there are resident pages
+            while self.count_test > self.resident.len().max(1) {
+                self.run_hand_test();
+            }
+
+            return index;
+        }
+    }
+}
+
+impl CacheReplacementPolicy for ClockPro {
+    fn update(&mut self, index: usize, key: &Key, _size: usize, _cost: u64) {
+        if let Some(id) = self.resident.get(&index) {
+            self.pages[*id].referenced = true;
+            return;
+        }
+
+        let hash = key.digest();
+        let id = match self.tests.get(&hash).cloned() {
+            Some(test) => {
+                // Reaccessed during its test period, the entry should have stayed cached as hot
+                self.unlink(test);
+                self.cold_target = (self.cold_target + 1).min(self.resident.len() + 1);
+                self.link(hash, index, PageKind::Hot)
+            },
+            None => self.link(hash, index, PageKind::Cold),
+        };
+        self.resident.insert(index, id);
+    }
+
+    fn remove(&mut self, index: usize) {
+        if let Some(id) = self.resident.remove(&index) {
+            self.unlink(id);
+        }
+    }
+
+    fn evict_next(&mut self) -> Result<usize, CacheError> {
+        if self.resident.is_empty() {
+            return Err(CacheError::EvictionFailure);
+        }
+
+        Ok(self.run_hand_cold())
+    }
+
+    fn memory_usage(&self) -> usize {
+        memory::vec_usage::<ProPage>(self.pages.capacity())
+            + memory::vec_usage::<usize>(self.free_pages.capacity())
+            + memory::hash_map_usage(&self.resident)
+            + memory::hash_map_usage(&self.tests)
+    }
+
+    fn eviction_order(&self) -> Vec<usize> {
+        // Cold pages from the cold hand onwards, then the hot pages that would be demoted later
+        let mut cold = Vec::new();
+        let mut hot = Vec::new();
+
+        if let Some(start) = self.hand_cold {
+            let mut id = start;
+            loop {
+                match self.pages[id].kind {
+                    PageKind::Cold => cold.push(self.pages[id].index),
+                    PageKind::Hot => hot.push(self.pages[id].index),
+                    PageKind::Test => {},
+                }
+
+                id = self.pages[id].next;
+                if id == start {
+                    break;
                 }
             }
+        }
+
+        cold.extend(hot);
+        cold
+    }
+}
 
-            self.hand = 0;
+impl LFU {
+    /**
+     * Create a policy using the given counter that halves every count each `decay_period`
+     * accesses, a period of 0 disables aging
+     */
+    pub fn with_config(counter: LFUCounter, decay_period: usize) -> LFU {
+        LFU {
+            frequencies: HashMap::new(),
+            buckets: BTreeMap::new(),
+            counter,
+            decay_period,
+            accesses: 0,
+            seed: 0x2545f4914f6cdd1d,
         }
+    }
 
-        self.referenced_list.remove(self.hand - 1);
-        Ok(self.hand - 1)
+    fn initial_frequency(&self) -> u64 {
+        match self.counter {
+            LFUCounter::Linear => 1,
+            LFUCounter::Logarithmic { .. } => 5,
+        }
+    }
+
+    fn increment(&mut self, frequency: u64) -> u64 {
+        match self.counter {
+            LFUCounter::Linear => frequency.saturating_add(1),
+            LFUCounter::Logarithmic { log_factor } => {
+                if frequency >= 255 {
+                    return frequency;
+                }
+
+                // xorshift, a weak generator is plenty for a probabilistic counter
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 7;
+                self.seed ^= self.seed << 17;
+
+                let base = frequency.saturating_sub(self.initial_frequency());
+                let threshold = u64::MAX / (base * log_factor + 1);
+                if self.seed <= threshold { frequency + 1 } else { frequency }
+            },
+        }
+    }
+
+    fn insert_bucket(&mut self, index: usize, frequency: u64) {
+        self.buckets.entry(frequency).or_default().insert(index, ());
+        self.frequencies.insert(index, frequency);
+    }
+
+    fn remove_bucket(&mut self, index: usize, frequency: u64) {
+        let empty = match self.buckets.get_mut(&frequency) {
+            Some(bucket) => {
+                bucket.remove(&index);
+                bucket.is_empty()
+            },
+            None => false,
+        };
+
+        if empty {
+            self.buckets.remove(&frequency);
+        }
+    }
+
+    /**
+     * Halve every count, merging buckets from the lowest count upwards so that entries which were
+     * less popular before aging stay ahead of more popular ones within the merged bucket
+     */
+    fn decay(&mut self) {
+        let mut buckets = BTreeMap::new();
+        for (frequency, indices) in mem::take(&mut self.buckets) {
+            let halved = frequency / 2;
+            let bucket = buckets.entry(halved).or_insert_with(LinkedHashMap::new);
+            for (index, _) in indices {
+                bucket.insert(index, ());
+                self.frequencies.insert(index, halved);
+            }
+        }
+
+        self.buckets = buckets;
     }
 }
 
 impl CacheReplacementPolicy for LFU {
-    fn new() -> Self {
-        LFU { 
-            frequency_list: Vec::new()
+    fn update(&mut self, index: usize, _key: &Key, _size: usize, _cost: u64) {
+        match self.frequencies.get(&index).cloned() {
+            Some(frequency) => {
+                let next = self.increment(frequency);
+                if next == frequency {
+                    // Still counts as the most recent use within the bucket
+                    if let Some(bucket) = self.buckets.get_mut(&frequency) {
+                        bucket.get_refresh(&index);
+                    }
+                } else {
+                    self.remove_bucket(index, frequency);
+                    self.insert_bucket(index, next);
+                }
+            },
+            None => {
+                let frequency = self.initial_frequency();
+                self.insert_bucket(index, frequency);
+            }
+        }
+
+        self.accesses += 1;
+        if self.decay_period > 0 && self.accesses >= self.decay_period {
+            self.accesses = 0;
+            self.decay();
         }
     }
 
-    fn update(&mut self, index: usize) {
-        if index < self.frequency_list.len() {
-            self.frequency_list[index] = self.frequency_list[index] + 1;
+    fn remove(&mut self, index: usize) {
+        if let Some(frequency) = self.frequencies.remove(&index) {
+            self.remove_bucket(index, frequency);
+        }
+    }
+    
+    fn evict_next(&mut self) -> Result<usize, CacheError> {
+        let frequency = match self.buckets.keys().next() {
+            Some(&frequency) => frequency,
+            None => return Err(CacheError::EvictionFailure),
+        };
+
+        let index = match self.buckets.get_mut(&frequency).and_then(|bucket| bucket.pop_front()) {
+            Some((index, _)) => index,
+            None => return Err(CacheError::EvictionFailure),
+        };
+
+        self.remove_bucket(index, frequency);
+        self.frequencies.remove(&index);
+        Ok(index)
+    }
+
+    fn memory_usage(&self) -> usize {
+        let buckets: usize = self.buckets.values().map(memory::linked_hash_map_usage).sum();
+        memory::hash_map_usage(&self.frequencies) + memory::btree_map_usage(&self.buckets) + buckets
+    }
+
+    fn eviction_order(&self) -> Vec<usize> {
+        self.buckets.values()
+            .flat_map(|bucket| bucket.keys().cloned())
+            .collect()
+    }
+}
+
+impl WTinyLFU {
+    pub fn new() -> WTinyLFU {
+        WTinyLFU::with_config(1, 80, 1024)
+    }
+
+    /**
+     * Create a policy with the given admission window and protected segment sizes (as a percentage
+     * of the entries tracked) and a frequency sketch with `sketch_width` counters per row.
+     */
+    pub fn with_config(window_percent: usize, protected_percent: usize, sketch_width: usize) -> WTinyLFU {
+        WTinyLFU {
+            window: LinkedHashMap::new(),
+            probation: LinkedHashMap::new(),
+            protected: LinkedHashMap::new(),
+            candidates: VecDeque::new(),
+            sketch: CountMinSketch::new(sketch_width),
+            window_percent: window_percent.min(100),
+            protected_percent: protected_percent.min(100),
+            admitted: None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.window.len() + self.probation.len() + self.protected.len()
+    }
+
+    fn window_capacity(&self) -> usize {
+        if self.window_percent == 0 {
+            return 0;
+        }
+        (self.len() * self.window_percent / 100).max(1)
+    }
+
+    fn protected_capacity(&self) -> usize {
+        (self.probation.len() + self.protected.len()) * self.protected_percent / 100
+    }
+
+    fn region(&self, index: usize) -> Option<Region> {
+        if self.window.contains_key(&index) {
+            Some(Region::Window)
+        } else if self.probation.contains_key(&index) {
+            Some(Region::Probation)
+        } else if self.protected.contains_key(&index) {
+            Some(Region::Protected)
         } else {
-            self.frequency_list.insert(index, 1);
+            None
+        }
+    }
+
+    /**
+     * Move entries that overflow the admission window into probation as eviction candidates
+     */
+    fn drain_window(&mut self) {
+        while self.window.len() > self.window_capacity() {
+            match self.window.pop_front() {
+                Some((index, hash)) => {
+                    self.probation.insert(index, hash);
+                    self.candidates.push_back(index);
+                },
+                None => break,
+            }
+        }
+    }
+
+    /**
+     * Demote the least recently used protected entries until the protected segment fits
+     */
+    fn drain_protected(&mut self) {
+        while self.protected.len() > self.protected_capacity() {
+            match self.protected.pop_front() {
+                Some((index, hash)) => {
+                    self.probation.insert(index, hash);
+                },
+                None => break,
+            }
+        }
+    }
+
+    /**
+     * The entry that would be evicted from the main region next
+     */
+    fn main_victim(&self) -> Option<(usize, u64)> {
+        self.probation.front()
+            .or_else(|| self.protected.front())
+            .map(|(index, hash)| (*index, *hash))
+    }
+}
+
+impl CacheReplacementPolicy for WTinyLFU {
+    fn update(&mut self, index: usize, key: &Key, _size: usize, _cost: u64) {
+        let hash = key.digest();
+
+        match self.region(index) {
+            Some(Region::Window) => {
+                self.sketch.increment(hash);
+                self.window.get_refresh(&index);
+            },
+            Some(Region::Probation) => {
+                self.sketch.increment(hash);
+                self.probation.remove(&index);
+                self.protected.insert(index, hash);
+                self.drain_protected();
+            },
+            Some(Region::Protected) => {
+                self.sketch.increment(hash);
+                self.protected.get_refresh(&index);
+            },
+            None => {
+                // The access was already recorded if the key had to pass admission
+                if self.admitted.take() != Some(hash) {
+                    self.sketch.increment(hash);
+                }
+
+                self.window.insert(index, hash);
+                self.drain_window();
+            },
         }
     }
 
     fn remove(&mut self, index: usize) {
-        self.frequency_list.remove(index);
+        if self.window.remove(&index).is_none() && self.probation.remove(&index).is_none() {
+            self.protected.remove(&index);
+        }
     }
-    
+
     fn evict_next(&mut self) -> Result<usize, CacheError> {
-        if self.frequency_list.len() == 0 {
-            return Err(CacheError::EvictionFailure);
+        // The oldest candidate that is still waiting in probation duels with the probation victim
+        while let Some(candidate) = self.candidates.pop_front() {
+            let candidate_hash = match self.probation.get(&candidate) {
+                Some(hash) => *hash,
+                None => continue,
+            };
+
+            let (victim, victim_hash) = match self.probation.front() {
+                Some((index, hash)) => (*index, *hash),
+                None => break,
+            };
+
+            if victim == candidate {
+                // Nothing older to compete with, keep looking for a contested candidate
+                continue;
+            }
+
+            let evicted = if self.sketch.frequency(candidate_hash) > self.sketch.frequency(victim_hash) {
+                victim
+            } else {
+                candidate
+            };
+
+            self.probation.remove(&evicted);
+            return Ok(evicted);
+        }
+
+        if let Some((index, _)) = self.probation.pop_front() {
+            return Ok(index);
+        }
+        if let Some((index, _)) = self.protected.pop_front() {
+            return Ok(index);
+        }
+        match self.window.pop_front() {
+            Some((index, _)) => Ok(index),
+            None => Err(CacheError::EvictionFailure)
         }
+    }
+
+    fn admit(&mut self, key: &Key) -> bool {
+        let hash = key.digest();
+        self.sketch.increment(hash);
+
+        // The admission window absorbs new keys, the duel happens once they leave the window
+        let admitted = self.window_percent > 0 || match self.main_victim() {
+            Some((_, victim_hash)) => self.sketch.frequency(hash) > self.sketch.frequency(victim_hash),
+            None => true,
+        };
+
+        // A rejected key is never inserted, so it must not be mistaken for the next new entry
+        self.admitted = if admitted { Some(hash) } else { None };
+        admitted
+    }
 
-        let mut index = 0;
-        let mut target_index = 0;
-        for frequency in self.frequency_list.iter() {
-            if *frequency < self.frequency_list[target_index] {
-                target_index = index;
+    fn memory_usage(&self) -> usize {
+        memory::linked_hash_map_usage(&self.window)
+            + memory::linked_hash_map_usage(&self.probation)
+            + memory::linked_hash_map_usage(&self.protected)
+            + memory::vec_usage::<usize>(self.candidates.capacity())
+            + self.sketch.memory_usage()
+    }
+
+    fn eviction_order(&self) -> Vec<usize> {
+        self.probation.keys()
+            .chain(self.window.keys())
+            .chain(self.protected.keys())
+            .cloned()
+            .collect()
+    }
+}
+
+impl S3FIFO {
+    pub fn new() -> S3FIFO {
+        S3FIFO::with_config(10)
+    }
+
+    /**
+     * Create a policy where the small FIFO holds `small_percent` of the entries tracked
+     */
+    pub fn with_config(small_percent: usize) -> S3FIFO {
+        S3FIFO {
+            small: VecDeque::new(),
+            main: VecDeque::new(),
+            ghost: LinkedHashMap::new(),
+            entries: HashMap::new(),
+            small_percent: small_percent.min(100),
+        }
+    }
+
+    fn small_capacity(&self) -> usize {
+        (self.entries.len() * self.small_percent / 100).max(1)
+    }
+
+    fn remember(&mut self, hash: u64) {
+        self.ghost.insert(hash, ());
+
+        // The ghost FIFO remembers about as many keys as there are entries in the cache
+        while self.ghost.len() > self.entries.len().max(1) {
+            self.ghost.pop_front();
+        }
+    }
+
+    fn evict_small(&mut self) -> Option<usize> {
+        while let Some(index) = self.small.pop_front() {
+            let (hash, frequency) = match self.entries.get(&index) {
+                Some(entry) => (entry.hash, entry.frequency),
+                None => continue,
+            };
+
+            if frequency > 1 {
+                self.main.push_back(index);
+            } else {
+                self.entries.remove(&index);
+                self.remember(hash);
+                return Some(index);
+            }
+        }
+
+        None
+    }
+
+    fn evict_main(&mut self) -> Option<usize> {
+        while let Some(index) = self.main.pop_front() {
+            let reinsert = match self.entries.get_mut(&index) {
+                Some(entry) if entry.frequency > 0 => {
+                    entry.frequency -= 1;
+                    true
+                },
+                Some(_) => false,
+                None => continue,
+            };
+
+            if reinsert {
+                self.main.push_back(index);
+            } else {
+                self.entries.remove(&index);
+                return Some(index);
             }
+        }
+
+        None
+    }
+}
 
-            index += 1;
+impl CacheReplacementPolicy for S3FIFO {
+    fn update(&mut self, index: usize, key: &Key, _size: usize, _cost: u64) {
+        if let Some(entry) = self.entries.get_mut(&index) {
+            entry.frequency = (entry.frequency + 1).min(3);
+            return;
         }
 
-        self.frequency_list.remove(target_index);
-        Ok(target_index)
+        let hash = key.digest();
+        self.entries.insert(index, S3Entry { hash, frequency: 0 });
+
+        if self.ghost.remove(&hash).is_some() {
+            self.main.push_back(index);
+        } else {
+            self.small.push_back(index);
+        }
     }
-}
\ No newline at end of file
+
+    fn remove(&mut self, index: usize) {
+        if self.entries.remove(&index).is_none() {
+            return;
+        }
+
+        match self.small.iter().position(|i| *i == index) {
+            Some(position) => {
+                self.small.remove(position);
+            },
+            None => {
+                if let Some(position) = self.main.iter().position(|i| *i == index) {
+                    self.main.remove(position);
+                }
+            }
+        }
+    }
+
+    fn evict_next(&mut self) -> Result<usize, CacheError> {
+        let evicted = if self.small.len() >= self.small_capacity() || self.main.is_empty() {
+            self.evict_small().or_else(|| self.evict_main())
+        } else {
+            self.evict_main().or_else(|| self.evict_small())
+        };
+
+        match evicted {
+            Some(index) => Ok(index),
+            None => Err(CacheError::EvictionFailure)
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        memory::vec_usage::<usize>(self.small.capacity())
+            + memory::vec_usage::<usize>(self.main.capacity())
+            + memory::linked_hash_map_usage(&self.ghost)
+            + memory::hash_map_usage(&self.entries)
+    }
+
+    fn eviction_order(&self) -> Vec<usize> {
+        self.small.iter().chain(self.main.iter()).cloned().collect()
+    }
+}
+
+impl GDSF {
+    pub fn new() -> GDSF {
+        GDSF {
+            entries: HashMap::new(),
+            priorities: BTreeMap::new(),
+            inflation: 0.0,
+            sequence: 0,
+        }
+    }
+
+    fn priority(&mut self, frequency: u64, size: usize, cost: u64) -> (u64, u64) {
+        let priority = self.inflation + (frequency as f64) * (cost.max(1) as f64) / (size.max(1) as f64);
+        self.sequence += 1;
+
+        // The bit pattern of a positive float orders the same way as the float itself, the
+        // sequence number breaks ties in favour of evicting the least recently used entry
+        (priority.to_bits(), self.sequence)
+    }
+}
+
+impl CacheReplacementPolicy for GDSF {
+    fn update(&mut self, index: usize, _key: &Key, size: usize, cost: u64) {
+        let frequency = match self.entries.remove(&index) {
+            Some(entry) => {
+                self.priorities.remove(&entry.priority);
+                entry.frequency + 1
+            },
+            None => 1,
+        };
+
+        let priority = self.priority(frequency, size, cost);
+        self.priorities.insert(priority, index);
+        self.entries.insert(index, GDSFEntry { frequency, priority });
+    }
+
+    fn remove(&mut self, index: usize) {
+        if let Some(entry) = self.entries.remove(&index) {
+            self.priorities.remove(&entry.priority);
+        }
+    }
+
+    fn evict_next(&mut self) -> Result<usize, CacheError> {
+        let priority = match self.priorities.keys().next() {
+            Some(priority) => *priority,
+            None => return Err(CacheError::EvictionFailure),
+        };
+
+        let index = self.priorities.remove(&priority).unwrap();
+        self.entries.remove(&index);
+        self.inflation = f64::from_bits(priority.0);
+        Ok(index)
+    }
+
+    fn memory_usage(&self) -> usize {
+        memory::hash_map_usage(&self.entries) + memory::btree_map_usage(&self.priorities)
+    }
+
+    fn eviction_order(&self) -> Vec<usize> {
+        self.priorities.values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> Key {
+        Key::new(name.to_string())
+    }
+
+    #[test]
+    fn tinylfu_rejects_a_key_less_frequent_than_the_victim() {
+        let mut policy = WTinyLFU::with_config(0, 80, 1024);
+        assert!(policy.admit(&key("hot")));
+        for _ in 0..4 {
+            policy.update(0, &key("hot"), 1, 1);
+        }
+
+        assert!(!policy.admit(&key("cold")));
+        for _ in 0..4 {
+            policy.admit(&key("cold"));
+        }
+        assert!(policy.admit(&key("cold")));
+    }
+
+    #[test]
+    fn tinylfu_forgets_a_rejected_candidate() {
+        let mut policy = WTinyLFU::with_config(0, 80, 1024);
+        for _ in 0..4 {
+            policy.update(0, &key("hot"), 1, 1);
+        }
+
+        assert!(!policy.admit(&key("cold")));
+        assert_eq!(policy.admitted, None);
+
+        // Inserting the key anyway still counts the access
+        policy.update(1, &key("cold"), 1, 1);
+        assert_eq!(policy.sketch.frequency(key("cold").digest()), 2);
+    }
+
+    #[test]
+    fn wtinylfu_keeps_a_frequent_entry_over_one_hit_wonders() {
+        let mut policy = WTinyLFU::with_config(1, 80, 1024);
+        for _ in 0..5 {
+            policy.update(0, &key("hot"), 1, 1);
+        }
+
+        for index in 1..100 {
+            policy.update(index, &key(&format!("cold{}", index)), 1, 1);
+            if index > 1 {
+                assert_ne!(policy.evict_next().ok(), Some(0));
+            }
+        }
+        assert!(policy.region(0) == Some(Region::Probation));
+    }
+    #[test]
+    fn s3fifo_promotes_reaccessed_entries_and_remembers_ghosts() {
+        let mut policy = S3FIFO::with_config(10);
+        for index in 0..10 {
+            policy.update(index, &key(&format!("k{}", index)), 1, 1);
+        }
+        policy.update(0, &key("k0"), 1, 1);
+        policy.update(0, &key("k0"), 1, 1);
+
+        // The reaccessed head of the small FIFO moves to main and the next one is evicted
+        assert_eq!(policy.evict_next().ok(), Some(1));
+
+        // A key returning while its hash is still a ghost goes straight into main
+        policy.update(10, &key("k1"), 1, 1);
+        assert_eq!(policy.eviction_order(), vec![2, 3, 4, 5, 6, 7, 8, 9, 0, 10]);
+    }
+    #[test]
+    fn lfu_evicts_the_least_recently_used_of_the_lowest_count() {
+        let mut policy = LFU::with_config(LFUCounter::Linear, 0);
+        for index in 1..4 {
+            policy.update(index, &key("k"), 1, 1);
+        }
+        policy.update(1, &key("k"), 1, 1);
+        policy.update(3, &key("k"), 1, 1);
+
+        assert_eq!(policy.evict_next().ok(), Some(2));
+        assert_eq!(policy.evict_next().ok(), Some(1));
+        assert_eq!(policy.evict_next().ok(), Some(3));
+        assert!(policy.evict_next().is_err());
+    }
+
+    #[test]
+    fn lfu_halves_counts_every_decay_period() {
+        let mut policy = LFU::with_config(LFUCounter::Linear, 10);
+        for _ in 0..8 {
+            policy.update(1, &key("k"), 1, 1);
+        }
+        policy.update(2, &key("k"), 1, 1);
+        policy.update(3, &key("k"), 1, 1);
+
+        assert_eq!(policy.frequencies[&1], 4);
+        assert_eq!(policy.frequencies[&2], 0);
+        assert_eq!(policy.eviction_order(), vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn lfu_logarithmic_counter_saturates_slowly() {
+        let mut policy = LFU::with_config(LFUCounter::Logarithmic { log_factor: 10 }, 0);
+        policy.update(1, &key("k"), 1, 1);
+        assert_eq!(policy.frequencies[&1], 5);
+
+        for _ in 0..1000 {
+            policy.update(1, &key("k"), 1, 1);
+        }
+        let frequency = policy.frequencies[&1];
+        assert!(frequency > 5 && frequency < 100);
+
+        policy.update(2, &key("k"), 1, 1);
+        assert_eq!(policy.evict_next().ok(), Some(2));
+    }
+    #[test]
+    fn clock_skips_referenced_and_removed_slots() {
+        let mut policy = Clock::with_references(1);
+        for index in 0..4 {
+            policy.update(index, &key("k"), 1, 1);
+        }
+        policy.remove(3);
+
+        // Every slot is referenced, the first sweep clears them and the second evicts in order
+        assert_eq!(policy.evict_next().ok(), Some(0));
+        policy.update(1, &key("k"), 1, 1);
+        assert_eq!(policy.evict_next().ok(), Some(2));
+        assert_eq!(policy.evict_next().ok(), Some(1));
+        assert!(policy.evict_next().is_err());
+    }
+
+    #[test]
+    fn clock_pro_promotes_reaccessed_cold_entries() {
+        let mut policy = ClockPro::new();
+        for index in 0..4 {
+            policy.update(index, &key(&format!("k{}", index)), 1, 1);
+        }
+        assert_eq!(policy.evict_next().ok(), Some(0));
+
+        // The evicted key comes back during its test period and is inserted as hot
+        policy.update(4, &key("k0"), 1, 1);
+        assert!(policy.pages[policy.resident[&4]].kind == PageKind::Hot);
+
+        // A referenced cold entry is promoted when the cold hand passes it
+        policy.update(1, &key("k1"), 1, 1);
+        assert_eq!(policy.evict_next().ok(), Some(2));
+        assert!(policy.pages[policy.resident[&1]].kind == PageKind::Hot);
+    }
+    #[test]
+    fn gdsf_weighs_frequency_and_cost_against_size() {
+        let mut policy = GDSF::new();
+        policy.update(0, &key("small"), 10, 1);
+        policy.update(1, &key("large"), 1000, 1);
+        assert_eq!(policy.evict_next().ok(), Some(1));
+
+        // A large value accessed often enough outweighs a small one accessed once
+        policy.update(1, &key("large"), 1000, 1);
+        for _ in 0..200 {
+            policy.update(1, &key("large"), 1000, 1);
+        }
+        assert_eq!(policy.evict_next().ok(), Some(0));
+
+        // Of two values of the same size the cheaper one to fetch again goes first
+        policy.update(2, &key("cheap"), 100, 1);
+        policy.update(3, &key("expensive"), 100, 10);
+        assert_eq!(policy.eviction_order(), vec![2, 3, 1]);
+    }
+}
@@ -0,0 +1,277 @@
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
+
+use cache::cache::{Cache, ScanItem, OptimisticReader, OptimisticRead};
+use cache::key::Key;
+use cache::data_entry::DataEntry;
+use cache::watch::Watchers;
+use cache::storage_structure::CacheStorageStructure;
+use cache::replacement_policy::CacheReplacementPolicy;
+
+/**
+ * A cache split into independent shards, each a complete `Cache` with its own storage structure,
+ * replacement policy, slice of the capacity and metrics behind its own lock.  Keys are assigned to
+ * shards by their digest so requests for different keys rarely contend for the same lock.
+ *
+ * Gets only take the shard lock shared, so reads never wait on each other and only briefly on
+ * writers.  With a storage structure that can be read without the lock, the cuckoo table, gets
+ * skip the shard lock altogether and run alongside the writer.  Their accesses are buffered by
+ * the shard and applied to its replacement policy in a batch by whichever thread drains the
+ * buffer.
+ *
+ * Every shard evicts on its own, which makes eviction order only approximately global.
+ */
+pub struct ShardedCache<T, R> {
+    shards: Vec<RwLock<Cache<T, R>>>,
+    readers: Vec<Option<OptimisticReader>>,
+}
+
+impl <T: CacheStorageStructure, R: CacheReplacementPolicy> ShardedCache<T, R> {
+    pub fn new(shards: Vec<Cache<T, R>>) -> ShardedCache<T, R> {
+        assert!(!shards.is_empty(), "A sharded cache needs at least one shard");
+
+        ShardedCache {
+            readers: shards.iter().map(|cache| cache.optimistic_reader()).collect(),
+            shards: shards.into_iter().map(RwLock::new).collect(),
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard_index(&self, key: &Key) -> usize {
+        (key.digest() % self.shards.len() as u64) as usize
+    }
+
+    /**
+     * Read a key without the shard lock if the shard has an optimistic reader that can serve it,
+     * and under a shared lock otherwise.  Once the read buffer of the shard fills up the reader
+     * drains it, unless a writer holds the shard in which case the writer drains it instead.
+     */
+    pub fn get(&self, key: Key) -> Option<DataEntry> {
+        self.lookup(key, false)
+    }
+
+    /**
+     * Read a key like `get`, also finding entries within the grace period of the shard after they
+     * expired
+     */
+    pub fn get_stale(&self, key: Key) -> Option<DataEntry> {
+        self.lookup(key, true)
+    }
+
+    fn lookup(&self, key: Key, stale: bool) -> Option<DataEntry> {
+        let index = self.shard_index(&key);
+        let shard = &self.shards[index];
+
+        let optimistic = match self.readers[index] {
+            Some(ref reader) if !stale => reader.read(&key),
+            _ => OptimisticRead::NeedsLock,
+        };
+
+        let (entry, needs_drain) = match optimistic {
+            OptimisticRead::Found(entry) => (Some(entry), self.readers[index].as_ref().is_some_and(|reader| reader.needs_drain())),
+            OptimisticRead::Missing => (None, self.readers[index].as_ref().is_some_and(|reader| reader.needs_drain())),
+            OptimisticRead::NeedsLock => {
+                let cache = shard.read().unwrap();
+                let entry = if stale { cache.read_stale(key) } else { cache.read(key) };
+                (entry, cache.reads.needs_drain())
+            }
+        };
+
+        if needs_drain {
+            if let Ok(mut cache) = shard.try_write() {
+                cache.drain_reads();
+            }
+        }
+
+        entry
+    }
+
+    /**
+     * Scan every shard and merge the keys in order.  Each shard is scanned up to the same limit,
+     * keys past the point where the first shard stopped are left for the next call so that
+     * resuming from the cursor never repeats or skips a key.
+     */
+    pub fn scan(&self, prefix: &str, limit: usize, cursor: Option<&str>) -> (Vec<ScanItem>, Option<String>) {
+        let mut items = Vec::new();
+        let mut bound: Option<String> = None;
+
+        self.for_each(|cache| {
+            cache.drain_reads();
+            let (shard_items, next) = cache.scan(prefix, limit, cursor);
+            items.extend(shard_items);
+            if let Some(next) = next {
+                if bound.as_ref().is_none_or(|bound| next < *bound) {
+                    bound = Some(next);
+                }
+            }
+        });
+
+        if let Some(ref bound) = bound {
+            items.retain(|item| item.key <= *bound);
+        }
+        items.sort_by(|a, b| a.key.cmp(&b.key));
+
+        if items.len() > limit {
+            items.truncate(limit);
+            let last = items.last().map(|item| item.key.clone());
+            return (items, last);
+        }
+
+        (items, bound)
+    }
+
+    /**
+     * Divide a new capacity between the shards, evicting from shards that are now over it
+     */
+    pub fn resize(&self, capacity: usize) {
+        let shards = self.shards.len();
+        self.for_each(|cache| cache.resize(capacity / shards));
+    }
+
+    /**
+     * The subscribers to the events of the cache, shared by all shards
+     */
+    pub fn watchers(&self) -> Arc<Watchers> {
+        self.shards[0].read().unwrap().watchers.clone()
+    }
+
+    /**
+     * Lock the shard the key belongs to for writing
+     */
+    pub fn shard(&self, key: &Key) -> RwLockWriteGuard<'_, Cache<T, R>> {
+        self.shards[self.shard_index(key)].write().unwrap()
+    }
+
+    /**
+     * Lock each shard in turn, only one shard is locked at a time
+     */
+    pub fn for_each<F: FnMut(&mut Cache<T, R>)>(&self, mut f: F) {
+        for shard in self.shards.iter() {
+            f(&mut shard.write().unwrap());
+        }
+    }
+
+    /**
+     * Lock each shard in turn for reading, only one shard is locked at a time
+     */
+    pub fn read_each<F: FnMut(&Cache<T, R>)>(&self, mut f: F) {
+        for shard in self.shards.iter() {
+            f(&shard.read().unwrap());
+        }
+    }
+
+    /**
+     * Lock every shard, always in the same order, for a consistent view across the shards.  The
+     * read buffers are drained so the replacement policies and metrics are up to date.
+     */
+    pub fn lock_all(&self) -> Vec<RwLockWriteGuard<'_, Cache<T, R>>> {
+        self.shards.iter().map(|shard| {
+            let mut cache = shard.write().unwrap();
+            cache.drain_reads();
+            cache
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use cache::value::Value;
+    use cache::storage_structure::{HashStorageStructure, CuckooStorageStructure};
+    use cache::replacement_policy::LRU;
+
+    fn sharded(shards: usize, capacity: usize) -> ShardedCache<HashStorageStructure, LRU> {
+        ShardedCache::new((0..shards).map(|_| Cache::new(capacity / shards, HashStorageStructure::new(), LRU::new())).collect())
+    }
+
+    fn set<T: CacheStorageStructure, R: CacheReplacementPolicy>(cache: &ShardedCache<T, R>, key: &str) {
+        let key = Key::new(key.to_string());
+        assert!(cache.shard(&key).set(key.clone(), Value::new(String::from("v"))).is_ok());
+    }
+
+    #[test]
+    fn keys_are_spread_over_the_shards_and_read_concurrently() {
+        let cache = Arc::new(sharded(4, 4 * 1024 * 1024));
+        for i in 0..100 {
+            set(&cache, &format!("k{}", i));
+        }
+
+        let mut counts = Vec::new();
+        cache.for_each(|shard| counts.push(shard.storage_structure.indices().len()));
+        assert_eq!(counts.iter().sum::<usize>(), 100);
+        assert!(counts.iter().all(|count| *count > 0));
+
+        let readers: Vec<_> = (0..4).map(|_| {
+            let cache = cache.clone();
+            thread::spawn(move || (0..100).all(|i| cache.get(Key::new(format!("k{}", i))).is_some()))
+        }).collect();
+        for reader in readers {
+            assert!(reader.join().unwrap());
+        }
+
+        let mut hits = 0;
+        for shard in cache.lock_all() {
+            hits += shard.metrics.hit_count_get;
+        }
+        assert!(hits > 0 && hits <= 400);
+    }
+
+    #[test]
+    fn resize_divides_the_capacity_between_the_shards() {
+        let cache = sharded(4, 4 * 1024 * 1024);
+        for i in 0..200 {
+            set(&cache, &format!("k{}", i));
+        }
+
+        cache.resize(48 * 1024);
+        let mut remaining = 0;
+        cache.for_each(|shard| {
+            assert_eq!(shard.capacity, 12 * 1024);
+            remaining += shard.storage_structure.indices().len();
+        });
+        assert!(remaining > 0 && remaining < 200);
+    }
+    #[test]
+    fn scan_merges_the_shards_and_resumes_from_the_cursor() {
+        let cache = sharded(4, 4 * 1024 * 1024);
+        for i in 0..30 {
+            set(&cache, &format!("k{:02}", i));
+        }
+        set(&cache, "other");
+
+        let mut keys = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let (items, next) = cache.scan("k", 7, cursor.as_deref());
+            assert!(items.len() <= 7);
+            keys.extend(items.into_iter().map(|item| item.key));
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(keys, (0..30).map(|i| format!("k{:02}", i)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn gets_from_a_cuckoo_table_do_not_wait_for_the_writer() {
+        let cache = ShardedCache::new(vec![Cache::new(1024 * 1024, CuckooStorageStructure::new(), LRU::new())]);
+        set(&cache, "a");
+
+        let writer = cache.shard(&Key::new(String::from("a")));
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(|| sender.send(cache.get(Key::new(String::from("a"))).is_some()).unwrap());
+            let read = receiver.recv_timeout(Duration::from_secs(10));
+            drop(writer);
+            assert_eq!(read, Ok(true));
+        });
+
+        assert_eq!(cache.lock_all()[0].metrics.hit_count_get, 1);
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+
+use linked_hash_map::LinkedHashMap;
+
+use cache::memory;
+
+pub const PAGE_SIZE: usize = 1024 * 1024;
+
+/**
+ * Memory is handed out in pages which are carved into equally sized chunks, one chunk size per
+ * slab class.  Chunk sizes start at the minimum chunk size and grow by the growth factor up to a
+ * full page, and an item is always placed in the smallest class whose chunks fit it.  This bounds
+ * the waste per item to the gap between two classes and means memory never fragments, at the cost
+ * of pages staying assigned to the class that first asked for them.
+ *
+ * Each class keeps its own LRU so that storing an item only ever evicts items of the same class.
+ * Pages can be moved between classes when the workload shifts, either on request or by the
+ * automover which compares eviction counts between runs.
+ *
+ * Items larger than a page are stored as a chain of chunks from the largest class, they take up
+ * as many whole chunks as needed and are evicted as a unit.
+ */
+pub struct SlabAllocator {
+    pub classes: Vec<SlabClass>,
+    pub memory_limit: usize,
+    pub pages_allocated: usize,
+    pub pages_moved: u64,
+    items: HashMap<usize, (usize, usize)>, // (index, (class, chunks))
+    automove_evictions: Vec<u64>, // (class, evictions seen by the last automove run)
+}
+
+pub struct SlabClass {
+    pub chunk_size: usize,
+    pub chunks_per_page: usize,
+    pub pages: usize,
+    pub used_chunks: usize,
+    pub evictions: u64,
+    lru: LinkedHashMap<usize, ()>,
+}
+
+impl SlabClass {
+    fn new(chunk_size: usize) -> SlabClass {
+        SlabClass {
+            chunk_size,
+            chunks_per_page: PAGE_SIZE / chunk_size,
+            pages: 0,
+            used_chunks: 0,
+            evictions: 0,
+            lru: LinkedHashMap::new(),
+        }
+    }
+
+    pub fn total_chunks(&self) -> usize {
+        self.pages * self.chunks_per_page
+    }
+
+    pub fn free_chunks(&self) -> usize {
+        self.total_chunks() - self.used_chunks
+    }
+}
+
+impl SlabAllocator {
+    /**
+     * Create an allocator for `memory_limit` bytes with classes starting at `min_chunk` bytes and
+     * growing by `growth_factor`
+     */
+    pub fn new(memory_limit: usize, growth_factor: f64, min_chunk: usize) -> SlabAllocator {
+        let growth_factor = if growth_factor > 1.0 { growth_factor } else { 1.25 };
+
+        let mut classes = Vec::new();
+        let mut chunk_size = align(min_chunk.max(8));
+        while chunk_size <= PAGE_SIZE / 2 {
+            classes.push(SlabClass::new(chunk_size));
+            chunk_size = align(((chunk_size as f64) * growth_factor) as usize).max(chunk_size + 8);
+        }
+        classes.push(SlabClass::new(PAGE_SIZE));
+        let class_count = classes.len();
+
+        SlabAllocator {
+            classes,
+            memory_limit,
+            pages_allocated: 0,
+            pages_moved: 0,
+            items: HashMap::new(),
+            automove_evictions: vec![0; class_count],
+        }
+    }
+
+    fn page_limit(&self) -> usize {
+        (self.memory_limit / PAGE_SIZE).max(1)
+    }
+
+    /**
+     * Returns the class and number of chunks needed for an item of `size` bytes: a single chunk of
+     * the smallest class that fits it, or a chain of chunks from the largest class
+     */
+    pub fn class_for(&self, size: usize) -> (usize, usize) {
+        match self.classes.iter().position(|class| class.chunk_size >= size) {
+            Some(class) => (class, 1),
+            None => {
+                let class = self.classes.len() - 1;
+                let chunk_size = self.classes[class].chunk_size;
+                (class, size.div_ceil(chunk_size))
+            }
+        }
+    }
+
+    /**
+     * Take `chunks` free chunks from the class, assigning new pages to it if the memory limit
+     * allows.  Returns false without taking any chunks when the class is full and some of its
+     * items have to be evicted first.
+     */
+    pub fn reserve(&mut self, class: usize, chunks: usize) -> bool {
+        let free = self.classes[class].free_chunks();
+        if free < chunks {
+            let chunks_per_page = self.classes[class].chunks_per_page;
+            let pages = (chunks - free).div_ceil(chunks_per_page);
+            if self.pages_allocated + pages > self.page_limit() {
+                return false;
+            }
+
+            self.pages_allocated += pages;
+            self.classes[class].pages += pages;
+        }
+
+        self.classes[class].used_chunks += chunks;
+        true
+    }
+
+    /**
+     * Place the item at index in the chunks previously reserved from the class
+     */
+    pub fn insert(&mut self, index: usize, class: usize, chunks: usize) {
+        self.items.insert(index, (class, chunks));
+        self.classes[class].lru.insert(index, ());
+    }
+
+    pub fn touch(&mut self, index: usize) {
+        if let Some(&(class, _)) = self.items.get(&index) {
+            self.classes[class].lru.get_refresh(&index);
+        }
+    }
+
+    /**
+     * The least recently used item of the class
+     */
+    pub fn victim(&self, class: usize) -> Option<usize> {
+        self.classes[class].lru.front().map(|(index, _)| *index)
+    }
+
+    /**
+     * Return the chunks of the item at index to its class, returning the class if it was allocated
+     */
+    pub fn release(&mut self, index: usize) -> Option<usize> {
+        match self.items.remove(&index) {
+            Some((class, chunks)) => {
+                self.classes[class].lru.remove(&index);
+                self.classes[class].used_chunks -= chunks;
+                Some(class)
+            },
+            None => None
+        }
+    }
+
+    /**
+     * Move a page from `src` to `dst`.  The source class must already have a whole page of free
+     * chunks, the page is taken from those.
+     */
+    pub fn move_page(&mut self, src: usize, dst: usize) -> bool {
+        if self.classes[src].pages == 0 || self.classes[src].free_chunks() < self.classes[src].chunks_per_page {
+            return false;
+        }
+
+        self.classes[src].pages -= 1;
+        self.classes[dst].pages += 1;
+        self.pages_moved += 1;
+        true
+    }
+
+    /**
+     * Pick a page move for the automover, or None if memory is where it is needed.  The class with
+     * the most evictions since the last run receives a page, preferably from a class with a whole
+     * page of free chunks and otherwise from the class holding the most pages that saw no
+     * evictions at all since the last run.
+     */
+    pub fn automove_candidate(&mut self) -> Option<(usize, usize)> {
+        let deltas: Vec<u64> = self.classes.iter()
+            .zip(self.automove_evictions.iter())
+            .map(|(class, seen)| class.evictions - seen)
+            .collect();
+        self.automove_evictions = self.classes.iter().map(|class| class.evictions).collect();
+
+        let dst = match deltas.iter().enumerate().filter(|&(_, delta)| *delta > 0).max_by_key(|&(_, delta)| *delta) {
+            Some((dst, _)) => dst,
+            None => return None,
+        };
+
+        let free = self.classes.iter()
+            .enumerate()
+            .filter(|&(id, class)| id != dst && class.pages > 0 && class.free_chunks() >= class.chunks_per_page)
+            .max_by_key(|&(_, class)| class.free_chunks())
+            .map(|(id, _)| id);
+
+        let idle = self.classes.iter()
+            .enumerate()
+            .filter(|&(id, class)| id != dst && class.pages > 1 && deltas[id] == 0)
+            .max_by_key(|&(_, class)| class.pages)
+            .map(|(id, _)| id);
+
+        free.or(idle).map(|src| (src, dst))
+    }
+
+    /**
+     * Heap memory used by the bookkeeping of the allocator in bytes, the pages are accounted for
+     * by the items placed in them
+     */
+    pub fn memory_usage(&self) -> usize {
+        let lrus: usize = self.classes.iter().map(|class| memory::linked_hash_map_usage(&class.lru)).sum();
+        memory::vec_usage::<SlabClass>(self.classes.capacity())
+            + memory::vec_usage::<u64>(self.automove_evictions.capacity())
+            + memory::hash_map_usage(&self.items)
+            + lrus
+    }
+}
+
+/**
+ * Statistics for a set of allocators created with the same classes, such as the allocators of the
+ * shards of a cache, with the counts of each class added together
+ */
+pub fn combined_stats(allocators: &[&SlabAllocator]) -> Vec<(String, String)> {
+    let mut stats = Vec::new();
+    let mut active_slabs = 0;
+
+    let first = match allocators.first() {
+        Some(first) => first,
+        None => return stats,
+    };
+
+    for (id, class) in first.classes.iter().enumerate() {
+        let classes: Vec<&SlabClass> = allocators.iter().map(|slabs| &slabs.classes[id]).collect();
+        let pages: usize = classes.iter().map(|class| class.pages).sum();
+        if pages == 0 {
+            continue;
+        }
+        active_slabs += 1;
+
+        let total_chunks: usize = classes.iter().map(|class| class.total_chunks()).sum();
+        let used_chunks: usize = classes.iter().map(|class| class.used_chunks).sum();
+        let evictions: u64 = classes.iter().map(|class| class.evictions).sum();
+
+        stats.push((format!("{}:chunk_size", id), class.chunk_size.to_string()));
+        stats.push((format!("{}:chunks_per_page", id), class.chunks_per_page.to_string()));
+        stats.push((format!("{}:total_pages", id), pages.to_string()));
+        stats.push((format!("{}:total_chunks", id), total_chunks.to_string()));
+        stats.push((format!("{}:used_chunks", id), used_chunks.to_string()));
+        stats.push((format!("{}:free_chunks", id), (total_chunks - used_chunks).to_string()));
+        stats.push((format!("{}:evictions", id), evictions.to_string()));
+    }
+
+    let pages_allocated: usize = allocators.iter().map(|slabs| slabs.pages_allocated).sum();
+    let pages_moved: u64 = allocators.iter().map(|slabs| slabs.pages_moved).sum();
+
+    stats.push((String::from("active_slabs"), active_slabs.to_string()));
+    stats.push((String::from("total_malloced"), (pages_allocated * PAGE_SIZE).to_string()));
+    stats.push((String::from("slabs_moved"), pages_moved.to_string()));
+    stats
+}
+
+/**
+ * Round up to a multiple of 8 bytes
+ */
+fn align(size: usize) -> usize {
+    (size + 7) & !7
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn items_go_to_the_smallest_class_that_fits() {
+        let slabs = SlabAllocator::new(4 * PAGE_SIZE, 2.0, 64);
+        assert_eq!(slabs.classes[0].chunk_size, 64);
+        assert_eq!(slabs.classes[1].chunk_size, 128);
+
+        assert_eq!(slabs.class_for(1), (0, 1));
+        assert_eq!(slabs.class_for(64), (0, 1));
+        assert_eq!(slabs.class_for(65), (1, 1));
+
+        // Larger than a page takes a chain of chunks from the largest class
+        let largest = slabs.classes.len() - 1;
+        assert_eq!(slabs.classes[largest].chunk_size, PAGE_SIZE);
+        assert_eq!(slabs.class_for(PAGE_SIZE * 2 + 1), (largest, 3));
+    }
+
+    #[test]
+    fn reserve_stops_at_the_memory_limit() {
+        let mut slabs = SlabAllocator::new(2 * PAGE_SIZE, 2.0, 64);
+        let largest = slabs.classes.len() - 1;
+
+        assert!(slabs.reserve(0, 1));
+        assert_eq!(slabs.classes[0].pages, 1);
+        assert!(slabs.reserve(largest, 1));
+        assert!(!slabs.reserve(largest, 1));
+        assert_eq!(slabs.pages_allocated, 2);
+
+        // The class with a page assigned keeps filling it
+        let chunks_per_page = slabs.classes[0].chunks_per_page;
+        assert!(slabs.reserve(0, chunks_per_page - 1));
+        assert!(!slabs.reserve(0, 1));
+    }
+
+    #[test]
+    fn each_class_evicts_its_own_least_recently_used_item() {
+        let mut slabs = SlabAllocator::new(4 * PAGE_SIZE, 2.0, 64);
+        for index in 0..3 {
+            assert!(slabs.reserve(0, 1));
+            slabs.insert(index, 0, 1);
+        }
+        assert!(slabs.reserve(1, 1));
+        slabs.insert(3, 1, 1);
+
+        slabs.touch(0);
+        assert_eq!(slabs.victim(0), Some(1));
+        assert_eq!(slabs.victim(1), Some(3));
+
+        assert_eq!(slabs.release(1), Some(0));
+        assert_eq!(slabs.release(1), None);
+        assert_eq!(slabs.classes[0].used_chunks, 2);
+        assert_eq!(slabs.victim(0), Some(2));
+    }
+    #[test]
+    fn automove_gives_a_free_page_to_the_class_evicting_most() {
+        let mut slabs = SlabAllocator::new(4 * PAGE_SIZE, 2.0, 64);
+        assert!(slabs.reserve(0, 1));
+        slabs.insert(0, 0, 1);
+        assert!(slabs.reserve(1, 1));
+        slabs.insert(1, 1, 1);
+
+        // A page with an item in it cannot be moved
+        assert!(!slabs.move_page(0, 1));
+
+        slabs.release(0);
+        slabs.classes[1].evictions += 5;
+        slabs.classes[2].evictions += 1;
+        assert_eq!(slabs.automove_candidate(), Some((0, 1)));
+        assert!(slabs.move_page(0, 1));
+        assert_eq!(slabs.classes[0].pages, 0);
+        assert_eq!(slabs.classes[1].pages, 2);
+        assert_eq!(slabs.pages_moved, 1);
+
+        // Only evictions since the previous run count
+        assert_eq!(slabs.automove_candidate(), None);
+    }
+}
@@ -1,5 +1,13 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hint;
+use std::mem;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use cache::key::Key;
 use cache::data_entry::DataEntry;
+use cache::memory;
 
 /**
  * With the current layout there must be two highly associated data sructures for maintaining the
@@ -13,21 +21,32 @@ use cache::data_entry::DataEntry;
  * be able to support a reordering replacement policy.
  */
 pub trait CacheStorageStructure {
-    fn new() -> Self;
-
+    /**
+     * Returns the total length of the keys and values stored
+     */
     fn size(&self) -> usize;
+
+    /**
+     * Returns the memory held by the structure in bytes, entries included
+     */
+    fn memory_usage(&self) -> usize;
     
     /**
      * Returns the index and entry if it exists
      */
-    fn get(&mut self, key: Key) -> Option<(usize, DataEntry)>;
-    fn get_index(&mut self, index: usize) -> Option<(usize, DataEntry)>;
+    fn get(&self, key: Key) -> Option<(usize, DataEntry)>;
+    fn get_index(&self, index: usize) -> Option<(usize, DataEntry)>;
+
+    /**
+     * Update the metadata of the entry at index in place, the key must not be changed.  Returns
+     * false if there is no entry at index.
+     */
+    fn update_index(&mut self, index: usize, update: &mut dyn FnMut(&mut DataEntry)) -> bool;
 
     /**
      * Set a key, value pair and return the new index and the removed entry if it exists
      */
     fn set(&mut self, entry: DataEntry) -> (usize, Option<DataEntry>);
-    fn set_index(&mut self, index: usize, entry: DataEntry) -> (usize, Option<DataEntry>);
 
     /**
      * Remove a key, value pair and return the old index and entry if it exists
@@ -35,67 +54,180 @@ pub trait CacheStorageStructure {
     fn remove(&mut self, key: Key) -> Option<(usize, DataEntry)>;
     fn remove_index(&mut self, index: usize) -> Option<(usize, DataEntry)>;
 
-    fn contains(&mut self, key: Key) -> bool;
+    /**
+     * Returns the indices of all entries currently stored
+     */
+    fn indices(&self) -> Vec<usize>;
+
+    /**
+     * Returns up to `limit` entries whose keys start with `prefix` in key order, starting after the
+     * key `after` if given.  Unordered structures have to visit and sort every entry to do so.
+     */
+    fn scan(&self, prefix: &str, after: Option<&Key>, limit: usize) -> Vec<(usize, DataEntry)> {
+        let mut entries: Vec<(usize, DataEntry)> = self.indices().into_iter()
+            .filter_map(|index| self.get_index(index))
+            .filter(|(_, entry)| entry.key.item.starts_with(prefix))
+            .filter(|(_, entry)| after.is_none_or(|after| entry.key > *after))
+            .collect();
+
+        entries.sort_by(|a, b| a.1.key.cmp(&b.1.key));
+        entries.truncate(limit);
+        entries
+    }
+
+    /**
+     * Returns a handle that looks up keys from any thread while the structure itself is locked or
+     * being changed, if the structure supports that
+     */
+    fn reader(&self) -> Option<StorageReader> {
+        None
+    }
+}
+
+impl<S: CacheStorageStructure + ?Sized> CacheStorageStructure for Box<S> {
+    fn size(&self) -> usize {
+        (**self).size()
+    }
+
+    fn memory_usage(&self) -> usize {
+        (**self).memory_usage()
+    }
+
+    fn get(&self, key: Key) -> Option<(usize, DataEntry)> {
+        (**self).get(key)
+    }
+
+    fn get_index(&self, index: usize) -> Option<(usize, DataEntry)> {
+        (**self).get_index(index)
+    }
+
+    fn update_index(&mut self, index: usize, update: &mut dyn FnMut(&mut DataEntry)) -> bool {
+        (**self).update_index(index, update)
+    }
+
+    fn set(&mut self, entry: DataEntry) -> (usize, Option<DataEntry>) {
+        (**self).set(entry)
+    }
+
+    fn remove(&mut self, key: Key) -> Option<(usize, DataEntry)> {
+        (**self).remove(key)
+    }
+
+    fn remove_index(&mut self, index: usize) -> Option<(usize, DataEntry)> {
+        (**self).remove_index(index)
+    }
+
+    fn indices(&self) -> Vec<usize> {
+        (**self).indices()
+    }
+
+    fn scan(&self, prefix: &str, after: Option<&Key>, limit: usize) -> Vec<(usize, DataEntry)> {
+        (**self).scan(prefix, after, limit)
+    }
+
+    fn reader(&self) -> Option<StorageReader> {
+        (**self).reader()
+    }
+}
+
+/**
+ * Construct an empty storage structure from its name
+ */
+pub fn from_name(name: &str) -> Option<Box<dyn CacheStorageStructure + Send + Sync>> {
+    match name.to_lowercase().as_str() {
+        "naive" => Some(Box::new(NaiveStorageStructure::new())),
+        "hash" => Some(Box::new(HashStorageStructure::new())),
+        "cuckoo" => Some(Box::new(CuckooStorageStructure::new())),
+        "ordered" => Some(Box::new(OrderedStorageStructure::new())),
+        _ => None,
+    }
 }
 
 /**
- * A naive storage structure with O(n) insert, lookup, and delete.
+ * A naive storage structure with O(n) insert, lookup, and delete.  Removed entries leave an empty
+ * slot behind which is reused by the next insert, so the index of an entry is stable for as long
+ * as the entry lives in the structure.
  */
 pub struct NaiveStorageStructure {
-    data: Vec<DataEntry>,
+    data: Vec<Option<DataEntry>>,
+    free_slots: Vec<usize>,
     size: usize,
+    heap_size: usize,
 }
 
-impl CacheStorageStructure for NaiveStorageStructure {
-    fn new() -> Self {
+impl NaiveStorageStructure {
+    pub fn new() -> NaiveStorageStructure {
         NaiveStorageStructure {
             data: Vec::new(),
+            free_slots: Vec::new(),
             size: 0,
+            heap_size: 0,
         }
     }
+}
 
+impl CacheStorageStructure for NaiveStorageStructure {
     fn size(&self) -> usize {
         self.size
     }
 
-    fn get(&mut self, key: Key) -> Option<(usize, DataEntry)> {
-        let mut index: usize = 0;
-        for entry in self.data.clone().into_iter() {
-            if entry.key == key {
-                return self.get_index(index);
+    fn memory_usage(&self) -> usize {
+        // Entry headers live inline in the slots, only their buffers are separate allocations
+        memory::vec_usage::<Option<DataEntry>>(self.data.capacity())
+            + memory::vec_usage::<usize>(self.free_slots.capacity())
+            + self.heap_size
+    }
+
+    fn get(&self, key: Key) -> Option<(usize, DataEntry)> {
+        for (index, slot) in self.data.iter().enumerate() {
+            match *slot {
+                Some(ref entry) if entry.key == key => return Some((index, entry.clone())),
+                _ => {}
             }
-            index += 1;
         }
 
         None
     }
 
-    fn get_index(&mut self, index: usize) -> Option<(usize, DataEntry)> {
+    fn get_index(&self, index: usize) -> Option<(usize, DataEntry)> {
         match self.data.get(index) {
-            Some(entry) => Some((index, entry.clone())),
-            None => None
+            Some(Some(entry)) => Some((index, entry.clone())),
+            _ => None
         }
-    }    
+    }
+
+    fn update_index(&mut self, index: usize, update: &mut dyn FnMut(&mut DataEntry)) -> bool {
+        self.data.get_mut(index).and_then(|slot| slot.as_mut()).map(update).is_some()
+    }
 
     fn set(&mut self, entry: DataEntry) -> (usize, Option<DataEntry>) {
         self.size += entry.len();  
+        self.heap_size += entry.heap_usage();
 
         match self.remove(entry.key.clone()) {
             Some((index, old_entry)) => {
-                self.data.insert(index, entry);
+                // Reclaim the slot that was just released so the entry keeps its index
+                self.free_slots.pop();
+                self.data[index] = Some(entry);
                 (index, Some(old_entry))
             },
             None => {
-                self.data.push(entry);
-                (self.data.len() - 1, None)
+                match self.free_slots.pop() {
+                    Some(index) => {
+                        self.data[index] = Some(entry);
+                        (index, None)
+                    },
+                    None => {
+                        // Grow a slot at a time, doubling would overshoot the memory budget
+                        self.data.reserve_exact(1);
+                        self.data.push(Some(entry));
+                        (self.data.len() - 1, None)
+                    }
+                }
             }
         }
     }
 
-    fn set_index(&mut self, index: usize, entry: DataEntry) -> (usize, Option<DataEntry>) {
-        unimplemented!()
-    }    
-
     fn remove(&mut self, key: Key) -> Option<(usize, DataEntry)> {
         match self.get(key) {
             Some((index, _)) => self.remove_index(index),
@@ -104,102 +236,831 @@ impl CacheStorageStructure for NaiveStorageStructure {
     }
 
     fn remove_index(&mut self, index: usize) -> Option<(usize, DataEntry)> {
-        let removed = self.data.remove(index);
+        match self.data.get_mut(index).and_then(|slot| slot.take()) {
+            Some(removed) => {
+                self.size -= removed.len();
+                self.heap_size -= removed.heap_usage();
+                self.free_slots.push(index);
+                Some((index, removed))
+            },
+            None => None
+        }
+    }
+
+    fn indices(&self) -> Vec<usize> {
+        self.data.iter()
+            .enumerate()
+            .filter(|&(_, slot)| slot.is_some())
+            .map(|(index, _)| index)
+            .collect()
+    }
+}
+
+/**
+ * Exact key lookups through a hash map from key to index, entries live in slots like those of the
+ * naive structure so indices stay stable.  Every key is held twice, once by the map and once by
+ * its entry.
+ */
+pub struct HashStorageStructure {
+    rid_map: HashMap<Key, usize>,
+    data: Vec<Option<DataEntry>>,
+    free_slots: Vec<usize>,
+    size: usize,
+    heap_size: usize,
+}
+
+impl HashStorageStructure {
+    pub fn new() -> HashStorageStructure {
+        HashStorageStructure {
+            rid_map: HashMap::new(),
+            data: Vec::new(),
+            free_slots: Vec::new(),
+            size: 0,
+            heap_size: 0,
+        }
+    }
+}
+
+impl CacheStorageStructure for HashStorageStructure {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn memory_usage(&self) -> usize {
+        memory::hash_map_usage(&self.rid_map)
+            + memory::vec_usage::<Option<DataEntry>>(self.data.capacity())
+            + memory::vec_usage::<usize>(self.free_slots.capacity())
+            + self.heap_size
+    }
+
+    fn get(&self, key: Key) -> Option<(usize, DataEntry)> {
+        match self.rid_map.get(&key) {
+            Some(&index) => self.get_index(index),
+            None => None
+        }
+    }
+
+    fn get_index(&self, index: usize) -> Option<(usize, DataEntry)> {
+        match self.data.get(index) {
+            Some(Some(entry)) => Some((index, entry.clone())),
+            _ => None
+        }
+    }
+
+    fn update_index(&mut self, index: usize, update: &mut dyn FnMut(&mut DataEntry)) -> bool {
+        self.data.get_mut(index).and_then(|slot| slot.as_mut()).map(update).is_some()
+    }
+
+    fn set(&mut self, entry: DataEntry) -> (usize, Option<DataEntry>) {
+        self.size += entry.len();
+        self.heap_size += entry.heap_usage();
+
+        match self.rid_map.get(&entry.key).cloned() {
+            Some(index) => {
+                let old_entry = self.data[index].take().unwrap();
+                self.size -= old_entry.len();
+                self.heap_size -= old_entry.heap_usage();
+                self.data[index] = Some(entry);
+                (index, Some(old_entry))
+            },
+            None => {
+                let index = match self.free_slots.pop() {
+                    Some(index) => index,
+                    None => {
+                        self.data.reserve_exact(1);
+                        self.data.push(None);
+                        self.data.len() - 1
+                    }
+                };
+
+                // The map holds its own copy of the key
+                self.heap_size += memory::allocation(entry.key.item.len());
+                self.rid_map.insert(entry.key.clone(), index);
+                self.data[index] = Some(entry);
+                (index, None)
+            }
+        }
+    }
+
+    fn remove(&mut self, key: Key) -> Option<(usize, DataEntry)> {
+        match self.rid_map.get(&key).cloned() {
+            Some(index) => self.remove_index(index),
+            None => None
+        }
+    }
+
+    fn remove_index(&mut self, index: usize) -> Option<(usize, DataEntry)> {
+        match self.data.get_mut(index).and_then(|slot| slot.take()) {
+            Some(removed) => {
+                self.rid_map.remove(&removed.key);
+                self.heap_size -= memory::allocation(removed.key.item.len());
+                self.size -= removed.len();
+                self.heap_size -= removed.heap_usage();
+                self.free_slots.push(index);
+                Some((index, removed))
+            },
+            None => None
+        }
+    }
+
+    fn indices(&self) -> Vec<usize> {
+        self.rid_map.values().cloned().collect()
+    }
+}
+
+/**
+ * Keys kept in order in a B-tree from key to index so every key sharing a prefix can be visited
+ * without looking at any other key, at the cost of logarithmic lookups.  Entries live in slots
+ * like those of the naive structure so indices stay stable.
+ */
+pub struct OrderedStorageStructure {
+    tree: BTreeMap<Key, usize>,
+    data: Vec<Option<DataEntry>>,
+    free_slots: Vec<usize>,
+    size: usize,
+    heap_size: usize,
+}
+
+impl OrderedStorageStructure {
+    pub fn new() -> OrderedStorageStructure {
+        OrderedStorageStructure {
+            tree: BTreeMap::new(),
+            data: Vec::new(),
+            free_slots: Vec::new(),
+            size: 0,
+            heap_size: 0,
+        }
+    }
+}
+
+impl CacheStorageStructure for OrderedStorageStructure {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn memory_usage(&self) -> usize {
+        memory::btree_map_usage(&self.tree)
+            + memory::vec_usage::<Option<DataEntry>>(self.data.capacity())
+            + memory::vec_usage::<usize>(self.free_slots.capacity())
+            + self.heap_size
+    }
+
+    fn get(&self, key: Key) -> Option<(usize, DataEntry)> {
+        match self.tree.get(&key) {
+            Some(&index) => self.get_index(index),
+            None => None
+        }
+    }
+
+    fn get_index(&self, index: usize) -> Option<(usize, DataEntry)> {
+        match self.data.get(index) {
+            Some(Some(entry)) => Some((index, entry.clone())),
+            _ => None
+        }
+    }
+
+    fn update_index(&mut self, index: usize, update: &mut dyn FnMut(&mut DataEntry)) -> bool {
+        self.data.get_mut(index).and_then(|slot| slot.as_mut()).map(update).is_some()
+    }
+
+    fn set(&mut self, entry: DataEntry) -> (usize, Option<DataEntry>) {
+        self.size += entry.len();
+        self.heap_size += entry.heap_usage();
+
+        match self.tree.get(&entry.key).cloned() {
+            Some(index) => {
+                let old_entry = self.data[index].take().unwrap();
+                self.size -= old_entry.len();
+                self.heap_size -= old_entry.heap_usage();
+                self.data[index] = Some(entry);
+                (index, Some(old_entry))
+            },
+            None => {
+                let index = match self.free_slots.pop() {
+                    Some(index) => index,
+                    None => {
+                        self.data.reserve_exact(1);
+                        self.data.push(None);
+                        self.data.len() - 1
+                    }
+                };
+
+                // The tree holds its own copy of the key
+                self.heap_size += memory::allocation(entry.key.item.len());
+                self.tree.insert(entry.key.clone(), index);
+                self.data[index] = Some(entry);
+                (index, None)
+            }
+        }
+    }
+
+    fn remove(&mut self, key: Key) -> Option<(usize, DataEntry)> {
+        match self.tree.get(&key).cloned() {
+            Some(index) => self.remove_index(index),
+            None => None
+        }
+    }
+
+    fn remove_index(&mut self, index: usize) -> Option<(usize, DataEntry)> {
+        match self.data.get_mut(index).and_then(|slot| slot.take()) {
+            Some(removed) => {
+                self.tree.remove(&removed.key);
+                self.heap_size -= memory::allocation(removed.key.item.len());
+                self.size -= removed.len();
+                self.heap_size -= removed.heap_usage();
+                self.free_slots.push(index);
+                Some((index, removed))
+            },
+            None => None
+        }
+    }
+
+    fn indices(&self) -> Vec<usize> {
+        self.tree.values().cloned().collect()
+    }
+
+    fn scan(&self, prefix: &str, after: Option<&Key>, limit: usize) -> Vec<(usize, DataEntry)> {
+        let start = match after {
+            Some(after) if after.item.as_str() >= prefix => Bound::Excluded(after.clone()),
+            _ => Bound::Included(Key::new(String::from(prefix))),
+        };
+
+        self.tree.range((start, Bound::Unbounded))
+            .take_while(|&(key, _)| key.item.starts_with(prefix))
+            .take(limit)
+            .filter_map(|(_, &index)| self.get_index(index))
+            .collect()
+    }
+}
+
+const CUCKOO_SLOTS: usize = 4;
+const CUCKOO_MAX_DEPTH: usize = 5;
+const CUCKOO_VERSIONS: usize = 1024;
+
+/**
+ * A bucketized cuckoo hash table in the style of MemC3.  Each key has two candidate buckets of
+ * four slots, a slot holds a one byte tag taken from the hash of the key together with the index
+ * of the entry, so most mismatches are rejected without touching the entry.  The alternate bucket
+ * is derived from the current bucket and the tag alone, which lets entries be moved without
+ * rehashing their keys.
+ *
+ * When both buckets are full a breadth first search finds the shortest chain of displacements
+ * ending in a free slot and the chain is applied from its free end, so an entry is always present
+ * in one of its buckets.  Every bucket maps onto a version counter that the writer makes odd while
+ * it moves an entry out of or into the bucket.  Lookups are optimistic: a key that is found is
+ * returned right away, a key that is not found only counts as missing if neither version was odd
+ * or changed during the search, otherwise the search is repeated.
+ *
+ * The buckets and entries live in a table shared with the readers handed out by `reader`, which
+ * look up keys from any thread without the lock the cache keeps around the structure.  All
+ * changes go through atomics and a lock per entry, so one writer at a time can change the table
+ * while readers run.  Only while the table grows, or the list of entries grows by a slot, are the
+ * readers briefly held back.
+ *
+ * The table doubles and rehashes when no displacement chain is found.
+ */
+pub struct CuckooStorageStructure {
+    table: Arc<CuckooTable>,
+    free_slots: Vec<usize>,
+    size: usize,
+    heap_size: usize,
+}
+
+struct CuckooTable {
+    slots: RwLock<CuckooSlots>,
+    versions: Vec<AtomicU64>,
+}
+
+struct CuckooSlots {
+    buckets: Vec<[AtomicU64; CUCKOO_SLOTS]>, // (tag << 56 | index + 1), 0 for an empty slot
+    data: Vec<RwLock<Option<DataEntry>>>,
+}
+
+/**
+ * Looks up keys in a storage structure from any thread without holding the lock around it,
+ * see `CacheStorageStructure::reader`
+ */
+#[derive(Clone)]
+pub struct StorageReader {
+    table: Arc<CuckooTable>,
+}
+
+impl StorageReader {
+    pub fn get(&self, key: &Key) -> Option<(usize, DataEntry)> {
+        let slots = self.table.slots.read().unwrap();
+        self.table.lookup(&slots, key, |entry| entry.clone())
+    }
+}
+
+impl CuckooSlots {
+    fn with_buckets(count: usize, data: Vec<RwLock<Option<DataEntry>>>) -> CuckooSlots {
+        CuckooSlots {
+            buckets: (0..count).map(|_| [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)]).collect(),
+            data,
+        }
+    }
+
+    /**
+     * Returns the tag and the two candidate buckets for a key
+     */
+    fn locate(&self, key: &Key) -> (u8, usize, usize) {
+        let hash = key.digest();
+        let tag = ((hash >> 56) as u8).max(1);
+        let bucket = hash as usize & (self.buckets.len() - 1);
+        (tag, bucket, self.alternate(bucket, tag))
+    }
+
+    fn alternate(&self, bucket: usize, tag: u8) -> usize {
+        (bucket ^ (tag as u64).wrapping_mul(0xc6a4a7935bd1e995) as usize) & (self.buckets.len() - 1)
+    }
+
+    fn slot(&self, bucket: usize, slot: usize) -> u64 {
+        self.buckets[bucket][slot].load(Ordering::Acquire)
+    }
+
+    /**
+     * Find the bucket and slot holding the entry at index with the given tag
+     */
+    fn find_slot(&self, tag: u8, first: usize, second: usize, index: usize) -> Option<(usize, usize)> {
+        let wanted = slot_value(tag, index);
+        for &bucket in [first, second].iter() {
+            for slot in 0..CUCKOO_SLOTS {
+                if self.slot(bucket, slot) == wanted {
+                    return Some((bucket, slot));
+                }
+            }
+        }
+        None
+    }
+
+    /**
+     * Search the two buckets for the key once, reading the entry with `read` if it is found
+     */
+    fn search<V, F: Fn(&DataEntry) -> V>(&self, tag: u8, first: usize, second: usize, key: &Key, read: &F) -> Option<(usize, V)> {
+        for &bucket in [first, second].iter() {
+            for slot in 0..CUCKOO_SLOTS {
+                let value = self.slot(bucket, slot);
+                if value == 0 || slot_tag(value) != tag {
+                    continue;
+                }
+
+                let index = slot_index(value);
+                if let Some(entry) = self.data.get(index) {
+                    match *entry.read().unwrap() {
+                        Some(ref entry) if entry.key == *key => return Some((index, read(entry))),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+impl CuckooTable {
+    fn version(&self, bucket: usize) -> &AtomicU64 {
+        &self.versions[bucket % CUCKOO_VERSIONS]
+    }
+
+    /**
+     * Mark the buckets as being written, their versions stay odd until `end_write`
+     */
+    fn begin_write(&self, first: usize, second: usize) {
+        self.version(first).fetch_add(1, Ordering::AcqRel);
+        if first % CUCKOO_VERSIONS != second % CUCKOO_VERSIONS {
+            self.version(second).fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    fn end_write(&self, first: usize, second: usize) {
+        self.begin_write(first, second);
+    }
+
+    /**
+     * Optimistic lookup of a key, reading the entry with `read` if it is found.  A miss is only
+     * trusted if no entry was moved between the buckets of the key while they were searched.
+     */
+    fn lookup<V, F: Fn(&DataEntry) -> V>(&self, slots: &CuckooSlots, key: &Key, read: F) -> Option<(usize, V)> {
+        let (tag, first, second) = slots.locate(key);
+
+        loop {
+            let first_version = self.version(first).load(Ordering::Acquire);
+            let second_version = self.version(second).load(Ordering::Acquire);
+
+            let found = slots.search(tag, first, second, key, &read);
+            if found.is_some() {
+                return found;
+            }
+
+            if (first_version | second_version) & 1 == 0
+                && self.version(first).load(Ordering::Acquire) == first_version
+                && self.version(second).load(Ordering::Acquire) == second_version {
+                return None;
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /**
+     * Place the entry at index into one of its buckets, displacing other entries if necessary.
+     * Returns false when no chain of displacements short enough is found.
+     */
+    fn insert_slot(&self, slots: &CuckooSlots, tag: u8, first: usize, second: usize, index: usize) -> bool {
+        // Breadth first search over the slots reachable from the two buckets, each node is a
+        // (bucket, slot, parent node) triple
+        let mut nodes: Vec<(usize, usize, Option<usize>)> = Vec::new();
+        let mut frontier = 0;
+        for &bucket in [first, second].iter() {
+            for slot in 0..CUCKOO_SLOTS {
+                nodes.push((bucket, slot, None));
+            }
+        }
+
+        let mut depth = 0;
+        let free = loop {
+            let level_end = nodes.len();
+            let mut free = None;
+
+            while frontier < level_end {
+                let (bucket, slot, _) = nodes[frontier];
+                let value = slots.slot(bucket, slot);
+                if value == 0 {
+                    free = Some(frontier);
+                    break;
+                }
+
+                if depth < CUCKOO_MAX_DEPTH {
+                    let alternate = slots.alternate(bucket, slot_tag(value));
+                    for next in 0..CUCKOO_SLOTS {
+                        nodes.push((alternate, next, Some(frontier)));
+                    }
+                }
+                frontier += 1;
+            }
+
+            if free.is_some() || nodes.len() == level_end {
+                break free;
+            }
+            depth += 1;
+        };
+
+        let mut node = match free {
+            Some(node) => node,
+            None => return false,
+        };
+
+        // Move entries into the free slot starting from the end of the chain.  Each entry is
+        // copied to its new slot before it is cleared from the old one, and the versions of both
+        // buckets are odd meanwhile.  A chain that passes through the same slot twice is abandoned
+        // part way, every entry moved so far is still in one of its buckets.
+        while let Some(parent) = nodes[node].2 {
+            let (to_bucket, to_slot, _) = nodes[node];
+            let (from_bucket, from_slot, _) = nodes[parent];
+
+            let value = slots.slot(from_bucket, from_slot);
+            if value == 0 || slots.slot(to_bucket, to_slot) != 0
+                || slots.alternate(from_bucket, slot_tag(value)) != to_bucket {
+                return false;
+            }
+
+            self.begin_write(from_bucket, to_bucket);
+            slots.buckets[to_bucket][to_slot].store(value, Ordering::Release);
+            slots.buckets[from_bucket][from_slot].store(0, Ordering::Release);
+            self.end_write(from_bucket, to_bucket);
+
+            node = parent;
+        }
+
+        let (bucket, slot, _) = nodes[node];
+        if slots.slot(bucket, slot) != 0 {
+            return false;
+        }
+
+        slots.buckets[bucket][slot].store(slot_value(tag, index), Ordering::Release);
+        true
+    }
+}
+
+impl CuckooStorageStructure {
+    pub fn new() -> CuckooStorageStructure {
+        CuckooStorageStructure {
+            table: Arc::new(CuckooTable {
+                slots: RwLock::new(CuckooSlots::with_buckets(16, Vec::new())),
+                versions: (0..CUCKOO_VERSIONS).map(|_| AtomicU64::new(0)).collect(),
+            }),
+            free_slots: Vec::new(),
+            size: 0,
+            heap_size: 0,
+        }
+    }
+
+    /**
+     * Double the number of buckets and place every entry again, readers wait until it is done
+     */
+    fn grow(&mut self) {
+        let mut slots = self.table.slots.write().unwrap();
+        let mut count = slots.buckets.len() * 2;
+
+        'grow: loop {
+            let data = mem::take(&mut slots.data);
+            *slots = CuckooSlots::with_buckets(count, data);
+
+            for index in 0..slots.data.len() {
+                let located = match *slots.data[index].read().unwrap() {
+                    Some(ref entry) => slots.locate(&entry.key),
+                    None => continue,
+                };
+
+                let (tag, first, second) = located;
+                if !self.table.insert_slot(&slots, tag, first, second, index) {
+                    count *= 2;
+                    continue 'grow;
+                }
+            }
+            return;
+        }
+    }
+}
+
+fn slot_value(tag: u8, index: usize) -> u64 {
+    ((tag as u64) << 56) | (index as u64 + 1)
+}
+
+fn slot_tag(value: u64) -> u8 {
+    (value >> 56) as u8
+}
+
+fn slot_index(value: u64) -> usize {
+    ((value & 0x00ff_ffff_ffff_ffff) - 1) as usize
+}
+
+impl CacheStorageStructure for CuckooStorageStructure {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn memory_usage(&self) -> usize {
+        let slots = self.table.slots.read().unwrap();
+        memory::vec_usage::<[AtomicU64; CUCKOO_SLOTS]>(slots.buckets.capacity())
+            + memory::vec_usage::<AtomicU64>(self.table.versions.capacity())
+            + memory::vec_usage::<RwLock<Option<DataEntry>>>(slots.data.capacity())
+            + memory::vec_usage::<usize>(self.free_slots.capacity())
+            + self.heap_size
+    }
+
+    fn get(&self, key: Key) -> Option<(usize, DataEntry)> {
+        let slots = self.table.slots.read().unwrap();
+        self.table.lookup(&slots, &key, |entry| entry.clone())
+    }
+
+    fn get_index(&self, index: usize) -> Option<(usize, DataEntry)> {
+        let slots = self.table.slots.read().unwrap();
+        match slots.data.get(index) {
+            Some(entry) => entry.read().unwrap().clone().map(|entry| (index, entry)),
+            None => None
+        }
+    }
+
+    fn update_index(&mut self, index: usize, update: &mut dyn FnMut(&mut DataEntry)) -> bool {
+        let slots = self.table.slots.read().unwrap();
+        let mut entry = match slots.data.get(index) {
+            Some(entry) => entry.write().unwrap(),
+            None => return false,
+        };
+        entry.as_mut().map(update).is_some()
+    }
+
+    fn set(&mut self, entry: DataEntry) -> (usize, Option<DataEntry>) {
+        self.size += entry.len();
+        self.heap_size += entry.heap_usage();
+
+        {
+            let slots = self.table.slots.read().unwrap();
+            if let Some((index, _)) = self.table.lookup(&slots, &entry.key, |_| ()) {
+                let old_entry = slots.data[index].write().unwrap().replace(entry).unwrap();
+                self.size -= old_entry.len();
+                self.heap_size -= old_entry.heap_usage();
+                return (index, Some(old_entry));
+            }
+        }
+
+        let index = match self.free_slots.pop() {
+            Some(index) => index,
+            None => {
+                let mut slots = self.table.slots.write().unwrap();
+                slots.data.reserve_exact(1);
+                slots.data.push(RwLock::new(None));
+                slots.data.len() - 1
+            }
+        };
+
+        let placed = {
+            let slots = self.table.slots.read().unwrap();
+            let (tag, first, second) = slots.locate(&entry.key);
+            *slots.data[index].write().unwrap() = Some(entry);
+            self.table.insert_slot(&slots, tag, first, second, index)
+        };
+        if !placed {
+            // The entry is already in place so growing the table places it as well
+            self.grow();
+        }
+
+        (index, None)
+    }
+
+    fn remove(&mut self, key: Key) -> Option<(usize, DataEntry)> {
+        let found = {
+            let slots = self.table.slots.read().unwrap();
+            self.table.lookup(&slots, &key, |_| ())
+        };
+        match found {
+            Some((index, _)) => self.remove_index(index),
+            None => None
+        }
+    }
+
+    fn remove_index(&mut self, index: usize) -> Option<(usize, DataEntry)> {
+        let removed = {
+            let slots = self.table.slots.read().unwrap();
+            let mut entry = match slots.data.get(index) {
+                Some(entry) => entry.write().unwrap(),
+                None => return None,
+            };
+            let (tag, first, second) = match *entry {
+                Some(ref entry) => slots.locate(&entry.key),
+                None => return None,
+            };
+
+            if let Some((bucket, slot)) = slots.find_slot(tag, first, second, index) {
+                slots.buckets[bucket][slot].store(0, Ordering::Release);
+            }
+            entry.take().unwrap()
+        };
+
         self.size -= removed.len();
-        Some((index, removed.clone()))
+        self.heap_size -= removed.heap_usage();
+        self.free_slots.push(index);
+        Some((index, removed))
     }
 
-    fn contains(&mut self, key: Key) -> bool {
-        match self.get(key) {
-            Some(_) => true,
-            None => false
-        }
-    }
-}
-
-// pub struct HashStorageStructure {
-//     rid_map: HashMap<Key, usize>,
-//     data: Vec<DataEntry>,
-//     size: usize,
-// }
-
-// impl CacheStorageStructure for HashStorageStructure {
-//     fn new() -> Self {
-//         HashStorageStructure {
-//             rid_map: HashMap::new(),
-//             data: Vec::new(),
-//             size: 0
-//         }
-//     }    
-
-//     fn size(&self) -> usize {
-//         self.size
-//     }
-
-//     fn get(&mut self, key: Key) -> Option<DataEntry> {
-//         match self.rid_map.get(&key).cloned() {
-//             Some(index) => Some(self.data[index].clone()),
-//             None => None
-//         }
-//     }
-
-//     fn set(&mut self, key: Key, value: Value) -> Result<usize, CacheError> {
-//         self.size += value.len();                
-        
-//         let new = DataEntry::new(key.clone(), value);
-//         match self.rid_map.get(&key).cloned() {
-//             Some(index) => {
-//                 self.data.remove(index);
-//                 self.data.insert(index, new);
-//                 Ok(index)
-//             },
-//             None => {
-//                 self.data.push(new);
-//                 self.rid_map.insert(key, self.data.len() - 1);
-//                 Ok(self.data.len() - 1)
-//             },
-//         }
-//     }
-
-//     fn remove(&mut self, key: Key) -> Result<DataEntry, CacheError> {
-//         match self.rid_map.get(&key).cloned() {
-//             Some(index) => {
-//                 // TODO: Figure out more efficient way
-//                 let removed = self.data.remove(index);
-//                 let start_index = self.rid_map.get(&key.clone()).cloned().unwrap();
-//                 for k in self.data.iter_mut().skip(start_index) {
-//                     let new_index = self.rid_map.get(&k.key).unwrap() - 1;
-//                     self.rid_map.insert(k.key.clone(), new_index);
-//                 }
-//                 self.rid_map.remove(&key);
-//                 self.size -= removed.len();
-
-//                 Ok(removed)
-//             },
-//             None => Err(CacheError::KeyNotFound),
-//         }
-//     }
-
-//     fn contains(&mut self, key: Key) -> bool {
-//         self.rid_map.contains_key(&key)
-//     }
-
-//     fn get_index(&mut self, key: Key) -> Option<usize> {
-//         self.rid_map.get(&key).cloned()
-//     }
-
-//     fn get_with_index(&mut self, index: usize) -> Option<DataEntry> {
-//         self.data.get(index).cloned()
-//     }
-
-//     fn move_entry(&mut self, key: Key, index: usize) -> Result<(), CacheError> {
-//         match self.rid_map.get(&key).cloned() {
-//             Some(index) => {
-//                 let removed = self.data.remove(index);
-//                 self.data.insert(index, removed);
-//                 Ok(())
-//             },
-//             None => Err(CacheError::KeyNotFound)
-//         }
-//     }
-// }
\ No newline at end of file
+    fn indices(&self) -> Vec<usize> {
+        let slots = self.table.slots.read().unwrap();
+        slots.data.iter()
+            .enumerate()
+            .filter(|&(_, entry)| entry.read().unwrap().is_some())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    fn reader(&self) -> Option<StorageReader> {
+        Some(StorageReader { table: self.table.clone() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use cache::value::Value;
+
+    fn entry(key: &str, value: &str) -> DataEntry {
+        DataEntry::new(Key::new(key.to_string()), Value::new(value.to_string()))
+    }
+
+    fn key(key: &str) -> Key {
+        Key::new(key.to_string())
+    }
+
+    fn set_get_remove<S: CacheStorageStructure>(mut structure: S) {
+        let (a, replaced) = structure.set(entry("a", "1"));
+        assert!(replaced.is_none());
+        let (b, _) = structure.set(entry("b", "22"));
+        assert_ne!(a, b);
+        assert_eq!(structure.size(), 5);
+
+        // Replacing a key keeps its index
+        let (index, replaced) = structure.set(entry("a", "333"));
+        assert_eq!(index, a);
+        assert_eq!(replaced.unwrap().value, Value::new("1".to_string()));
+        assert_eq!(structure.get(key("a")).unwrap().1.value, Value::new("333".to_string()));
+        assert_eq!(structure.size(), 7);
+
+        assert_eq!(structure.remove(key("a")).map(|(index, _)| index), Some(a));
+        assert!(structure.get(key("a")).is_none());
+        assert!(structure.remove(key("a")).is_none());
+        assert_eq!(structure.get_index(b).unwrap().1.key, key("b"));
+        assert_eq!(structure.indices(), vec![b]);
+        assert_eq!(structure.size(), 3);
+    }
+
+    #[test]
+    fn every_structure_sets_gets_and_removes() {
+        set_get_remove(NaiveStorageStructure::new());
+        set_get_remove(HashStorageStructure::new());
+        set_get_remove(CuckooStorageStructure::new());
+        set_get_remove(OrderedStorageStructure::new());
+    }
+
+    #[test]
+    fn cuckoo_displaces_and_grows_without_losing_entries() {
+        let mut structure = CuckooStorageStructure::new();
+        let buckets = structure.table.slots.read().unwrap().buckets.len();
+        for i in 0..1000 {
+            structure.set(entry(&format!("k{}", i), "v"));
+        }
+        let slots = structure.table.slots.read().unwrap();
+        assert!(slots.buckets.len() > buckets);
+
+        // Every entry sits in one of its two buckets
+        for i in 0..1000 {
+            let (index, entry) = structure.get(key(&format!("k{}", i))).unwrap();
+            let (tag, first, second) = slots.locate(&entry.key);
+            assert!(slots.find_slot(tag, first, second, index).is_some());
+        }
+        drop(slots);
+
+        for i in (0..1000).filter(|i| i % 2 == 0) {
+            assert!(structure.remove(key(&format!("k{}", i))).is_some());
+        }
+        for i in 0..1000 {
+            assert_eq!(structure.get(key(&format!("k{}", i))).is_some(), i % 2 == 1);
+        }
+        assert_eq!(structure.indices().len(), 500);
+    }
+
+    #[test]
+    fn cuckoo_readers_find_keys_while_entries_are_displaced() {
+        let mut structure = CuckooStorageStructure::new();
+        for i in 0..100 {
+            structure.set(entry(&format!("k{}", i), "v"));
+        }
+        let reader = structure.reader().unwrap();
+
+        thread::scope(|scope| {
+            let readers: Vec<_> = (0..2).map(|_| {
+                let reader = reader.clone();
+                scope.spawn(move || {
+                    for _ in 0..200 {
+                        for i in 0..100 {
+                            if reader.get(&key(&format!("k{}", i))).is_none() {
+                                return false;
+                            }
+                        }
+                    }
+                    true
+                })
+            }).collect();
+
+            // Fill the table far enough to displace entries and grow it several times over
+            for i in 0..5000 {
+                structure.set(entry(&format!("new{}", i), "v"));
+                if i % 3 == 0 {
+                    structure.remove(key(&format!("new{}", i)));
+                }
+            }
+
+            for reader in readers {
+                assert!(reader.join().unwrap());
+            }
+        });
+        assert!(reader.get(&key("new1")).is_some());
+        assert!(reader.get(&key("new0")).is_none());
+    }
+
+    #[test]
+    fn cuckoo_misses_are_not_trusted_while_entries_are_displaced() {
+        let structure = CuckooStorageStructure::new();
+        let reader = structure.reader().unwrap();
+        let (_, first, second) = structure.table.slots.read().unwrap().locate(&key("missing"));
+
+        structure.table.begin_write(first, second);
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || sender.send(reader.get(&key("missing")).is_none()).unwrap());
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+        structure.table.end_write(first, second);
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(10)), Ok(true));
+    }
+    fn scan_by_prefix<S: CacheStorageStructure>(mut structure: S) {
+        for name in ["b:2", "a:1", "b:1", "b:3", "c:1"].iter() {
+            structure.set(entry(name, "v"));
+        }
+
+        let keys = |entries: Vec<(usize, DataEntry)>| -> Vec<String> {
+            entries.into_iter().map(|(_, entry)| entry.key.item).collect()
+        };
+        assert_eq!(keys(structure.scan("b:", None, 10)), vec!["b:1", "b:2", "b:3"]);
+        assert_eq!(keys(structure.scan("b:", None, 2)), vec!["b:1", "b:2"]);
+        assert_eq!(keys(structure.scan("b:", Some(&key("b:2")), 10)), vec!["b:3"]);
+        assert_eq!(keys(structure.scan("", Some(&key("b:3")), 10)), vec!["c:1"]);
+        assert!(structure.scan("d", None, 10).is_empty());
+    }
+
+    #[test]
+    fn scans_return_keys_in_order() {
+        scan_by_prefix(HashStorageStructure::new());
+        scan_by_prefix(OrderedStorageStructure::new());
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::{HashMap, HashSet};
+
+use cache::key::Key;
+use cache::memory;
+
+/**
+ * The keys carrying each tag.  The index is kept in step with the entries in the storage
+ * structure, every entry that is stored adds its key under its tags and every entry that leaves,
+ * whether it was replaced, removed, evicted or reclaimed after it expired, takes its key out again.
+ * A tag without keys is dropped.
+ */
+pub struct TagIndex {
+    keys: HashMap<String, HashSet<Key>>,
+    usage: usize, // Memory held by the tags and their sets of keys, kept up to date as they change
+}
+
+impl TagIndex {
+    pub fn new() -> TagIndex {
+        TagIndex {
+            keys: HashMap::new(),
+            usage: 0,
+        }
+    }
+
+    pub fn add(&mut self, key: &Key, tags: &[String]) {
+        for tag in tags {
+            if !self.keys.contains_key(tag) {
+                self.usage += memory::allocation(tag.len());
+            }
+
+            let keys = self.keys.entry(tag.clone()).or_default();
+            let before = memory::hash_set_usage(keys);
+            if keys.insert(key.clone()) {
+                self.usage += memory::allocation(key.item.len());
+            }
+            self.usage = self.usage + memory::hash_set_usage(keys) - before;
+        }
+    }
+
+    pub fn remove(&mut self, key: &Key, tags: &[String]) {
+        for tag in tags {
+            let empty = match self.keys.get_mut(tag) {
+                Some(keys) => {
+                    if let Some(removed) = keys.take(key) {
+                        self.usage -= memory::allocation(removed.item.len());
+                    }
+                    keys.is_empty()
+                },
+                None => false,
+            };
+            if empty {
+                if let Some((tag, keys)) = self.keys.remove_entry(tag) {
+                    self.usage -= memory::allocation(tag.len()) + memory::hash_set_usage(&keys);
+                }
+            }
+        }
+    }
+
+    /**
+     * The keys carrying the tag
+     */
+    pub fn keys(&self, tag: &str) -> Vec<Key> {
+        match self.keys.get(tag) {
+            Some(keys) => keys.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+        self.usage = 0;
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        memory::hash_map_usage(&self.keys) + self.usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::cache::tests::key;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    fn sorted(mut keys: Vec<Key>) -> Vec<String> {
+        keys.sort();
+        keys.into_iter().map(|key| key.item).collect()
+    }
+
+    #[test]
+    fn keys_are_indexed_under_each_of_their_tags() {
+        let mut index = TagIndex::new();
+        index.add(&key("a"), &tags(&["red", "big"]));
+        index.add(&key("b"), &tags(&["red"]));
+        index.add(&key("b"), &tags(&["red"]));
+
+        assert_eq!(sorted(index.keys("red")), vec!["a", "b"]);
+        assert_eq!(sorted(index.keys("big")), vec!["a"]);
+        assert!(index.keys("blue").is_empty());
+
+        index.remove(&key("a"), &tags(&["red", "big"]));
+        assert_eq!(sorted(index.keys("red")), vec!["b"]);
+        assert!(index.keys("big").is_empty());
+
+        // Dropping the last key of every tag gives back all the memory
+        index.remove(&key("b"), &tags(&["red"]));
+        assert!(index.keys.is_empty());
+        assert_eq!(index.usage, 0);
+    }
+}
@@ -0,0 +1,12 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/**
+ * The current time as seconds since the unix epoch, the resolution used for expiry and access
+ * times of entries
+ */
+pub fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0,
+    }
+}
@@ -1,15 +1,47 @@
+use std::mem;
+use std::sync::Arc;
+
+use cache::memory;
+
+/**
+ * Values are kept as a chain of chunks of at most `CHUNK_SIZE` bytes so that a large value never
+ * needs a single contiguous allocation.  Chunks are reference counted, handing a value out of the
+ * cache only copies the chain and not the data.
+ */
+pub const CHUNK_SIZE: usize = 512 * 1024;
+
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub struct Value {
-    // TODO: Vec<u8>
-    pub item: String,
+    pub chunks: Vec<Arc<[u8]>>,
     pub cas: u64,
+    len: usize,
 }
 
 impl Value {
     pub fn new(item: String) -> Value {
+        Value::from_bytes(item.into_bytes())
+    }
+
+    pub fn from_bytes(item: Vec<u8>) -> Value {
+        let chunks = item.chunks(CHUNK_SIZE).map(Arc::from).collect();
+
         Value { 
-            item: item, 
-            cas: 0 
+            chunks,
+            cas: 0,
+            len: item.len(),
+        }
+    }
+
+    /**
+     * A value made of the given chunks as they are, each should be at most `CHUNK_SIZE` bytes
+     */
+    pub fn from_chunks(chunks: Vec<Arc<[u8]>>) -> Value {
+        let len = chunks.iter().map(|chunk| chunk.len()).sum();
+
+        Value {
+            chunks,
+            cas: 0,
+            len,
         }
     }
 
@@ -18,6 +50,32 @@ impl Value {
     }
 
     pub fn len(&self) -> usize {
-        self.item.len()
+        self.len
     }
-}
\ No newline at end of file
+
+    /**
+     * Heap memory held by the chunk chain, each chunk is an allocation with reference counts
+     */
+    pub fn heap_usage(&self) -> usize {
+        let chunks: usize = self.chunks.iter().map(|chunk| memory::allocation(2 * mem::size_of::<usize>() + chunk.len())).sum();
+        memory::vec_usage::<Arc<[u8]>>(self.chunks.capacity()) + chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_values_are_split_into_chunks() {
+        let bytes: Vec<u8> = (0..2 * CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        let value = Value::from_bytes(bytes.clone());
+
+        assert_eq!(value.len(), bytes.len());
+        assert_eq!(value.chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![CHUNK_SIZE, CHUNK_SIZE, 10]);
+        assert_eq!(value.chunks.concat(), bytes);
+        assert_eq!(Value::from_chunks(value.chunks.clone()), value);
+
+        assert!(Value::new(String::new()).chunks.is_empty());
+    }
+}
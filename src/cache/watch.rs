@@ -0,0 +1,244 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use cache::key::Key;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EventKind {
+    Set,
+    Delete,
+    Evict,
+    Expire, // An expired entry was reclaimed
+}
+
+impl EventKind {
+    pub fn all() -> Vec<EventKind> {
+        vec![EventKind::Set, EventKind::Delete, EventKind::Evict, EventKind::Expire]
+    }
+
+    pub fn from_name(name: &str) -> Option<EventKind> {
+        match name.to_lowercase().as_str() {
+            "set" | "sets" => Some(EventKind::Set),
+            "delete" | "deletes" => Some(EventKind::Delete),
+            "evict" | "evictions" => Some(EventKind::Evict),
+            "expire" | "expirations" => Some(EventKind::Expire),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EventKind::Set => "set",
+            EventKind::Delete => "delete",
+            EventKind::Evict => "evict",
+            EventKind::Expire => "expire",
+        }
+    }
+}
+
+pub struct Event {
+    pub kind: EventKind,
+    pub key: String,
+}
+
+/**
+ * A connection watching the events of keys starting with `prefix`.  Events wait in a buffer of
+ * at most `capacity` events for the connection to send them, an event that finds the buffer full
+ * is dropped and counted instead.  Once the connection hangs up the subscriber is closed, which
+ * wakes whoever waits on it.
+ */
+pub struct Subscriber {
+    prefix: String,
+    kinds: Vec<EventKind>,
+    capacity: usize,
+    queue: Mutex<VecDeque<Event>>,
+    ready: Condvar,
+    closed: AtomicBool,
+    pub dropped: AtomicU64,
+}
+
+impl Subscriber {
+    fn wants(&self, kind: EventKind, key: &Key) -> bool {
+        self.kinds.contains(&kind) && key.item.starts_with(&self.prefix)
+    }
+
+    fn offer(&self, kind: EventKind, key: &Key) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        queue.push_back(Event { kind, key: key.item.clone() });
+        self.ready.notify_one();
+        true
+    }
+
+    /**
+     * Take the buffered events, waiting up to `timeout` for one if there are none
+     */
+    pub fn wait(&self, timeout: Duration) -> VecDeque<Event> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.is_empty() && !self.is_closed() {
+            queue = self.ready.wait_timeout(queue, timeout).unwrap().0;
+        }
+        mem::take(&mut *queue)
+    }
+
+    pub fn close(&self) {
+        let _queue = self.queue.lock().unwrap();
+        self.closed.store(true, Ordering::Relaxed);
+        self.ready.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+/**
+ * The subscribers to the events of a namespace.  Events are published by the shards while they
+ * are locked, so publishing never waits on a subscriber: with nobody watching it is a single
+ * atomic load, otherwise the event is copied into the buffer of every subscriber that wants it.
+ */
+pub struct Watchers {
+    pub capacity: usize, // Events buffered per subscriber
+    subscribers: RwLock<Vec<Arc<Subscriber>>>,
+    active: AtomicUsize,
+    pub published: AtomicU64,
+    pub dropped: AtomicU64,
+}
+
+/**
+ * A subscription that ends when it is dropped
+ */
+pub struct Subscription {
+    watchers: Arc<Watchers>,
+    pub subscriber: Arc<Subscriber>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.watchers.unsubscribe(&self.subscriber);
+    }
+}
+
+impl Watchers {
+    pub fn new(capacity: usize) -> Watchers {
+        Watchers {
+            capacity,
+            subscribers: RwLock::new(Vec::new()),
+            active: AtomicUsize::new(0),
+            published: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn subscribe(watchers: &Arc<Watchers>, prefix: &str, kinds: Vec<EventKind>) -> Subscription {
+        let subscriber = Arc::new(Subscriber {
+            prefix: String::from(prefix),
+            kinds,
+            capacity: watchers.capacity,
+            queue: Mutex::new(VecDeque::new()),
+            ready: Condvar::new(),
+            closed: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+        });
+
+        let mut subscribers = watchers.subscribers.write().unwrap();
+        subscribers.push(subscriber.clone());
+        watchers.active.store(subscribers.len(), Ordering::Relaxed);
+
+        Subscription {
+            watchers: watchers.clone(),
+            subscriber,
+        }
+    }
+
+    fn unsubscribe(&self, subscriber: &Arc<Subscriber>) {
+        let mut subscribers = self.subscribers.write().unwrap();
+        subscribers.retain(|other| !Arc::ptr_eq(other, subscriber));
+        self.active.store(subscribers.len(), Ordering::Relaxed);
+    }
+
+    pub fn publish(&self, kind: EventKind, key: &Key) {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        for subscriber in self.subscribers.read().unwrap().iter() {
+            if subscriber.wants(kind, key) {
+                if subscriber.offer(kind, key) {
+                    self.published.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Vec<(String, String)> {
+        vec![
+            (String::from("watchers"), self.active.load(Ordering::Relaxed).to_string()),
+            (String::from("watch_events"), self.published.load(Ordering::Relaxed).to_string()),
+            (String::from("watch_dropped"), self.dropped.load(Ordering::Relaxed).to_string()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+    use cache::cache::tests::key;
+
+    fn events(subscriber: &Subscriber) -> Vec<(EventKind, String)> {
+        subscriber.wait(Duration::from_millis(0)).into_iter().map(|event| (event.kind, event.key)).collect()
+    }
+
+    #[test]
+    fn subscribers_get_the_events_they_asked_for() {
+        let watchers = Arc::new(Watchers::new(2));
+        let users = Watchers::subscribe(&watchers, "user:", vec![EventKind::Set, EventKind::Delete]);
+        let evictions = Watchers::subscribe(&watchers, "", vec![EventKind::Evict]);
+
+        watchers.publish(EventKind::Set, &key("user:1"));
+        watchers.publish(EventKind::Set, &key("session:1"));
+        watchers.publish(EventKind::Evict, &key("user:2"));
+        watchers.publish(EventKind::Delete, &key("user:1"));
+        watchers.publish(EventKind::Set, &key("user:3"));
+
+        // The buffer holds two events, the third is dropped
+        assert_eq!(events(&users.subscriber), vec![(EventKind::Set, String::from("user:1")), (EventKind::Delete, String::from("user:1"))]);
+        assert_eq!(users.subscriber.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(events(&evictions.subscriber), vec![(EventKind::Evict, String::from("user:2"))]);
+        assert_eq!(watchers.published.load(Ordering::Relaxed), 3);
+        assert_eq!(watchers.dropped.load(Ordering::Relaxed), 1);
+
+        drop(users);
+        assert_eq!(watchers.active.load(Ordering::Relaxed), 1);
+        watchers.publish(EventKind::Set, &key("user:4"));
+        assert_eq!(watchers.published.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn closing_wakes_a_waiting_subscriber() {
+        let watchers = Arc::new(Watchers::new(16));
+        let subscription = Watchers::subscribe(&watchers, "", EventKind::all());
+        let subscriber = subscription.subscriber.clone();
+
+        let start = Instant::now();
+        let waiter = thread::spawn(move || subscriber.wait(Duration::from_secs(30)).len());
+        thread::sleep(Duration::from_millis(50));
+        subscription.subscriber.close();
+
+        assert_eq!(waiter.join().unwrap(), 0);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(subscription.subscriber.is_closed());
+        assert!(subscription.subscriber.wait(Duration::from_secs(30)).is_empty());
+    }
+}
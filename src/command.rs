@@ -1,13 +1,18 @@
 use std::borrow::Borrow;
+use std::mem;
 use packet::MemPacket;
 
-use cache::cache::Cache;
+use cache::namespace::Namespaces;
+use persistence::Persistence;
+use persistence::oplog::Op;
+use cache::key::Key;
+use cache::watch::{Watchers, Subscription, EventKind};
 use cache::storage_structure::CacheStorageStructure;
 use cache::replacement_policy::CacheReplacementPolicy;
 
 use commands;
 
-fn handle_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(packet: MemPacket, cache: &mut Cache<T, R>) -> Option<MemPacket> {
+fn handle_command<T: CacheStorageStructure>(mut packet: MemPacket, namespaces: &Namespaces<T, Box<dyn CacheReplacementPolicy + Send + Sync>>, persistence: &Persistence, namespace: &mut String) -> Option<MemPacket> {
     println!("handle_command");
     println!("{:?}", packet.header);
     println!("{:?}", packet);
@@ -19,12 +24,109 @@ fn handle_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(packet: M
         return Some(response);
     }
 
+    match packet.header.opcode {
+        0xe3 => return commands::namespace::use_command(packet, namespaces, namespace),
+        0xe4 => return commands::namespace::namespace_command(packet, namespaces, persistence),
+        0xe5 => return commands::admin::snapshot_command(packet, namespaces, persistence),
+        _ => {},
+    }
+
+    // Key based commands go to the namespace named by the key if there is one, everything else
+    // to the namespace selected by the connection
+    let routed = match packet.header.opcode {
+        0x00 | 0x01 | 0x02 | 0x03 | 0x04 | 0x05 | 0x06 | 0x0e | 0x0f | 0xe6 | 0xe7 | 0xe9 => namespaces.route(namespace, &packet.key)
+            .map(|(cache, name, key)| (cache, String::from(name), String::from(key))),
+        _ => namespaces.get(namespace).map(|cache| (cache, namespace.clone(), packet.key.clone())),
+    };
+
+    let (cache, name) = match routed {
+        Some((cache, name, key)) => {
+            let value = mem::take(&mut packet.value);
+            packet.with_key(key);
+            packet.with_value(value);
+            (cache, name)
+        },
+        None => {
+            response.header.with_status(0x0001);
+            response.with_value(String::from("Unknown namespace"));
+            return Some(response);
+        }
+    };
+    let cache = &*cache;
+
+    // Key based commands only lock the shard holding the key
+    let key = Key::new(packet.key.clone());
+
+    // Changes are logged while the shards they changed are still locked, so the log has the
+    // changes to a key in the order they were made
     match packet.header.opcode {
         0x00 => commands::get::get_command(packet, cache),
-        0x01 => commands::set::set_command(packet, cache),
-        0x02 => commands::set::add_command(packet, cache),
-        0x03 => commands::set::replace_command(packet, cache),
-        0x04 => commands::delete::delete_command(packet, cache),
+        0xe6 => commands::lease::lease_get_command(packet, &mut cache.shard(&key)),
+        0x01 | 0x02 | 0x03 | 0x05 | 0x06 | 0x0e | 0x0f | 0xe7 => {
+            let mut shard = cache.shard(&key);
+            let response = match packet.header.opcode {
+                0x01 => commands::set::set_command(packet, &mut shard),
+                0x02 => commands::set::add_command(packet, &mut shard),
+                0x03 => commands::set::replace_command(packet, &mut shard),
+                0x05 | 0x06 => commands::incr::incr_command(packet, &mut shard),
+                0xe7 => commands::lease::lease_set_command(packet, &mut shard),
+                _ => commands::set::append_command(packet, &mut shard),
+            };
+
+            if succeeded(&response) {
+                // The entry may not have survived making room for it, and is logged as it was set
+                match shard.storage_structure.get(key.clone()).and_then(|(_, entry)| shard.materialize(entry).ok()) {
+                    Some(entry) => persistence.log(Op::Set(&name, &entry)),
+                    None => persistence.log(Op::Delete(&name, &key)),
+                }
+            }
+            response
+        },
+        0x04 => {
+            let mut shard = cache.shard(&key);
+            let response = commands::delete::delete_command(packet, &mut shard);
+            if succeeded(&response) {
+                persistence.log(Op::Delete(&name, &key));
+            }
+            response
+        },
+        0x08 => {
+            let mut shards = cache.lock_all();
+            let response = commands::delete::flush_command(packet, &mut shards);
+            if succeeded(&response) {
+                persistence.log(Op::Flush(&name));
+            }
+            response
+        },
+        0xe8 => {
+            let tag = packet.key.clone();
+            let mut shards = cache.lock_all();
+            let response = commands::delete::invalidate_command(packet, &mut shards);
+            if succeeded(&response) {
+                persistence.log(Op::InvalidateTag(&name, &tag));
+            }
+            response
+        },
+        0xe9 => {
+            let prefix = packet.key.clone();
+            let mut shards = cache.lock_all();
+            let response = commands::delete::flush_prefix_command(packet, &mut shards);
+            if succeeded(&response) {
+                persistence.log(Op::FlushPrefix(&name, &prefix));
+            }
+            response
+        },
+        0x10 => commands::stat::stat_command(packet, cache),
+        0xe0 => {
+            let policy = packet.key.clone();
+            let response = commands::admin::policy_command(packet, namespaces, &name);
+            if succeeded(&response) {
+                persistence.log(Op::SetPolicy(&name, &policy));
+            }
+            response
+        },
+        0xe1 => commands::admin::slabs_command(packet, cache),
+        0xe2 => commands::admin::scan_command(packet, cache),
         _ => {
             response.header.with_status(0x0081);
             Some(response) 
@@ -32,8 +134,55 @@ fn handle_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(packet: M
     }
 }
 
+fn succeeded(response: &Option<MemPacket>) -> bool {
+    match *response {
+        Some(ref response) => response.header.status == 0x0000,
+        None => false,
+    }
+}
+
+/**
+ * Subscribe to the events of a namespace if the command is `WATCH [prefix] [kinds]`, kinds being
+ * a comma separated list of set, delete, evict and expire, all of them by default.  The prefix
+ * may name the namespace like a key does, `<namespace>:` alone being a prefix of the current
+ * namespace.
+ */
+pub fn parse_watch<T: CacheStorageStructure>(command: &str, namespaces: &Namespaces<T, Box<dyn CacheReplacementPolicy + Send + Sync>>, namespace: &str) -> Option<Result<Subscription, MemPacket>> {
+    let mut iter = command.split_whitespace();
+    match iter.next() {
+        Some(cmd) if cmd.to_uppercase() == "WATCH" => {},
+        _ => return None,
+    }
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(0xea);
+
+    let prefix = iter.next().unwrap_or("");
+    let kinds = match iter.next() {
+        Some(kinds) => {
+            let parsed: Vec<Option<EventKind>> = kinds.split(',').map(EventKind::from_name).collect();
+            if parsed.iter().any(|kind| kind.is_none()) {
+                response.header.with_status(0x0004);
+                response.with_value(format!("Unknown event kind in {}", kinds));
+                return Some(Err(response));
+            }
+            parsed.into_iter().flatten().collect()
+        },
+        None => EventKind::all(),
+    };
+
+    match namespaces.route(namespace, prefix) {
+        Some((cache, _, prefix)) => Some(Ok(Watchers::subscribe(&cache.watchers(), prefix, kinds))),
+        None => {
+            response.header.with_status(0x0001);
+            response.with_value(String::from("Unknown namespace"));
+            Some(Err(response))
+        }
+    }
+}
+
 // TODO: This will eventually be removed once a client is implemented, for now this exists for the purposes of telnet
-pub fn parse_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(command: &str, cache: &mut Cache<T, R>) -> Option<MemPacket> {
+pub fn parse_command<T: CacheStorageStructure>(command: &str, namespaces: &Namespaces<T, Box<dyn CacheReplacementPolicy + Send + Sync>>, persistence: &Persistence, namespace: &mut String) -> Option<MemPacket> {
     let mut iter = command.split_whitespace();
 
     let mut extra_bytes: Vec<u8> = Vec::new();
@@ -53,6 +202,8 @@ pub fn parse_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(comman
                     code = 0x01;
                     key_bytes = Vec::from(iter.next().unwrap().as_bytes());
                     value_bytes = Vec::from(iter.next().unwrap().as_bytes());        
+                    // Optional ttl in seconds followed by optional comma separated tags
+                    extra_bytes = Vec::from(iter.collect::<Vec<&str>>().join(" ").as_bytes());
                 },
                 "ADD" => {
                     code = 0x02;
@@ -68,13 +219,16 @@ pub fn parse_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(comman
                     code = 0x04;
                     key_bytes = Vec::from(iter.next().unwrap().as_bytes());          
                 },
-                "INCREMENT" => {
+                "INCREMENT" | "INCR" => {
                     code = 0x05;
-                    // TODO:            
+                    key_bytes = Vec::from(iter.next().unwrap().as_bytes());
+                    // Optional delta, 1 without one
+                    extra_bytes = Vec::from(iter.next().unwrap_or("").as_bytes());
                 },
-                "DECREMENT" => {
+                "DECREMENT" | "DECR" => {
                     code = 0x06;
-                    // TODO:            
+                    key_bytes = Vec::from(iter.next().unwrap().as_bytes());
+                    extra_bytes = Vec::from(iter.next().unwrap_or("").as_bytes());
                 },
                 "QUIT" => {
                     code = 0x07;
@@ -82,7 +236,6 @@ pub fn parse_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(comman
                 },
                 "FLUSH" => {
                     code = 0x08;
-                    // TODO:            
                 },
                 "GETQ" => {
                     code = 0x09;
@@ -106,15 +259,17 @@ pub fn parse_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(comman
                 },
                 "APPEND" => {
                     code = 0x0e;
-                    // TODO:            
+                    key_bytes = Vec::from(iter.next().unwrap().as_bytes());
+                    value_bytes = Vec::from(iter.next().unwrap().as_bytes());
                 },
                 "PREPEND" => {
                     code = 0x0f;
-                    // TODO:            
+                    key_bytes = Vec::from(iter.next().unwrap().as_bytes());
+                    value_bytes = Vec::from(iter.next().unwrap().as_bytes());
                 },
-                "STAT" => {
+                "STAT" | "STATS" => {
                     code = 0x10;
-                    // TODO:            
+                    key_bytes = Vec::from(iter.next().unwrap_or("").as_bytes());
                 },
                 "SETQ" => {
                     code = 0x11;
@@ -156,6 +311,51 @@ pub fn parse_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(comman
                     code = 0x1a;
                     // TODO:            
                 },
+                "SLABS" => {
+                    code = 0xe1;
+                    key_bytes = Vec::from(iter.next().unwrap_or("").as_bytes());
+                    value_bytes = Vec::from(iter.collect::<Vec<&str>>().join(" ").as_bytes());
+                },
+                "SCAN" => {
+                    code = 0xe2;
+                    key_bytes = Vec::from(iter.next().unwrap_or("").as_bytes());
+                    value_bytes = Vec::from(iter.collect::<Vec<&str>>().join(" ").as_bytes());
+                },
+                "USE" => {
+                    code = 0xe3;
+                    key_bytes = Vec::from(iter.next().unwrap_or("").as_bytes());
+                },
+                "NAMESPACE" => {
+                    code = 0xe4;
+                    key_bytes = Vec::from(iter.next().unwrap_or("").as_bytes());
+                    value_bytes = Vec::from(iter.collect::<Vec<&str>>().join(" ").as_bytes());
+                },
+                "SNAPSHOT" => {
+                    code = 0xe5;
+                },
+                "GETL" => {
+                    code = 0xe6;
+                    key_bytes = Vec::from(iter.next().unwrap().as_bytes());
+                },
+                "SETL" => {
+                    code = 0xe7;
+                    key_bytes = Vec::from(iter.next().unwrap().as_bytes());
+                    value_bytes = Vec::from(iter.next().unwrap().as_bytes());
+                    // Lease token, optional ttl in seconds and optional comma separated tags
+                    extra_bytes = Vec::from(iter.collect::<Vec<&str>>().join(" ").as_bytes());
+                },
+                "INVALIDATE" => {
+                    code = 0xe8;
+                    key_bytes = Vec::from(iter.next().unwrap_or("").as_bytes());
+                },
+                "FLUSH_PREFIX" => {
+                    code = 0xe9;
+                    key_bytes = Vec::from(iter.next().unwrap_or("").as_bytes());
+                },
+                "POLICY" => {
+                    code = 0xe0;
+                    key_bytes = Vec::from(iter.next().unwrap_or("").as_bytes());
+                },
                 _ => {
                     code = 0xFF;
                 }
@@ -175,5 +375,66 @@ pub fn parse_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(comman
     request.with_extras(String::from_utf8_lossy(extra_bytes.as_slice()).into_owned());
     request.with_value(String::from_utf8_lossy(value_bytes.as_slice()).into_owned());
 
-    handle_command(request, cache)
-}
\ No newline at end of file
+    handle_command(request, namespaces, persistence, namespace)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use cache::namespace::DEFAULT_NAMESPACE;
+    use cache::namespace::tests::namespaces;
+    use cache::value::Value;
+
+    fn key(name: &str) -> Key {
+        Key::new(name.to_string())
+    }
+
+    #[test]
+    fn a_namespace_name_alone_is_a_prefix_of_the_current_namespace() {
+        let namespaces = namespaces(1024 * 1024);
+        let persistence = Persistence::new(None, None);
+        let mut current = String::from(DEFAULT_NAMESPACE);
+        assert!(namespaces.create("tenant", 64 * 1024, "lru", 0).is_ok());
+        let default = namespaces.get(DEFAULT_NAMESPACE).unwrap();
+        let tenant = namespaces.get("tenant").unwrap();
+
+        let mut run = |command: &str| parse_command(command, &namespaces, &persistence, &mut current).unwrap().header.status;
+        assert!(default.shard(&key("tenant:a")).set(key("tenant:a"), Value::new(String::from("v"))).is_ok());
+        assert_eq!(run("SET tenant:k v"), 0x0000);
+
+        assert_eq!(run("FLUSH_PREFIX tenant:"), 0x0000);
+        assert!(default.get(key("tenant:a")).is_none());
+        assert!(tenant.get(key("k")).is_some());
+
+        assert_eq!(run("FLUSH_PREFIX"), 0x0004);
+        assert_eq!(run("FLUSH_PREFIX tenant:k"), 0x0000);
+        assert!(tenant.get(key("k")).is_none());
+    }
+
+    #[test]
+    fn watching_a_namespace_name_alone_watches_the_prefix() {
+        let namespaces = namespaces(1024 * 1024);
+        assert!(namespaces.create("tenant", 64 * 1024, "lru", 0).is_ok());
+        let default = namespaces.get(DEFAULT_NAMESPACE).unwrap();
+        let tenant = namespaces.get("tenant").unwrap();
+
+        let prefix = match parse_watch("WATCH tenant: set", &namespaces, DEFAULT_NAMESPACE) {
+            Some(Ok(subscription)) => subscription,
+            _ => panic!("WATCH tenant: was refused"),
+        };
+        let within = match parse_watch("WATCH tenant:k set", &namespaces, DEFAULT_NAMESPACE) {
+            Some(Ok(subscription)) => subscription,
+            _ => panic!("WATCH tenant:k was refused"),
+        };
+
+        for (cache, name) in [(&default, "tenant:a"), (&default, "other"), (&tenant, "k1"), (&tenant, "x")].iter() {
+            assert!(cache.shard(&key(name)).set(key(name), Value::new(String::from("v"))).is_ok());
+        }
+
+        let keys = |subscription: &Subscription| -> Vec<String> {
+            subscription.subscriber.wait(Duration::from_millis(0)).into_iter().map(|event| event.key).collect()
+        };
+        assert_eq!(keys(&prefix), vec!["tenant:a"]);
+        assert_eq!(keys(&within), vec!["k1"]);
+    }
+}
@@ -0,0 +1,152 @@
+use packet::MemPacket;
+
+use cache::sharded_cache::ShardedCache;
+use cache::namespace::Namespaces;
+use persistence::Persistence;
+use cache::storage_structure::CacheStorageStructure;
+use cache::replacement_policy::CacheReplacementPolicy;
+use cache::error::CacheError;
+
+const SCAN_DEFAULT_LIMIT: usize = 100;
+const SCAN_LIMIT: usize = 1000;
+
+/**
+ * Switch the replacement policy of every shard of the namespace, the key names the new policy.
+ * The policy is saved in snapshots and the switch is logged, so it survives a restart.
+ */
+pub fn policy_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, namespaces: &Namespaces<T, R>, namespace: &str) -> Option<MemPacket> {
+    println!("policy_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(request.header.opcode);
+
+    if request.has_extras() || request.has_value() {
+        response.header.with_status(0x0004);
+        return Some(response);
+    }
+
+    if let Err(e) = namespaces.set_policy(namespace, &request.key) {
+        response.header.with_status(0x0004);
+        response.with_value(e);
+        return Some(response);
+    }
+
+    response.header.with_status(0x0000);
+    response.with_value(request.key);
+
+    Some(response)
+}
+
+/**
+ * Slab administration, `reassign <src> <dst>` moves a page from one slab class to another in every
+ * shard that can spare one
+ */
+pub fn slabs_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, cache: &ShardedCache<T, R>) -> Option<MemPacket> {
+    println!("slabs_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(request.header.opcode);
+
+    if request.key != "reassign" {
+        response.header.with_status(0x0004);
+        return Some(response);
+    }
+
+    let classes: Vec<usize> = request.value.split_whitespace().filter_map(|class| class.parse().ok()).collect();
+    if classes.len() != 2 {
+        response.header.with_status(0x0004);
+        return Some(response);
+    }
+
+    let mut result = Err(CacheError::BadSlabClass);
+    cache.for_each(|shard| {
+        let moved = shard.reassign_slab(classes[0], classes[1]);
+        if result.is_err() {
+            result = moved;
+        }
+    });
+
+    match result {
+        Ok(()) => {
+            response.header.with_status(0x0000);
+        },
+        Err(CacheError::BadSlabClass) => {
+            response.header.with_status(0x0004);
+            response.with_value(String::from("BADCLASS"));
+        },
+        Err(_) => {
+            response.header.with_status(0x0084);
+            response.with_value(String::from("NOSPARE"));
+        }
+    }
+
+    Some(response)
+}
+
+/**
+ * List keys starting with the prefix given as the key, `*` for every key, in key order.  The value
+ * holds an optional limit and the cursor returned by a previous scan.  Each key is reported as
+ * `ITEM <key> <size> <ttl> <last access>` with a ttl of -1 for keys that never expire, followed by
+ * `CURSOR <cursor>` when there may be more keys.
+ */
+pub fn scan_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, cache: &ShardedCache<T, R>) -> Option<MemPacket> {
+    println!("scan_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(request.header.opcode);
+
+    let prefix = if request.key == "*" { "" } else { request.key.as_str() };
+
+    let mut args = request.value.split_whitespace();
+    let limit = match args.next().map(|limit| limit.parse::<usize>()) {
+        Some(Ok(limit)) if limit > 0 => limit.min(SCAN_LIMIT),
+        Some(_) => {
+            response.header.with_status(0x0004);
+            return Some(response);
+        },
+        None => SCAN_DEFAULT_LIMIT,
+    };
+    let cursor = args.next();
+
+    let (items, next) = cache.scan(prefix, limit, cursor);
+
+    let mut value = String::new();
+    for item in items {
+        let ttl = match item.ttl {
+            Some(ttl) => ttl.to_string(),
+            None => String::from("-1"),
+        };
+        value.push_str(&format!("ITEM {} {} {} {}\r\n", item.key, item.size, ttl, item.last_access));
+    }
+    if let Some(next) = next {
+        value.push_str(&format!("CURSOR {}\r\n", next));
+    }
+    value.push_str("END");
+
+    response.header.with_status(0x0000);
+    response.with_value(value);
+    Some(response)
+}
+
+/**
+ * Save the contents of every namespace to the configured snapshot path
+ */
+pub fn snapshot_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, namespaces: &Namespaces<T, R>, persistence: &Persistence) -> Option<MemPacket> {
+    println!("snapshot_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(request.header.opcode);
+
+    match persistence.snapshot(namespaces) {
+        Ok(saved) => {
+            response.header.with_status(0x0000);
+            response.with_value(format!("Saved {} items", saved));
+        },
+        Err(e) => {
+            response.header.with_status(0x0084);
+            response.with_value(e);
+        }
+    }
+
+    Some(response)
+}
@@ -1,3 +1,5 @@
+use std::sync::RwLockWriteGuard;
+
 use packet::MemPacket;
 
 use cache::cache::Cache;
@@ -19,4 +21,77 @@ pub fn delete_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(reque
     cache.remove(Key::new(request.key));
     response.header.with_status(0x0000);    
     Some(response)
-}
\ No newline at end of file
+}
+
+/**
+ * Remove every entry of the namespace carrying the tag named by the key, answering with the
+ * number of entries removed.  The shards are locked by the caller.
+ */
+pub fn invalidate_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, shards: &mut [RwLockWriteGuard<Cache<T, R>>]) -> Option<MemPacket> {
+    println!("invalidate_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(request.header.opcode);
+
+    if !request.has_key() || request.has_extras() || request.has_value() {
+        response.header.with_status(0x0004);
+        return Some(response);
+    }
+
+    let removed: usize = shards.iter_mut().map(|shard| shard.invalidate_tag(&request.key)).sum();
+
+    response.header.with_status(0x0000);
+    response.with_value(removed.to_string());
+    Some(response)
+}
+
+/**
+ * Remove every entry of the namespace whose key starts with the prefix named by the key.  The
+ * shards are locked by the caller.
+ */
+pub fn flush_prefix_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, shards: &mut [RwLockWriteGuard<Cache<T, R>>]) -> Option<MemPacket> {
+    println!("flush_prefix_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(request.header.opcode);
+
+    if request.has_extras() || request.has_value() {
+        response.header.with_status(0x0004);
+        return Some(response);
+    }
+
+    // An empty prefix would flush the whole namespace, which is what FLUSH is for
+    if request.key.is_empty() {
+        response.header.with_status(0x0004);
+        response.with_value(String::from("FLUSH_PREFIX needs a prefix"));
+        return Some(response);
+    }
+
+    for shard in shards.iter_mut() {
+        shard.flush_prefix(&request.key);
+    }
+    // Counted once for the namespace rather than once per shard
+    if let Some(shard) = shards.first_mut() {
+        shard.metrics.prefix_flushes += 1;
+    }
+
+    response.header.with_status(0x0000);
+    Some(response)
+}
+
+/**
+ * Remove every entry of the namespace, the shards are locked by the caller
+ */
+pub fn flush_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, shards: &mut [RwLockWriteGuard<Cache<T, R>>]) -> Option<MemPacket> {
+    println!("flush_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(request.header.opcode);
+
+    for shard in shards.iter_mut() {
+        shard.flush();
+    }
+
+    response.header.with_status(0x0000);
+    Some(response)
+}
@@ -1,11 +1,12 @@
 use packet::MemPacket;
 
-use cache::cache::Cache;
+use cache::sharded_cache::ShardedCache;
 use cache::key::Key;
+use cache::time;
 use cache::storage_structure::CacheStorageStructure;
 use cache::replacement_policy::CacheReplacementPolicy;
 
-pub fn get_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, cache: &mut Cache<T, R>) -> Option<MemPacket> {
+pub fn get_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, cache: &ShardedCache<T, R>) -> Option<MemPacket> {
     println!("get_command");
 
     let mut response = MemPacket::new(false);
@@ -23,9 +24,24 @@ pub fn get_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request:
     response.with_key(String::from_utf8_lossy(key_bytes.as_slice()).into_owned());
     response.with_extras(String::from_utf8_lossy(extra_bytes.as_slice()).into_owned());
 
-    match cache.get(Key::new(request.key)) {
+    let key = Key::new(request.key);
+    match cache.get_stale(key.clone()) {
+        // An entry in its grace period is marked stale, and the one reader to recompute it gets a
+        // lease token in the cas to set it with
+        Some(ref value) if value.is_expired(time::now()) => {
+            match cache.shard(&key).revalidate(&key) {
+                Some(token) => {
+                    response.with_extras(String::from("stale win"));
+                    response.header.with_cas(token);
+                },
+                None => {
+                    response.with_extras(String::from("stale"));
+                }
+            }
+            response.with_value_chunks(value.value.chunks.clone());
+        },
         Some(value) => {
-            response.with_value(value.value.item.clone());
+            response.with_value_chunks(value.value.chunks.clone());
         },
         None => {
             response.header.with_status(0x0001);
@@ -0,0 +1,72 @@
+use std::str;
+
+use packet::MemPacket;
+
+use cache::cache::Cache;
+use cache::key::Key;
+use cache::value::Value;
+use cache::storage_structure::CacheStorageStructure;
+use cache::replacement_policy::CacheReplacementPolicy;
+
+use commands::set::stored;
+
+/**
+ * Add the delta in the extras, 1 without one, to a value holding a decimal number, or subtract it
+ * for the decrement opcode.  Incrementing wraps around at 2^64 and decrementing stops at 0.  The
+ * new value is returned and the entry keeps its flags and expiry.
+ */
+pub fn incr_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, cache: &mut Cache<T, R>) -> Option<MemPacket> {
+    println!("incr_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(request.header.opcode);
+
+    let delta: u64 = match request.extras.trim() {
+        "" => 1,
+        delta => match delta.parse() {
+            Ok(delta) => delta,
+            Err(_) => {
+                response.header.with_status(0x0004);
+                response.with_value(String::from("Invalid delta"));
+                return Some(response);
+            }
+        },
+    };
+
+    let mut entry = match cache.get(Key::new(request.key)) {
+        Some(entry) => entry,
+        None => {
+            response.header.with_status(0x0001);
+            response.with_value(String::from("Not found"));
+            return Some(response);
+        }
+    };
+
+    let bytes = entry.value.chunks.concat();
+    let current: u64 = match str::from_utf8(&bytes).ok().and_then(|value| value.trim().parse().ok()) {
+        Some(current) => current,
+        None => {
+            response.header.with_status(0x0006);
+            response.with_value(String::from("Non-numeric value"));
+            return Some(response);
+        }
+    };
+
+    let next = if request.header.opcode == 0x06 {
+        current.saturating_sub(delta)
+    } else {
+        current.wrapping_add(delta)
+    };
+
+    let cas = entry.value.cas;
+    entry.value = Value::new(next.to_string());
+    entry.value.cas = cas;
+    entry.value.inc_cas();
+
+    let result = cache.insert(entry);
+    stored(result, &mut response);
+    if response.header.status == 0x0000 {
+        response.with_value(next.to_string());
+    }
+    Some(response)
+}
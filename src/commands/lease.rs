@@ -0,0 +1,119 @@
+use packet::MemPacket;
+
+use cache::cache::Cache;
+use cache::key::Key;
+use cache::value::Value;
+use cache::data_entry::DataEntry;
+use cache::lease::LeaseRead;
+use cache::storage_structure::CacheStorageStructure;
+use cache::replacement_policy::CacheReplacementPolicy;
+
+use commands::set::{stored, parse_tags};
+
+/**
+ * Get a value, or a lease to fill it on a miss.  A granted lease is a miss with the token in the
+ * cas, a miss without a token means another client holds the lease and the key should be read
+ * again shortly.  While the key is filled the value it had before it was deleted may be returned
+ * instead, marked as stale in the extras.
+ */
+pub fn lease_get_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, cache: &mut Cache<T, R>) -> Option<MemPacket> {
+    println!("lease_get_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(request.header.opcode);
+
+    if request.has_extras() || request.has_value() {
+        response.header.with_status(0x0004);
+        return Some(response);
+    }
+
+    response.with_key(request.key.clone());
+
+    match cache.lease_get(Key::new(request.key)) {
+        LeaseRead::Hit(entry) => {
+            response.with_value_chunks(entry.value.chunks.clone());
+        },
+        LeaseRead::Stale(entry) => {
+            response.with_extras(String::from("stale"));
+            response.with_value_chunks(entry.value.chunks.clone());
+        },
+        LeaseRead::Granted(token) => {
+            response.header.with_status(0x0001);
+            response.header.with_cas(token);
+            response.with_value(String::from("Not found"));
+        },
+        LeaseRead::Wait => {
+            response.header.with_status(0x0001);
+            response.with_value(String::from("Not found, lease held"));
+        }
+    }
+
+    Some(response)
+}
+
+/**
+ * Set a value with the lease token handed out when it was missed, carried in the extras together
+ * with an optional ttl and tags.  The set is refused with 0x0002 unless the lease is still valid.
+ */
+pub fn lease_set_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, cache: &mut Cache<T, R>) -> Option<MemPacket> {
+    println!("lease_set_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(request.header.opcode);
+
+    let mut extras = request.extras.split_whitespace();
+    let token = match extras.next().and_then(|token| token.parse().ok()) {
+        Some(token) => token,
+        None => {
+            response.header.with_status(0x0004);
+            return Some(response);
+        }
+    };
+    let ttl = match extras.next() {
+        Some(ttl) => match ttl.parse() {
+            Ok(ttl) => ttl,
+            Err(_) => {
+                response.header.with_status(0x0004);
+                return Some(response);
+            }
+        },
+        None => cache.item_lifetime,
+    };
+    let tags = extras.next().map(parse_tags).unwrap_or_default();
+    if extras.next().is_some() {
+        response.header.with_status(0x0004);
+        return Some(response);
+    }
+
+    let entry = DataEntry::new(Key::new(request.key), Value::new(request.value)).expires_in(ttl).with_tags(tags);
+    let result = cache.insert_leased(entry, token);
+    stored(result, &mut response);
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::cache::tests::{cache, key};
+    use commands::set::tests::request;
+
+    #[test]
+    fn a_lease_set_carries_the_token_a_ttl_and_tags() {
+        let mut cache = cache(1024 * 1024);
+        let token = match cache.lease_get(key("k")) {
+            LeaseRead::Granted(token) => token,
+            _ => panic!("the first miss should be granted a lease"),
+        };
+
+        // Anything after the tags is refused and leaves the lease to be redeemed
+        let response = lease_set_command(request("k", &format!("{} 60 red extra", token)), &mut cache).unwrap();
+        assert_eq!(response.header.status, 0x0004);
+        assert!(!cache.contains(key("k")));
+
+        let response = lease_set_command(request("k", &format!("{} 60 red", token)), &mut cache).unwrap();
+        assert_eq!(response.header.status, 0x0000);
+        let (_, entry) = cache.storage_structure.get(key("k")).unwrap();
+        assert_eq!(entry.tags, vec![String::from("red")]);
+        assert!(entry.expires_at > 0);
+    }
+}
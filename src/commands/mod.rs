@@ -1,3 +1,9 @@
 pub mod get;
 pub mod set;
-pub mod delete;
\ No newline at end of file
+pub mod delete;
+pub mod incr;
+pub mod lease;
+pub mod admin;
+pub mod stat;
+pub mod namespace;
+pub mod watch;
\ No newline at end of file
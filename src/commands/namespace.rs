@@ -0,0 +1,106 @@
+use packet::MemPacket;
+
+use cache::cache::Cache;
+use cache::namespace::Namespaces;
+use persistence::Persistence;
+use persistence::oplog::Op;
+use cache::storage_structure::CacheStorageStructure;
+use cache::replacement_policy::CacheReplacementPolicy;
+
+use commands::stat;
+
+/**
+ * Select the namespace named by the key for the rest of the connection
+ */
+pub fn use_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, namespaces: &Namespaces<T, R>, namespace: &mut String) -> Option<MemPacket> {
+    println!("use_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(request.header.opcode);
+
+    match namespaces.get(&request.key) {
+        Some(_) => {
+            *namespace = request.key.clone();
+            response.header.with_status(0x0000);
+            response.with_value(request.key);
+        },
+        None => {
+            response.header.with_status(0x0001);
+            response.with_value(String::from("Unknown namespace"));
+        }
+    }
+
+    Some(response)
+}
+
+/**
+ * Namespace administration, the key is the subcommand and the value its arguments:
+ *
+ * `create <name> <capacity> [policy] [ttl]` adds a namespace, with the default policy and no
+ * default ttl unless given, `resize <name> <capacity>` changes its capacity, `drop <name>` removes
+ * it and `list` reports the statistics of every namespace.
+ */
+pub fn namespace_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, namespaces: &Namespaces<T, R>, persistence: &Persistence) -> Option<MemPacket> {
+    println!("namespace_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(request.header.opcode);
+
+    let args: Vec<&str> = request.value.split_whitespace().collect();
+    let result = match (request.key.as_str(), args.len()) {
+        ("create", 2) | ("create", 3) | ("create", 4) => {
+            let capacity = args[1].parse();
+            let ttl = args.get(3).map_or(Ok(0), |ttl| ttl.parse());
+            match (capacity, ttl) {
+                (Ok(capacity), Ok(ttl)) => {
+                    let policy = args.get(2).cloned().unwrap_or("");
+                    namespaces.create(args[0], capacity, policy, ttl)
+                        .map(|_| persistence.log(Op::CreateNamespace(args[0], capacity, policy, ttl)))
+                },
+                _ => Err(String::from("Invalid capacity or ttl")),
+            }
+        },
+        ("resize", 2) => match args[1].parse() {
+            Ok(capacity) => namespaces.resize(args[0], capacity)
+                .map(|_| persistence.log(Op::ResizeNamespace(args[0], capacity))),
+            Err(_) => Err(String::from("Invalid capacity")),
+        },
+        ("drop", 1) => namespaces.drop_namespace(args[0])
+            .map(|_| persistence.log(Op::DropNamespace(args[0]))),
+        ("list", 0) => {
+            response.with_value(list(namespaces));
+            Ok(())
+        },
+        _ => Err(String::from("Usage: NAMESPACE create|resize|drop|list")),
+    };
+
+    match result {
+        Ok(()) => {
+            response.header.with_status(0x0000);
+        },
+        Err(e) => {
+            response.header.with_status(0x0004);
+            response.with_value(e);
+        }
+    }
+
+    Some(response)
+}
+
+fn list<T: CacheStorageStructure, R: CacheReplacementPolicy>(namespaces: &Namespaces<T, R>) -> String {
+    let mut value = String::new();
+
+    for (name, cache) in namespaces.list() {
+        let shards = cache.lock_all();
+        let shards: Vec<&Cache<T, R>> = shards.iter().map(|shard| &**shard).collect();
+
+        let ttl = shards.first().map_or(0, |shard| shard.item_lifetime);
+        value.push_str(&format!("STAT {}:default_ttl {}\r\n", name, ttl));
+        for (stat, count) in stat::general_stats(&shards) {
+            value.push_str(&format!("STAT {}:{} {}\r\n", name, stat, count));
+        }
+    }
+
+    value.push_str("END");
+    value
+}
@@ -3,22 +3,74 @@ use packet::MemPacket;
 use cache::cache::Cache;
 use cache::key::Key;
 use cache::value::Value;
+use cache::data_entry::DataEntry;
 use cache::storage_structure::CacheStorageStructure;
 use cache::replacement_policy::CacheReplacementPolicy;
+use cache::error::CacheError;
 
 fn set<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, cache: &mut Cache<T, R>, response: &mut MemPacket) {
     // TODO: If the Data Version Check (CAS) is nonzero, the requested operation MUST only succeed if the item exists and has a CAS value identical to the provided value.
     
-    match cache.set(Key::new(request.key), Value::new(request.value)) {
-        Ok(val) => {
+    // The expiration in seconds is carried in the extras, 0 never expires and without one the
+    // default ttl of the cache applies.  It may be followed by the tags of the entry, tags
+    // without a ttl or anything after the tags are refused rather than silently dropped.
+    let mut extras = request.extras.split_whitespace();
+    let ttl = match extras.next() {
+        Some(ttl) => match ttl.parse() {
+            Ok(ttl) => ttl,
+            Err(_) => {
+                response.header.with_status(0x0004);
+                return;
+            }
+        },
+        None => cache.item_lifetime,
+    };
+    let tags = extras.next().map(parse_tags).unwrap_or_default();
+    if extras.next().is_some() {
+        response.header.with_status(0x0004);
+        return;
+    }
+
+    let entry = DataEntry::new(Key::new(request.key), Value::new(request.value)).expires_in(ttl).with_tags(tags);
+    let result = cache.insert(entry);
+    stored(result, response);
+}
+
+/**
+ * Tags are given as a comma separated list
+ */
+pub fn parse_tags(tags: &str) -> Vec<String> {
+    tags.split(',').filter(|tag| !tag.is_empty()).map(String::from).collect()
+}
+
+/**
+ * Report the outcome of storing an entry
+ */
+pub fn stored(result: Result<(), CacheError>, response: &mut MemPacket) {
+    match result {
+        Ok(()) => {
             response.header.with_status(0x0000);
             response.header.with_cas(0x0000000000000001);
         },
+        Err(CacheError::AdmissionRejected) => {
+            response.header.with_status(0x0005);
+        },
+        Err(CacheError::KeyTooLarge) => {
+            response.header.with_status(0x0004);
+        },
+        Err(CacheError::ValueTooLarge) => {
+            response.header.with_status(0x0003);
+        },
+        Err(CacheError::LeaseInvalid) => {
+            response.header.with_status(0x0002);
+        },
+        Err(CacheError::OutOfMemory) => {
+            response.header.with_status(0x0082);
+        },
         Err(_) => {
             response.header.with_status(0x0084);
         }
     }
-    
 }
 
 pub fn set_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, cache: &mut Cache<T, R>) -> Option<MemPacket> {
@@ -77,4 +129,71 @@ pub fn replace_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(requ
 
     set(request, cache, &mut response);
     Some(response)
-}
\ No newline at end of file
+}
+
+/**
+ * Append the value of the request to an existing entry, or prepend it for the prepend opcode.  The
+ * entry keeps its flags and expiry.
+ */
+pub fn append_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, cache: &mut Cache<T, R>) -> Option<MemPacket> {
+    println!("append_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(request.header.opcode);
+
+    let mut entry = match cache.get(Key::new(request.key)) {
+        Some(entry) => entry,
+        None => {
+            response.header.with_status(0x0005);
+            return Some(response);
+        }
+    };
+
+    let mut bytes = Vec::with_capacity(entry.value.len() + request.value.len());
+    if request.header.opcode == 0x0f {
+        bytes.extend_from_slice(request.value.as_bytes());
+    }
+    for chunk in entry.value.chunks.iter() {
+        bytes.extend_from_slice(chunk);
+    }
+    if request.header.opcode != 0x0f {
+        bytes.extend_from_slice(request.value.as_bytes());
+    }
+
+    let cas = entry.value.cas;
+    entry.value = Value::from_bytes(bytes);
+    entry.value.cas = cas;
+    entry.value.inc_cas();
+
+    let result = cache.insert(entry);
+    stored(result, &mut response);
+    Some(response)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use cache::cache::tests::{cache, key};
+
+    pub fn request(key: &str, extras: &str) -> MemPacket {
+        let mut request = MemPacket::new(true);
+        request.with_key(String::from(key)).with_extras(String::from(extras)).with_value(String::from("v"));
+        request
+    }
+
+    #[test]
+    fn extras_carry_a_ttl_and_tags() {
+        let mut cache = cache(1024 * 1024);
+
+        let response = set_command(request("k", "60 red,,blue"), &mut cache).unwrap();
+        assert_eq!(response.header.status, 0x0000);
+        let (_, entry) = cache.storage_structure.get(key("k")).unwrap();
+        assert_eq!(entry.tags, vec![String::from("red"), String::from("blue")]);
+        assert!(entry.expires_at > 0);
+
+        // A malformed ttl or anything after the tags is refused instead of setting the key
+        assert_eq!(set_command(request("bad", "red,blue"), &mut cache).unwrap().header.status, 0x0004);
+        assert_eq!(set_command(request("bad", "60 red extra"), &mut cache).unwrap().header.status, 0x0004);
+        assert!(!cache.contains(key("bad")));
+    }
+}
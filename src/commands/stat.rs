@@ -0,0 +1,103 @@
+use std::sync::atomic::Ordering;
+
+use packet::MemPacket;
+
+use cache::cache::{Cache, CacheMetrics};
+use cache::sharded_cache::ShardedCache;
+use cache::slab::{self, SlabAllocator};
+use cache::storage_structure::CacheStorageStructure;
+use cache::replacement_policy::CacheReplacementPolicy;
+
+/**
+ * Report statistics for the group named by the key, one `STAT <name> <value>` line per statistic.
+ * Statistics are added up over all shards.
+ */
+pub fn stat_command<T: CacheStorageStructure, R: CacheReplacementPolicy>(request: MemPacket, cache: &ShardedCache<T, R>) -> Option<MemPacket> {
+    println!("stat_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(request.header.opcode);
+
+    if request.has_extras() || request.has_value() {
+        response.header.with_status(0x0004);
+        return Some(response);
+    }
+
+    let shards = cache.lock_all();
+    let shards: Vec<&Cache<T, R>> = shards.iter().map(|shard| &**shard).collect();
+
+    let stats = match request.key.as_str() {
+        "" => general_stats(&shards),
+        "slabs" => {
+            let allocators: Vec<&SlabAllocator> = shards.iter().filter_map(|shard| shard.slabs.as_ref()).collect();
+            slab::combined_stats(&allocators)
+        },
+        "extstore" => match shards.first().and_then(|shard| shard.ext.as_ref()) {
+            Some(ext) => ext.stats(),
+            None => Vec::new(),
+        },
+        _ => {
+            response.header.with_status(0x0001);
+            return Some(response);
+        }
+    };
+
+    let mut value = String::new();
+    for (name, stat) in stats {
+        value.push_str(&format!("STAT {} {}\r\n", name, stat));
+    }
+    value.push_str("END");
+
+    response.with_key(request.key);
+    response.with_value(value);
+    Some(response)
+}
+
+pub fn general_stats<T: CacheStorageStructure, R: CacheReplacementPolicy>(shards: &[&Cache<T, R>]) -> Vec<(String, String)> {
+    let mut metrics = CacheMetrics::new();
+    let (mut bytes, mut payload_bytes, mut limit_maxbytes, mut curr_items) = (0, 0, 0, 0);
+    let mut reads_dropped = 0;
+
+    for shard in shards {
+        metrics.merge(&shard.metrics);
+        bytes += shard.memory_usage();
+        payload_bytes += shard.storage_structure.size();
+        limit_maxbytes += shard.capacity;
+        curr_items += shard.storage_structure.indices().len();
+        reads_dropped += shard.reads.dropped.load(Ordering::Relaxed);
+    }
+
+    let mut stats = vec![
+        (String::from("shards"), shards.len().to_string()),
+        (String::from("bytes"), bytes.to_string()),
+        (String::from("payload_bytes"), payload_bytes.to_string()),
+        (String::from("limit_maxbytes"), limit_maxbytes.to_string()),
+        (String::from("curr_items"), curr_items.to_string()),
+        (String::from("get_hits"), metrics.hit_count_get.to_string()),
+        (String::from("get_misses"), metrics.miss_count_get.to_string()),
+        (String::from("set_hits"), metrics.hit_count_set.to_string()),
+        (String::from("set_misses"), metrics.miss_count_set.to_string()),
+        (String::from("delete_hits"), metrics.hit_count_delete.to_string()),
+        (String::from("delete_misses"), metrics.miss_count_delete.to_string()),
+        (String::from("evictions"), metrics.evictions.to_string()),
+        (String::from("rejections"), metrics.rejections.to_string()),
+        (String::from("reads_dropped"), reads_dropped.to_string()),
+        (String::from("leases_granted"), metrics.leases_granted.to_string()),
+        (String::from("lease_waits"), metrics.lease_waits.to_string()),
+        (String::from("lease_stale_hits"), metrics.lease_stale_hits.to_string()),
+        (String::from("lease_rejections"), metrics.lease_rejections.to_string()),
+        (String::from("stale_hits"), metrics.stale_hits.to_string()),
+        (String::from("revalidations"), metrics.revalidations.to_string()),
+        (String::from("tag_invalidations"), metrics.tag_invalidations.to_string()),
+        (String::from("prefix_flushes"), metrics.prefix_flushes.to_string()),
+        (String::from("prefix_reclaimed"), metrics.prefix_reclaimed.to_string()),
+    ];
+
+    if let Some(compressor) = shards.first().and_then(|shard| shard.compressor.as_ref()) {
+        stats.extend(compressor.stats());
+    }
+    if let Some(shard) = shards.first() {
+        stats.extend(shard.watchers.stats());
+    }
+    stats
+}
@@ -0,0 +1,59 @@
+use std::io::Write;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use packet::MemPacket;
+
+use cache::watch::Subscription;
+
+/**
+ * Send the events of a subscription to the connection until it goes away, either because a write
+ * fails or because the subscriber was closed when the connection hung up.  Each event is a packet
+ * with the key and the kind of event in the extras.  Events dropped because the connection fell
+ * behind are reported as a `dropped` packet with the number of events lost.
+ */
+pub fn stream<W: Write>(subscription: &Subscription, out: &mut W) {
+    println!("watch_command");
+
+    let mut response = MemPacket::new(false);
+    response.header.with_opcode(0xea);
+    response.with_value(String::from("Watching"));
+    if send(&response, out).is_err() {
+        return;
+    }
+
+    loop {
+        let events = subscription.subscriber.wait(Duration::from_secs(1));
+        if subscription.subscriber.is_closed() {
+            return;
+        }
+
+        let dropped = subscription.subscriber.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            let mut response = MemPacket::new(false);
+            response.header.with_opcode(0xea);
+            response.with_extras(String::from("dropped"));
+            response.with_value(dropped.to_string());
+            if send(&response, out).is_err() {
+                return;
+            }
+        }
+
+        for event in events {
+            let mut response = MemPacket::new(false);
+            response.header.with_opcode(0xea);
+            response.with_key(event.key);
+            response.with_extras(String::from(event.kind.name()));
+            response.with_value(String::new());
+            if send(&response, out).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn send<W: Write>(response: &MemPacket, out: &mut W) -> Result<(), ()> {
+    response.write_to(out).map_err(|_| ())?;
+    out.write_all(b"\r\n").map_err(|_| ())?;
+    out.flush().map_err(|_| ())
+}
@@ -0,0 +1,433 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::thread;
+
+use persistence::oplog::FsyncPolicy;
+use cache::compression::Algorithm;
+use cache::replacement_policy::PolicyOptions;
+
+/**
+ * Server configuration.  Options are read from the command line in order, `--config <path>` loads
+ * a file of `name = value` lines at that point so later flags override values from the file.
+ *
+ * Setting a memory limit in megabytes (`-m`) switches the cache to slab allocation with classes
+ * growing by `-f` from a minimum chunk size of `-n` bytes.  Pages are rebalanced between classes
+ * in the background unless `--slab-automove 0` is given.
+ *
+ * The largest value that can be stored is set with `-I`, in bytes or with a `k`, `m` or `g` suffix, up
+ * to 1GB.
+ *
+ * LFU, the default policy, halves its access counts every `--lfu-decay` accesses, 10000 by
+ * default or 0 to never age them.  With `--lfu-log-factor <factor>` it keeps logarithmic counts
+ * like Redis instead of counting every access, Redis uses a factor of 10.
+ *
+ * CLOCK remembers up to `--clock-references` accesses per entry, 1 by default, and gives an entry
+ * that many passes of the hand before evicting it.
+ *
+ * The cache is split into `--shards` independent shards, one per core by default, which divide
+ * the capacity or memory limit between them.  `--bench <threads>` measures get throughput with up
+ * to that many threads instead of serving requests.
+ *
+ * With `--snapshot <path>` the contents of the cache are saved to the path on shutdown and on the
+ * `SNAPSHOT` command, and `--warm-restart 1` loads them back at startup.
+ *
+ * With `--oplog <path>` every change is also logged to the path and replayed on top of the
+ * snapshot at startup, so the snapshot path is required.  `--oplog-fsync` forces the log to disk
+ * `always`, `everysec` or `never`, and once the log grows past `--oplog-compact` bytes, 64MB by
+ * default, a snapshot is taken in the background and the log starts over.
+ *
+ * `--ext-path <dir>` adds a disk tier in the directory: values of at least `--ext-item-size` bytes,
+ * 512 by default, move there when they are evicted from memory.  The tier is made of pages of
+ * `--ext-page-size` bytes, 64MB by default, and holds up to `--ext-size` bytes, 1GB by default.
+ * It is not available together with slab allocation.
+ *
+ * `--compression lz4|zstd` compresses values of at least `--compression-threshold` bytes, 1KB by
+ * default, as they are stored.
+ *
+ * A lease handed out on a miss by `GETL` lasts `--lease-time` seconds, 10 by default.  With
+ * `--stale-grace <seconds>` expired values are still served for that long, marked as stale, while
+ * a single client recomputes them.
+ *
+ * `FLUSH_PREFIX` only marks the keys under the prefix as flushed.  With `--prefix-reclaim <seconds>`
+ * the flushed entries are removed in the background at that interval rather than left in memory
+ * until they are evicted.
+ *
+ * `WATCH [prefix] [kinds]` streams the sets, deletes, evictions and expirations of a namespace to
+ * the connection.  Each watching connection buffers up to `--watch-buffer` events, 1024 by
+ * default, beyond which events are dropped and counted.
+ */
+#[derive(Clone)]
+pub struct Config {
+    pub address: String,
+    pub capacity: usize,
+    pub storage: String,
+    pub policy: Option<String>,
+    pub lfu_decay: usize,
+    pub lfu_log_factor: u64,
+    pub clock_references: u8,
+    pub memory_limit: Option<usize>,
+    pub growth_factor: f64,
+    pub min_chunk: usize,
+    pub slab_automove: bool,
+    pub max_item_size: usize,
+    pub shards: usize,
+    pub bench_threads: Option<usize>,
+    pub snapshot: Option<String>,
+    pub warm_restart: bool,
+    pub oplog: Option<String>,
+    pub oplog_fsync: FsyncPolicy,
+    pub oplog_compact: usize,
+    pub ext_path: Option<String>,
+    pub ext_item_size: usize,
+    pub ext_page_size: usize,
+    pub ext_size: usize,
+    pub compression: Option<Algorithm>,
+    pub compression_threshold: usize,
+    pub lease_time: u64,
+    pub stale_grace: u64,
+    pub prefix_reclaim: u64,
+    pub watch_buffer: usize,
+}
+
+pub const MAX_ITEM_SIZE_LIMIT: usize = 1024 * 1024 * 1024;
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            address: String::from("127.0.0.1:4321"),
+            capacity: 64 * 1024 * 1024,
+            storage: String::from("naive"),
+            policy: None,
+            lfu_decay: 10000,
+            lfu_log_factor: 0,
+            clock_references: 1,
+            memory_limit: None,
+            growth_factor: 1.25,
+            min_chunk: 48,
+            slab_automove: true,
+            max_item_size: 1024 * 1024,
+            shards: thread::available_parallelism().map(|cores| cores.get()).unwrap_or(1),
+            bench_threads: None,
+            snapshot: None,
+            warm_restart: false,
+            oplog: None,
+            oplog_fsync: FsyncPolicy::EverySecond,
+            oplog_compact: 64 * 1024 * 1024,
+            ext_path: None,
+            ext_item_size: 512,
+            ext_page_size: 64 * 1024 * 1024,
+            ext_size: 1024 * 1024 * 1024,
+            compression: None,
+            compression_threshold: 1024,
+            lease_time: 10,
+            stale_grace: 0,
+            prefix_reclaim: 0,
+            watch_buffer: 1024,
+        }
+    }
+
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Config, String> {
+        let mut config = Config::new();
+
+        while let Some(flag) = args.next() {
+            let value = match args.next() {
+                Some(value) => value,
+                None => return Err(format!("Missing value for {}", flag)),
+            };
+
+            match flag.as_str() {
+                "--config" => config.load(&value)?,
+                "--listen" => config.apply("listen", &value)?,
+                "--capacity" => config.apply("capacity", &value)?,
+                "--storage" => config.apply("storage", &value)?,
+                "--policy" => config.apply("policy", &value)?,
+                "--lfu-decay" => config.apply("lfu_decay", &value)?,
+                "--lfu-log-factor" => config.apply("lfu_log_factor", &value)?,
+                "--clock-references" => config.apply("clock_references", &value)?,
+                "-m" => config.apply("memory_limit", &value)?,
+                "-f" => config.apply("growth_factor", &value)?,
+                "-n" => config.apply("min_chunk", &value)?,
+                "--slab-automove" => config.apply("slab_automove", &value)?,
+                "-I" => config.apply("max_item_size", &value)?,
+                "--shards" => config.apply("shards", &value)?,
+                "--bench" => config.apply("bench_threads", &value)?,
+                "--snapshot" => config.apply("snapshot", &value)?,
+                "--warm-restart" => config.apply("warm_restart", &value)?,
+                "--oplog" => config.apply("oplog", &value)?,
+                "--oplog-fsync" => config.apply("oplog_fsync", &value)?,
+                "--oplog-compact" => config.apply("oplog_compact", &value)?,
+                "--ext-path" => config.apply("ext_path", &value)?,
+                "--ext-item-size" => config.apply("ext_item_size", &value)?,
+                "--ext-page-size" => config.apply("ext_page_size", &value)?,
+                "--ext-size" => config.apply("ext_size", &value)?,
+                "--compression" => config.apply("compression", &value)?,
+                "--compression-threshold" => config.apply("compression_threshold", &value)?,
+                "--lease-time" => config.apply("lease_time", &value)?,
+                "--stale-grace" => config.apply("stale_grace", &value)?,
+                "--prefix-reclaim" => config.apply("prefix_reclaim", &value)?,
+                "--watch-buffer" => config.apply("watch_buffer", &value)?,
+                _ => return Err(format!("Unknown option {}", flag)),
+            }
+        }
+
+        if config.oplog.is_some() && config.snapshot.is_none() {
+            return Err(String::from("The op log needs a snapshot path to be compacted into"));
+        }
+
+        if config.ext_path.is_some() && config.memory_limit.is_some() {
+            return Err(String::from("The disk tier is not available together with slab allocation"));
+        }
+
+        Ok(config)
+    }
+
+    /**
+     * The replacement policy to use, without one set the cuckoo storage structure is paired with
+     * CLOCK which like the table itself needs no more than a few bits per entry, and everything
+     * else with LFU
+     */
+    pub fn policy(&self) -> &str {
+        match self.policy {
+            Some(ref policy) => policy,
+            None if self.storage == "cuckoo" => "clock",
+            None => "lfu",
+        }
+    }
+
+    pub fn policy_options(&self) -> PolicyOptions {
+        PolicyOptions {
+            lfu_decay: self.lfu_decay,
+            lfu_log_factor: self.lfu_log_factor,
+            clock_references: self.clock_references,
+        }
+    }
+
+    fn load(&mut self, path: &str) -> Result<(), String> {
+        let mut contents = String::new();
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+            Ok(_) => {},
+            Err(e) => return Err(format!("Unable to read {}: {}", path, e)),
+        }
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => self.apply(name.trim(), value.trim())?,
+                _ => return Err(format!("Invalid line in {}: {}", path, line)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "listen" => self.address = String::from(value),
+            "capacity" => {
+                self.capacity = match value.parse() {
+                    Ok(capacity) => capacity,
+                    Err(_) => return Err(format!("Invalid capacity {}", value)),
+                }
+            },
+            "storage" => self.storage = String::from(value),
+            "policy" => self.policy = Some(String::from(value)),
+            "lfu_decay" => {
+                self.lfu_decay = match value.parse() {
+                    Ok(accesses) => accesses,
+                    Err(_) => return Err(format!("Invalid LFU decay period {}", value)),
+                }
+            },
+            "lfu_log_factor" => {
+                self.lfu_log_factor = match value.parse() {
+                    Ok(factor) => factor,
+                    Err(_) => return Err(format!("Invalid LFU log factor {}", value)),
+                }
+            },
+            "clock_references" => {
+                self.clock_references = match value.parse() {
+                    Ok(references) if references > 0 => references,
+                    _ => return Err(format!("Invalid CLOCK reference limit {}", value)),
+                }
+            },
+            "memory_limit" => {
+                self.memory_limit = match value.parse() {
+                    Ok(megabytes) => Some(megabytes),
+                    Err(_) => return Err(format!("Invalid memory limit {}", value)),
+                }
+            },
+            "growth_factor" => {
+                self.growth_factor = match value.parse() {
+                    Ok(factor) if factor > 1.0 => factor,
+                    _ => return Err(format!("Invalid growth factor {}", value)),
+                }
+            },
+            "slab_automove" => {
+                self.slab_automove = match value {
+                    "1" | "true" => true,
+                    "0" | "false" => false,
+                    _ => return Err(format!("Invalid slab automove setting {}", value)),
+                }
+            },
+            "min_chunk" => {
+                self.min_chunk = match value.parse() {
+                    Ok(size) => size,
+                    Err(_) => return Err(format!("Invalid minimum chunk size {}", value)),
+                }
+            },
+            "max_item_size" => {
+                self.max_item_size = match parse_size(value) {
+                    Some(size) if size > 0 && size <= MAX_ITEM_SIZE_LIMIT => size,
+                    _ => return Err(format!("Invalid max item size {}", value)),
+                }
+            },
+            "shards" => {
+                self.shards = match value.parse() {
+                    Ok(shards) if shards > 0 => shards,
+                    _ => return Err(format!("Invalid shard count {}", value)),
+                }
+            },
+            "snapshot" => self.snapshot = Some(String::from(value)),
+            "warm_restart" => {
+                self.warm_restart = match value {
+                    "1" | "true" => true,
+                    "0" | "false" => false,
+                    _ => return Err(format!("Invalid warm restart setting {}", value)),
+                }
+            },
+            "oplog" => self.oplog = Some(String::from(value)),
+            "oplog_fsync" => {
+                self.oplog_fsync = match FsyncPolicy::from_name(value) {
+                    Some(fsync) => fsync,
+                    None => return Err(format!("Invalid op log fsync policy {}", value)),
+                }
+            },
+            "oplog_compact" => {
+                self.oplog_compact = match parse_size(value) {
+                    Some(size) if size > 0 => size,
+                    _ => return Err(format!("Invalid op log compaction size {}", value)),
+                }
+            },
+            "ext_path" => self.ext_path = Some(String::from(value)),
+            "ext_item_size" => {
+                self.ext_item_size = match parse_size(value) {
+                    Some(size) => size,
+                    None => return Err(format!("Invalid disk tier item size {}", value)),
+                }
+            },
+            "ext_page_size" => {
+                self.ext_page_size = match parse_size(value) {
+                    Some(size) if size > 0 => size,
+                    _ => return Err(format!("Invalid disk tier page size {}", value)),
+                }
+            },
+            "ext_size" => {
+                self.ext_size = match parse_size(value) {
+                    Some(size) if size > 0 => size,
+                    _ => return Err(format!("Invalid disk tier size {}", value)),
+                }
+            },
+            "compression" => {
+                self.compression = match value {
+                    "none" => None,
+                    _ => match Algorithm::from_name(value) {
+                        Some(algorithm) => Some(algorithm),
+                        None => return Err(format!("Unknown compression {}", value)),
+                    },
+                }
+            },
+            "compression_threshold" => {
+                self.compression_threshold = match parse_size(value) {
+                    Some(size) => size,
+                    None => return Err(format!("Invalid compression threshold {}", value)),
+                }
+            },
+            "lease_time" => {
+                self.lease_time = match value.parse() {
+                    Ok(seconds) if seconds > 0 => seconds,
+                    _ => return Err(format!("Invalid lease time {}", value)),
+                }
+            },
+            "stale_grace" => {
+                self.stale_grace = match value.parse() {
+                    Ok(seconds) => seconds,
+                    Err(_) => return Err(format!("Invalid stale grace {}", value)),
+                }
+            },
+            "prefix_reclaim" => {
+                self.prefix_reclaim = match value.parse() {
+                    Ok(seconds) => seconds,
+                    Err(_) => return Err(format!("Invalid prefix reclaim interval {}", value)),
+                }
+            },
+            "watch_buffer" => {
+                self.watch_buffer = match value.parse() {
+                    Ok(events) if events > 0 => events,
+                    _ => return Err(format!("Invalid watch buffer {}", value)),
+                }
+            },
+            "bench_threads" => {
+                self.bench_threads = match value.parse() {
+                    Ok(threads) if threads > 0 => Some(threads),
+                    _ => return Err(format!("Invalid benchmark thread count {}", value)),
+                }
+            },
+            _ => return Err(format!("Unknown setting {}", name)),
+        }
+
+        Ok(())
+    }
+}
+
+/**
+ * Parse a size in bytes with an optional `k`, `m` or `g` suffix, None if it does not fit
+ */
+fn parse_size(value: &str) -> Option<usize> {
+    let (digits, multiplier) = match value.chars().last() {
+        Some('k') | Some('K') => (&value[..value.len() - 1], 1024),
+        Some('m') | Some('M') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('g') | Some('G') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    digits.parse::<usize>().ok().and_then(|size| size.checked_mul(multiplier))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Config, String> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn sizes_take_a_suffix() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("4k"), Some(4 * 1024));
+        assert_eq!(parse_size("2M"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_size("1g"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size("m"), None);
+        assert_eq!(parse_size("1x"), None);
+        assert_eq!(parse_size(&format!("{}g", usize::MAX)), None);
+    }
+
+    #[test]
+    fn max_item_size_is_bounded() {
+        assert_eq!(args(&["-I", "4m"]).unwrap().max_item_size, 4 * 1024 * 1024);
+        assert!(args(&["-I", "0"]).is_err());
+        assert!(args(&["-I", &(MAX_ITEM_SIZE_LIMIT + 1).to_string()]).is_err());
+    }
+
+    #[test]
+    fn clock_references_reach_the_policy_options() {
+        assert_eq!(args(&[]).unwrap().policy_options().clock_references, 1);
+        assert_eq!(args(&["--clock-references", "3"]).unwrap().policy_options().clock_references, 3);
+        assert!(args(&["--clock-references", "0"]).is_err());
+        assert!(args(&["--clock-references", "256"]).is_err());
+    }
+}
@@ -1,59 +1,341 @@
+use std::env;
+use std::process;
 use std::str;
+use std::thread;
+use std::io::BufReader;
 use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
 
 extern crate linked_hash_map;
+extern crate lz4_flex;
+extern crate zstd;
 
 use packet::MemPacket;
 mod packet;
 
 use cache::cache::Cache;
-use cache::storage_structure::{CacheStorageStructure, NaiveStorageStructure};
-use cache::replacement_policy::{CacheReplacementPolicy, LRU, Clock, LFU};
+use cache::sharded_cache::ShardedCache;
+use cache::namespace::{self, Namespaces};
+use cache::storage_structure::{self, CacheStorageStructure};
+use cache::replacement_policy::{self, CacheReplacementPolicy};
+use cache::slab::SlabAllocator;
+use cache::extstore::ExtStore;
+use cache::compression::Compressor;
+use cache::watch::Watchers;
 mod cache;
 
+use config::Config;
+mod config;
+
 mod command;
 mod commands;
+mod bench;
+
+use persistence::Persistence;
+use persistence::oplog::{self, FsyncPolicy, OpLog};
+mod persistence;
+
+mod shutdown;
+
+type Storage = Box<dyn CacheStorageStructure + Send + Sync>;
+type Policy = Box<dyn CacheReplacementPolicy + Send + Sync>;
 
-fn handle_client(mut stream: TcpStream) {
-    let mut cache: Cache<_, _> = Cache::new(360, NaiveStorageStructure::new(), LFU::new());
+/**
+ * Parts of the caches that every namespace uses together
+ */
+#[derive(Clone)]
+struct Shared {
+    ext: Option<Arc<ExtStore>>,
+    compressor: Option<Arc<Compressor>>,
+}
+
+fn handle_client<T: CacheStorageStructure>(mut stream: TcpStream, namespaces: &Namespaces<T, Policy>, persistence: &Persistence) {
+    // Commands are read a line at a time so a value can be larger than a single read
+    let mut reader = match stream.try_clone() {
+        Ok(stream) => BufReader::new(stream),
+        Err(_) => return,
+    };
+
+    let mut namespace = String::from(namespace::DEFAULT_NAMESPACE);
 
     loop {
-        let mut buffer = [0; 128];
-        let len = stream.read(&mut buffer).unwrap();
+        let mut buffer = Vec::new();
+        match reader.read_until(b'\n', &mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {},
+        };
 
-        let string = match str::from_utf8(&buffer[0..len]) {
+        let string = match str::from_utf8(&buffer) {
             Ok(s) => s,
             Err(e) => panic!("Invalid UTF-8 sequence: {}", e)
         };
         println!("{}", string);
 
-        match command::parse_command(string, &mut cache) {
-            Some(response) => {
-                println!("{:?}", response.header);
-                println!("{:?}", response);
+        // A watching connection only receives events from then on
+        if let Some(watch) = command::parse_watch(string, namespaces, &namespace) {
+            match watch {
+                Ok(subscription) => {
+                    // Nothing more is read from a watching connection except to notice when it
+                    // hangs up, which ends the subscription even while no events arrive
+                    let subscriber = subscription.subscriber.clone();
+                    let hangup = thread::spawn(move || {
+                        let mut buffer = [0; 64];
+                        while let Ok(read) = reader.read(&mut buffer) {
+                            if read == 0 {
+                                break;
+                            }
+                        }
+                        subscriber.close();
+                    });
 
-                let _ = stream.write(response.bytes().as_slice());
-                let _ = stream.write(b"\r\n");
-                let _ = stream.flush();
-            },
-            None => {},
+                    commands::watch::stream(&subscription, &mut stream);
+                    let _ = stream.shutdown(Shutdown::Both);
+                    let _ = hangup.join();
+                    break;
+                },
+                Err(response) => {
+                    let _ = response.write_to(&mut stream);
+                    let _ = stream.write(b"\r\n");
+                    let _ = stream.flush();
+                    continue;
+                }
+            }
+        }
+
+        if let Some(response) = command::parse_command(string, namespaces, persistence, &mut namespace) {
+            println!("{:?}", response.header);
+            println!("{:?}", response);
+
+            // No shard is locked while writing, the value chunks are shared with the cache
+            let _ = response.write_to(&mut stream);
+            let _ = stream.write(b"\r\n");
+            let _ = stream.flush();
         }
     }
 }
 
+/**
+ * Build the shards of a cache, the capacity or memory limit is divided evenly between them.  The
+ * default namespace takes its capacity and policy from the configuration, other namespaces give
+ * their own capacity and optionally their own policy.  Every namespace shares the disk tier and
+ * the compressor, while the shards of a namespace share its watchers.
+ */
+fn build_cache(config: &Config, shared: &Shared, capacity: Option<usize>, policy: &str) -> Result<ShardedCache<Storage, Policy>, String> {
+    let policy = if policy.is_empty() { config.policy() } else { policy };
+
+    let mut shards = Vec::with_capacity(config.shards);
+    let watchers = Arc::new(Watchers::new(config.watch_buffer));
+
+    for _ in 0..config.shards {
+        let storage_structure = match storage_structure::from_name(&config.storage) {
+            Some(storage_structure) => storage_structure,
+            None => return Err(format!("Unknown storage structure {}", config.storage)),
+        };
+
+        let replacement_policy = match replacement_policy::from_name(policy, &config.policy_options()) {
+            Some(replacement_policy) => replacement_policy,
+            None => return Err(format!("Unknown replacement policy {}", policy)),
+        };
+
+        let mut cache: Cache<_, _> = match (capacity, config.memory_limit) {
+            (Some(capacity), _) => Cache::new(capacity / config.shards, storage_structure, replacement_policy),
+            (None, Some(megabytes)) => {
+                let slabs = SlabAllocator::new(megabytes * 1024 * 1024 / config.shards, config.growth_factor, config.min_chunk);
+                Cache::with_slabs(slabs, storage_structure, replacement_policy)
+            },
+            (None, None) => Cache::new(config.capacity / config.shards, storage_structure, replacement_policy),
+        };
+        cache.max_val_len = config.max_item_size;
+        cache.ext = shared.ext.clone();
+        cache.compressor = shared.compressor.clone();
+        cache.leases.duration = config.lease_time;
+        cache.stale_grace = config.stale_grace;
+        cache.policy = policy.to_lowercase();
+        cache.watchers = watchers.clone();
+        shards.push(cache);
+    }
+
+    Ok(ShardedCache::new(shards))
+}
+
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:4321").unwrap();
+    let config = match Config::from_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let ext = match config.ext_path {
+        Some(ref path) => match ExtStore::open(path, config.ext_item_size, config.ext_page_size as u64, config.ext_size as u64) {
+            Ok(ext) => Some(Arc::new(ext)),
+            Err(e) => {
+                println!("Unable to open disk tier {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let shared = Shared {
+        ext: ext.clone(),
+        compressor: config.compression.map(|algorithm| Arc::new(Compressor::new(algorithm, config.compression_threshold))),
+    };
+
+    let cache = match build_cache(&config, &shared, None, "") {
+        Ok(cache) => cache,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Some(threads) = config.bench_threads {
+        bench::run(&cache, threads);
+        return;
+    }
+
+    let factory_config = config.clone();
+    let factory = Box::new(move |capacity, policy: &str| build_cache(&factory_config, &shared, Some(capacity), policy));
+    let options = config.policy_options();
+    let policies = Box::new(move |policy: &str| replacement_policy::from_name(policy, &options));
+    let namespaces = Arc::new(Namespaces::new(cache, factory, policies));
+    // The op log only holds what changed since the snapshot so it is always replayed on top of it
+    if config.warm_restart || config.oplog.is_some() {
+        if let Some(ref path) = config.snapshot {
+            match persistence::snapshot::load(&namespaces, path) {
+                Ok(loaded) => println!("Loaded {} items from {}", loaded, path),
+                Err(e) => println!("Unable to load snapshot {}: {}", path, e),
+            }
+        }
+    }
+
+    let log = match config.oplog {
+        Some(ref path) => {
+            match oplog::replay(&namespaces, path) {
+                Ok(applied) => println!("Replayed {} changes from {}", applied, path),
+                Err(e) => {
+                    println!("Unable to replay op log {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+
+            match OpLog::open(path, config.oplog_fsync) {
+                Ok(log) => Some(log),
+                Err(e) => {
+                    println!("Unable to open op log {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        },
+        None => None,
+    };
+    let persistence = Arc::new(Persistence::new(config.snapshot.clone(), log));
+
+    shutdown::install();
+    {
+        let (namespaces, persistence) = (namespaces.clone(), persistence.clone());
+        thread::spawn(move || {
+            while !shutdown::requested() {
+                thread::sleep(Duration::from_millis(100));
+            }
+
+            if persistence.snapshot_path.is_some() {
+                match persistence.snapshot(&namespaces) {
+                    Ok(saved) => println!("Saved {} items", saved),
+                    Err(e) => println!("{}", e),
+                }
+            }
+            process::exit(0);
+        });
+    }
+
+    if persistence.log.is_some() {
+        let (namespaces, persistence, compact) = (namespaces.clone(), persistence.clone(), config.oplog_compact as u64);
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(1));
+
+                if let Some(ref log) = persistence.log {
+                    if log.fsync == FsyncPolicy::EverySecond {
+                        if let Err(e) = log.sync() {
+                            println!("Unable to sync op log {}: {}", log.path, e);
+                        }
+                    }
+
+                    if log.len() > compact {
+                        match persistence.snapshot(&namespaces) {
+                            Ok(saved) => println!("Compacted op log into a snapshot of {} items", saved),
+                            Err(e) => println!("{}", e),
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    if let Some(ext) = ext {
+        let namespaces = namespaces.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(1));
+
+                // Every shard moves its values off the page before the page goes
+                while let Some(page) = ext.compaction_candidate() {
+                    for (_, cache) in namespaces.list() {
+                        cache.for_each(|shard| shard.compact_ext(page));
+                    }
+                    if let Err(e) = ext.remove_page(page) {
+                        println!("Unable to remove disk tier page {}: {}", page, e);
+                    }
+                }
+            }
+        });
+    }
+
+    if config.memory_limit.is_some() && config.slab_automove {
+        let namespaces = namespaces.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(10));
+                for (_, cache) in namespaces.list() {
+                    cache.for_each(|shard| shard.slab_automove());
+                }
+            }
+        });
+    }
+
+    if config.prefix_reclaim > 0 {
+        let (namespaces, interval) = (namespaces.clone(), config.prefix_reclaim);
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(interval));
+                for (name, cache) in namespaces.list() {
+                    let mut reclaimed = 0;
+                    cache.for_each(|shard| reclaimed += shard.reclaim_flushed());
+                    if reclaimed > 0 {
+                        println!("Reclaimed {} flushed items of namespace {}", reclaimed, name);
+                    }
+                }
+            }
+        });
+    }
+
+    let listener = TcpListener::bind(config.address.as_str()).unwrap();
 
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
                 println!("Established connection!");
-                handle_client(stream);
+                let (namespaces, persistence) = (namespaces.clone(), persistence.clone());
+                thread::spawn(move || handle_client(stream, &namespaces, &persistence));
             }
             Err(e) => {
                 panic!("Unable to establish connection: {}", e);
             }
         }
     }
-}
\ No newline at end of file
+}
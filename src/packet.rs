@@ -1,4 +1,7 @@
 use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::sync::Arc;
 // TODO: String -> &str to have better cache locality
 
 pub struct MemPacket {
@@ -6,6 +9,7 @@ pub struct MemPacket {
     pub extras: String,
     pub key: String,
     pub value: String,
+    pub value_chunks: Vec<Arc<[u8]>>, // Values handed out by the cache, written after `value`
 }
 
 impl MemPacket {
@@ -54,6 +58,7 @@ impl MemPacket {
             extras: String::from_utf8_lossy(if e_len > 0 { &bytes[e_start..e_end] } else { &[] }).into_owned(),
             key:    String::from_utf8_lossy(if k_len > 0 { &bytes[k_start..k_end] } else { &[] }).into_owned(),
             value:  String::from_utf8_lossy(if (v_len as usize - e_len as usize - k_len as usize) > 0 { &bytes[v_start..v_end] } else { &[] }).into_owned(),
+            value_chunks: Vec::new(),
         }
     }
 
@@ -63,6 +68,7 @@ impl MemPacket {
             key: String::new(),
             extras: String::new(),
             value: String::new(),
+            value_chunks: Vec::new(),
         }
     }
 
@@ -84,6 +90,17 @@ impl MemPacket {
         self
     }
 
+    /**
+     * Use a chain of chunks as the value, they are shared with the cache rather than copied
+     */
+    pub fn with_value_chunks(&mut self, chunks: Vec<Arc<[u8]>>) -> &mut MemPacket {
+        let len: usize = chunks.iter().map(|chunk| chunk.len()).sum();
+        self.header.with_value_len(len as u32);
+        self.value = String::new();
+        self.value_chunks = chunks;
+        self
+    }
+
     pub fn has_key(&self) -> bool {
         self.header.key_length > 0 && self.key.len() > 0
     }
@@ -93,7 +110,7 @@ impl MemPacket {
     }
 
     pub fn has_value(&self) -> bool {
-        self.header.total_body_length - self.header.key_length as u32 - self.header.extras_length as u32 > 0 && self.value.len() > 0
+        self.header.total_body_length - self.header.key_length as u32 - self.header.extras_length as u32 > 0 && (!self.value.is_empty() || !self.value_chunks.is_empty())
     }
 
     pub fn bytes(&self) -> Vec<u8> {
@@ -103,9 +120,28 @@ impl MemPacket {
         out.extend(self.extras.bytes());
         out.extend(self.key.bytes());
         out.extend(self.value.bytes());
+        for chunk in self.value_chunks.iter() {
+            out.extend_from_slice(chunk);
+        }
 
         return out;
     }
+
+    /**
+     * Write the packet out piece by piece so that large values are streamed chunk by chunk
+     * instead of being assembled into a single buffer first
+     */
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(self.header.bytes().as_slice())?;
+        out.write_all(self.extras.as_bytes())?;
+        out.write_all(self.key.as_bytes())?;
+        out.write_all(self.value.as_bytes())?;
+        for chunk in self.value_chunks.iter() {
+            out.write_all(chunk)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl fmt::Debug for MemPacket {
@@ -114,6 +150,7 @@ impl fmt::Debug for MemPacket {
             .field("extras", &self.extras)
             .field("key", &self.key)
             .field("value", &self.value)
+            .field("value_chunks", &self.value_chunks.len())
             .finish()
     }
 }
@@ -0,0 +1,68 @@
+pub mod snapshot;
+pub mod oplog;
+
+use std::sync::Mutex;
+
+use cache::namespace::Namespaces;
+use cache::storage_structure::CacheStorageStructure;
+use cache::replacement_policy::CacheReplacementPolicy;
+use persistence::oplog::{Op, OpLog};
+
+/**
+ * Where the contents of the cache are saved so that they survive a restart: snapshots of the
+ * whole cache and, optionally, a log of the changes made since the last snapshot
+ */
+pub struct Persistence {
+    pub snapshot_path: Option<String>,
+    pub log: Option<OpLog>,
+    snapshotting: Mutex<()>,
+}
+
+impl Persistence {
+    pub fn new(snapshot_path: Option<String>, log: Option<OpLog>) -> Persistence {
+        Persistence {
+            snapshot_path,
+            log,
+            snapshotting: Mutex::new(()),
+        }
+    }
+
+    /**
+     * Write a snapshot of every namespace to the configured path, returning the number of entries.
+     * The op log is rotated before the snapshot is taken and its older records discarded once the
+     * snapshot is complete, which is also how the log is compacted.
+     */
+    pub fn snapshot<T: CacheStorageStructure, R: CacheReplacementPolicy>(&self, namespaces: &Namespaces<T, R>) -> Result<usize, String> {
+        let path = match self.snapshot_path {
+            Some(ref path) => path,
+            None => return Err(String::from("No snapshot path configured")),
+        };
+
+        // Only one snapshot at a time, a second rotation could otherwise discard records that
+        // are not in the snapshot being written
+        let _snapshotting = self.snapshotting.lock().unwrap();
+
+        if let Some(ref log) = self.log {
+            log.rotate().map_err(|e| format!("Unable to rotate op log {}: {}", log.path, e))?;
+        }
+
+        let saved = snapshot::save(namespaces, path).map_err(|e| format!("Unable to write snapshot {}: {}", path, e))?;
+
+        if let Some(ref log) = self.log {
+            log.remove_rotated().map_err(|e| format!("Unable to remove rotated op log {}: {}", log.path, e))?;
+        }
+
+        Ok(saved)
+    }
+
+    /**
+     * Record a change in the op log, if there is one
+     */
+    pub fn log(&self, op: Op) {
+        if let Some(ref log) = self.log {
+            if let Err(e) = log.append(&op) {
+                println!("Unable to write to op log {}: {}", log.path, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,481 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Error, ErrorKind, SeekFrom};
+use std::io::prelude::*;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use cache::data_entry::DataEntry;
+use cache::key::Key;
+use cache::namespace::Namespaces;
+use cache::storage_structure::CacheStorageStructure;
+use cache::replacement_policy::CacheReplacementPolicy;
+use cache::time;
+use config::MAX_ITEM_SIZE_LIMIT;
+use persistence::snapshot::{write_entry, read_entry, write_bytes, read_string, write_u32, write_u64, read_u32, read_u64};
+
+/**
+ * The op log records every change to the cache since the last snapshot so that writes made after
+ * it survive a crash.  It starts with a magic number and format version followed by records that
+ * each start with their length, so a record torn by a crash can be recognised and dropped.
+ *
+ * Incrementing and appending are logged as the entry they produce rather than as the operation,
+ * so every record describes the state of a key and replaying a record twice has no further
+ * effect.  That lets a snapshot be taken while writes continue: the log is rotated first and the
+ * rotated log is removed once the snapshot is complete, records written to the new log in the
+ * meantime may already be in the snapshot and are simply applied again.
+ */
+const MAGIC: &[u8; 8] = b"RMCOPLOG";
+const VERSION: u32 = 2;
+
+const MAX_RECORD_LEN: usize = MAX_ITEM_SIZE_LIMIT + 1024 * 1024;
+
+const OP_SET: u8 = 1;
+const OP_DELETE: u8 = 2;
+const OP_FLUSH: u8 = 3;
+const OP_CREATE_NAMESPACE: u8 = 4;
+const OP_RESIZE_NAMESPACE: u8 = 5;
+const OP_DROP_NAMESPACE: u8 = 6;
+const OP_INVALIDATE_TAG: u8 = 7;
+const OP_FLUSH_PREFIX: u8 = 8;
+const OP_SET_POLICY: u8 = 9;
+
+/**
+ * A change to a namespace
+ */
+pub enum Op<'a> {
+    Set(&'a str, &'a DataEntry),
+    Delete(&'a str, &'a Key),
+    Flush(&'a str),
+    CreateNamespace(&'a str, usize, &'a str, u64),
+    ResizeNamespace(&'a str, usize),
+    DropNamespace(&'a str),
+    InvalidateTag(&'a str, &'a str),
+    FlushPrefix(&'a str, &'a str),
+    SetPolicy(&'a str, &'a str),
+}
+
+/**
+ * When appended records are forced to disk: after every record, once a second by a background
+ * thread, or whenever the operating system writes them back
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FsyncPolicy {
+    Always,
+    EverySecond,
+    Never,
+}
+
+impl FsyncPolicy {
+    pub fn from_name(name: &str) -> Option<FsyncPolicy> {
+        match name.to_lowercase().as_str() {
+            "always" => Some(FsyncPolicy::Always),
+            "everysec" => Some(FsyncPolicy::EverySecond),
+            "never" => Some(FsyncPolicy::Never),
+            _ => None,
+        }
+    }
+}
+
+struct LogFile {
+    file: File,
+    len: u64,
+}
+
+pub struct OpLog {
+    pub path: String,
+    pub fsync: FsyncPolicy,
+    log: Mutex<LogFile>,
+    dirty: AtomicBool,
+}
+
+impl OpLog {
+    /**
+     * Open the log at path for appending, creating it if it does not exist
+     */
+    pub fn open(path: &str, fsync: FsyncPolicy) -> io::Result<OpLog> {
+        Ok(OpLog {
+            path: String::from(path),
+            fsync,
+            log: Mutex::new(open_file(path)?),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /**
+     * Append a record.  Records are written with a single write so a record is never interleaved
+     * with another, callers hold the lock on whatever they changed so that records for the same
+     * key are in the order the changes were made.
+     */
+    pub fn append(&self, op: &Op) -> io::Result<()> {
+        let mut record = vec![0; 4];
+        encode(&mut record, op)?;
+        let len = (record.len() - 4) as u32;
+        record[..4].copy_from_slice(&len.to_be_bytes());
+
+        let mut log = self.log.lock().unwrap();
+        log.file.write_all(&record)?;
+        log.len += record.len() as u64;
+
+        match self.fsync {
+            FsyncPolicy::Always => log.file.sync_data(),
+            FsyncPolicy::EverySecond => {
+                self.dirty.store(true, Ordering::Relaxed);
+                Ok(())
+            },
+            FsyncPolicy::Never => Ok(()),
+        }
+    }
+
+    /**
+     * Force records appended since the last sync to disk
+     */
+    pub fn sync(&self) -> io::Result<()> {
+        if self.dirty.swap(false, Ordering::Relaxed) {
+            self.log.lock().unwrap().file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Size of the log in bytes
+     */
+    pub fn len(&self) -> u64 {
+        self.log.lock().unwrap().len
+    }
+
+    /**
+     * Move the records written so far to the rotated log and start an empty log.  If a rotated
+     * log is still around because the snapshot that should have replaced it failed, the records
+     * are added to it instead.
+     */
+    pub fn rotate(&self) -> io::Result<()> {
+        let rotated = rotated_path(&self.path);
+        let mut log = self.log.lock().unwrap();
+        log.file.sync_data()?;
+
+        if fs::metadata(&rotated).is_ok() {
+            let mut out = OpenOptions::new().append(true).open(&rotated)?;
+            let mut input = File::open(&self.path)?;
+            input.seek(SeekFrom::Start((MAGIC.len() + 4) as u64))?;
+            io::copy(&mut input, &mut out)?;
+            out.sync_data()?;
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::rename(&self.path, &rotated)?;
+        }
+
+        *log = open_file(&self.path)?;
+        Ok(())
+    }
+
+    /**
+     * Remove the rotated log once a snapshot holds everything in it
+     */
+    pub fn remove_rotated(&self) -> io::Result<()> {
+        match fs::remove_file(rotated_path(&self.path)) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+fn rotated_path(path: &str) -> String {
+    format!("{}.old", path)
+}
+
+fn open_file(path: &str) -> io::Result<LogFile> {
+    let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+    let mut len = file.metadata()?.len();
+
+    if len == 0 {
+        file.write_all(MAGIC)?;
+        write_u32(&mut file, VERSION)?;
+        file.sync_all()?;
+        len = (MAGIC.len() + 4) as u64;
+    }
+
+    Ok(LogFile {
+        file,
+        len,
+    })
+}
+
+fn encode(out: &mut Vec<u8>, op: &Op) -> io::Result<()> {
+    match *op {
+        Op::Set(namespace, entry) => {
+            out.push(OP_SET);
+            write_bytes(out, namespace.as_bytes())?;
+            write_entry(out, entry)
+        },
+        Op::Delete(namespace, key) => {
+            out.push(OP_DELETE);
+            write_bytes(out, namespace.as_bytes())?;
+            write_bytes(out, key.item.as_bytes())
+        },
+        Op::Flush(namespace) => {
+            out.push(OP_FLUSH);
+            write_bytes(out, namespace.as_bytes())
+        },
+        Op::CreateNamespace(namespace, capacity, policy, ttl) => {
+            out.push(OP_CREATE_NAMESPACE);
+            write_bytes(out, namespace.as_bytes())?;
+            write_u64(out, capacity as u64)?;
+            write_bytes(out, policy.as_bytes())?;
+            write_u64(out, ttl)
+        },
+        Op::ResizeNamespace(namespace, capacity) => {
+            out.push(OP_RESIZE_NAMESPACE);
+            write_bytes(out, namespace.as_bytes())?;
+            write_u64(out, capacity as u64)
+        },
+        Op::DropNamespace(namespace) => {
+            out.push(OP_DROP_NAMESPACE);
+            write_bytes(out, namespace.as_bytes())
+        },
+        Op::InvalidateTag(namespace, tag) => {
+            out.push(OP_INVALIDATE_TAG);
+            write_bytes(out, namespace.as_bytes())?;
+            write_bytes(out, tag.as_bytes())
+        },
+        Op::FlushPrefix(namespace, prefix) => {
+            out.push(OP_FLUSH_PREFIX);
+            write_bytes(out, namespace.as_bytes())?;
+            write_bytes(out, prefix.as_bytes())
+        },
+        Op::SetPolicy(namespace, policy) => {
+            out.push(OP_SET_POLICY);
+            write_bytes(out, namespace.as_bytes())?;
+            write_bytes(out, policy.as_bytes())
+        },
+    }
+}
+
+/**
+ * Apply the rotated log, if there is one, and then the log at path to the namespaces, returning
+ * the number of records applied.  A record torn by a crash ends the log and is cut off so that
+ * new records are appended after the last complete one.
+ */
+pub fn replay<T: CacheStorageStructure, R: CacheReplacementPolicy>(namespaces: &Namespaces<T, R>, path: &str) -> io::Result<usize> {
+    let mut applied = 0;
+
+    for path in [rotated_path(path), String::from(path)].iter() {
+        match File::open(path) {
+            Ok(_) => applied += replay_file(namespaces, path)?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(applied)
+}
+
+fn replay_file<T: CacheStorageStructure, R: CacheReplacementPolicy>(namespaces: &Namespaces<T, R>, path: &str) -> io::Result<usize> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut input = BufReader::new(file);
+
+    if file_len == 0 {
+        return Ok(0);
+    }
+
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "not an op log"));
+    }
+
+    let version = read_u32(&mut input)?;
+    if version != VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, format!("unsupported op log version {}", version)));
+    }
+
+    let mut offset = (MAGIC.len() + 4) as u64;
+    let mut applied = 0;
+
+    loop {
+        let len = match read_u32(&mut input) {
+            Ok(len) => len as usize,
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        // A length no record can have is what is left of a torn one
+        if len > MAX_RECORD_LEN {
+            break;
+        }
+
+        let mut record = vec![0; len];
+        match input.read_exact(&mut record) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+
+        apply(namespaces, &mut record.as_slice())?;
+        offset += 4 + len as u64;
+        applied += 1;
+    }
+
+    if offset < file_len {
+        println!("Dropping {} bytes of an incomplete record at the end of {}", file_len - offset, path);
+        OpenOptions::new().write(true).open(path)?.set_len(offset)?;
+    }
+
+    Ok(applied)
+}
+
+fn apply<T: CacheStorageStructure, R: CacheReplacementPolicy>(namespaces: &Namespaces<T, R>, record: &mut &[u8]) -> io::Result<()> {
+    let mut op = [0; 1];
+    record.read_exact(&mut op)?;
+    let namespace = read_string(record)?;
+
+    // Namespace changes are applied whether or not they already were before the snapshot
+    match op[0] {
+        OP_SET => {
+            let entry = read_entry(record)?;
+            if let Some(cache) = namespaces.get(&namespace) {
+                let mut shard = cache.shard(&entry.key);
+                if entry.is_expired(time::now()) {
+                    shard.remove(entry.key);
+                } else {
+                    let _ = shard.insert(entry);
+                }
+            }
+        },
+        OP_DELETE => {
+            let key = Key::new(read_string(record)?);
+            if let Some(cache) = namespaces.get(&namespace) {
+                cache.shard(&key).remove(key);
+            }
+        },
+        OP_FLUSH => {
+            if let Some(cache) = namespaces.get(&namespace) {
+                cache.for_each(|shard| shard.flush());
+            }
+        },
+        OP_CREATE_NAMESPACE => {
+            let capacity = read_u64(record)? as usize;
+            let policy = read_string(record)?;
+            let ttl = read_u64(record)?;
+            if namespaces.get(&namespace).is_none() {
+                if let Err(e) = namespaces.create(&namespace, capacity, &policy, ttl) {
+                    println!("Skipping namespace {} of the op log: {}", namespace, e);
+                }
+            }
+        },
+        OP_RESIZE_NAMESPACE => {
+            let capacity = read_u64(record)? as usize;
+            let _ = namespaces.resize(&namespace, capacity);
+        },
+        OP_DROP_NAMESPACE => {
+            let _ = namespaces.drop_namespace(&namespace);
+        },
+        OP_INVALIDATE_TAG => {
+            let tag = read_string(record)?;
+            if let Some(cache) = namespaces.get(&namespace) {
+                cache.for_each(|shard| { shard.invalidate_tag(&tag); });
+            }
+        },
+        OP_FLUSH_PREFIX => {
+            let prefix = read_string(record)?;
+            if let Some(cache) = namespaces.get(&namespace) {
+                cache.for_each(|shard| shard.flush_prefix(&prefix));
+            }
+        },
+        OP_SET_POLICY => {
+            let policy = read_string(record)?;
+            if let Err(e) = namespaces.set_policy(&namespace, &policy) {
+                println!("Skipping policy switch of the op log: {}", e);
+            }
+        },
+        _ => return Err(Error::new(ErrorKind::InvalidData, "unknown op log record")),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::namespace::DEFAULT_NAMESPACE;
+    use cache::namespace::tests::namespaces;
+    use cache::value::Value;
+    use persistence::snapshot::tests::temp_path;
+
+    fn entry(key: &str, value: &str) -> DataEntry {
+        DataEntry::new(Key::new(key.to_string()), Value::new(value.to_string()))
+    }
+
+    fn value<T: CacheStorageStructure, R: CacheReplacementPolicy>(namespaces: &Namespaces<T, R>, namespace: &str, key: &str) -> Option<Value> {
+        namespaces.get(namespace)?.get(Key::new(key.to_string())).map(|entry| entry.value)
+    }
+
+    #[test]
+    fn replaying_the_log_reproduces_the_changes() {
+        let path = temp_path("oplog");
+        let log = OpLog::open(&path, FsyncPolicy::Always).unwrap();
+        log.append(&Op::Set(DEFAULT_NAMESPACE, &entry("a", "1"))).unwrap();
+        log.append(&Op::Set(DEFAULT_NAMESPACE, &entry("b", "2"))).unwrap();
+        log.append(&Op::Set(DEFAULT_NAMESPACE, &entry("a", "3"))).unwrap();
+        log.append(&Op::Delete(DEFAULT_NAMESPACE, &Key::new(String::from("b")))).unwrap();
+        log.append(&Op::CreateNamespace("tenant", 64 * 1024, "lfu", 0)).unwrap();
+        log.append(&Op::Set("tenant", &entry("t", "4"))).unwrap();
+        log.append(&Op::ResizeNamespace("tenant", 128 * 1024)).unwrap();
+        log.append(&Op::CreateNamespace("gone", 64 * 1024, "lru", 0)).unwrap();
+        log.append(&Op::DropNamespace("gone")).unwrap();
+        drop(log);
+
+        // Replaying on top of state that already holds some of the changes has the same outcome
+        let replayed = namespaces(1024 * 1024);
+        for _ in 0..2 {
+            assert_eq!(replay(&replayed, &path).unwrap(), 9);
+            assert_eq!(value(&replayed, DEFAULT_NAMESPACE, "a"), Some(Value::new(String::from("3"))));
+            assert_eq!(value(&replayed, DEFAULT_NAMESPACE, "b"), None);
+            assert_eq!(value(&replayed, "tenant", "t"), Some(Value::new(String::from("4"))));
+            replayed.get("tenant").unwrap().for_each(|shard| assert_eq!(shard.capacity, 64 * 1024));
+            assert!(replayed.get("gone").is_none());
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_torn_record_is_cut_off() {
+        let path = temp_path("oplog-torn");
+        let log = OpLog::open(&path, FsyncPolicy::Never).unwrap();
+        log.append(&Op::Set(DEFAULT_NAMESPACE, &entry("a", "1"))).unwrap();
+        let complete = log.len();
+        log.append(&Op::Set(DEFAULT_NAMESPACE, &entry("b", "2"))).unwrap();
+        drop(log);
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(complete + 6).unwrap();
+
+        let replayed = namespaces(1024 * 1024);
+        assert_eq!(replay(&replayed, &path).unwrap(), 1);
+        assert!(value(&replayed, DEFAULT_NAMESPACE, "a").is_some());
+        assert!(value(&replayed, DEFAULT_NAMESPACE, "b").is_none());
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+
+        // New records go after the last complete one
+        let log = OpLog::open(&path, FsyncPolicy::Never).unwrap();
+        log.append(&Op::Set(DEFAULT_NAMESPACE, &entry("c", "3"))).unwrap();
+        drop(log);
+        assert_eq!(replay(&namespaces(1024 * 1024), &path).unwrap(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn the_rotated_log_is_replayed_first() {
+        let path = temp_path("oplog-rotate");
+        let log = OpLog::open(&path, FsyncPolicy::EverySecond).unwrap();
+        log.append(&Op::Set(DEFAULT_NAMESPACE, &entry("a", "1"))).unwrap();
+        log.rotate().unwrap();
+        log.append(&Op::Set(DEFAULT_NAMESPACE, &entry("a", "2"))).unwrap();
+        log.sync().unwrap();
+
+        let replayed = namespaces(1024 * 1024);
+        assert_eq!(replay(&replayed, &path).unwrap(), 2);
+        assert_eq!(value(&replayed, DEFAULT_NAMESPACE, "a"), Some(Value::new(String::from("2"))));
+
+        log.remove_rotated().unwrap();
+        assert_eq!(replay(&namespaces(1024 * 1024), &path).unwrap(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+}
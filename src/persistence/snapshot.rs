@@ -0,0 +1,388 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind};
+use std::io::prelude::*;
+
+use std::sync::Arc;
+
+use cache::cache::Cache;
+use cache::data_entry::DataEntry;
+use cache::key::Key;
+use cache::namespace::Namespaces;
+use cache::sharded_cache::ShardedCache;
+use cache::storage_structure::CacheStorageStructure;
+use cache::replacement_policy::CacheReplacementPolicy;
+use cache::time;
+use cache::value::Value;
+use config::MAX_ITEM_SIZE_LIMIT;
+
+/**
+ * A snapshot starts with a magic number and format version followed by one record per namespace,
+ * its name, capacity, default ttl and replacement policy, each followed by one record per entry of
+ * the namespace.  The entries of each shard are written in the order the replacement policy would
+ * evict them, so loading them in file order leaves the most valuable entries as the most recently
+ * inserted ones and anything that no longer fits is what would have been evicted first anyway.
+ *
+ * The shards are written one at a time under their read lock, straight from the cache to the file,
+ * so saving never holds more than one entry in memory.
+ *
+ * Integers are big endian.  The snapshot is written to a temporary file that replaces the previous
+ * snapshot only once it is complete.
+ */
+const MAGIC: &[u8; 8] = b"RMCSNAP\0";
+const VERSION: u32 = 4;
+
+const MAX_STRING_LEN: usize = 64 * 1024;
+
+const RECORD_END: u8 = 0;
+const RECORD_NAMESPACE: u8 = 1;
+const RECORD_ENTRY: u8 = 2;
+
+/**
+ * Write every namespace to the file at path, returning the number of entries written
+ */
+pub fn save<T: CacheStorageStructure, R: CacheReplacementPolicy>(namespaces: &Namespaces<T, R>, path: &str) -> io::Result<usize> {
+    let temporary = format!("{}.tmp", path);
+    let written;
+
+    {
+        let file = File::create(&temporary)?;
+        let mut out = BufWriter::new(&file);
+
+        out.write_all(MAGIC)?;
+        write_u32(&mut out, VERSION)?;
+        written = write_namespaces(&mut out, namespaces)?;
+
+        out.flush()?;
+        drop(out);
+        file.sync_all()?;
+    }
+
+    fs::rename(&temporary, path)?;
+    Ok(written)
+}
+
+/**
+ * Load a snapshot into the namespaces, creating namespaces that do not exist yet.  Expired entries
+ * are skipped and entries that do not fit evict as they would have in the running cache.  Returns
+ * the number of entries loaded.
+ */
+pub fn load<T: CacheStorageStructure, R: CacheReplacementPolicy>(namespaces: &Namespaces<T, R>, path: &str) -> io::Result<usize> {
+    let mut input = BufReader::new(File::open(path)?);
+
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "not a snapshot"));
+    }
+
+    let version = read_u32(&mut input)?;
+    if version != VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, format!("unsupported snapshot version {}", version)));
+    }
+
+    read_namespaces(&mut input, namespaces)
+}
+
+/**
+ * Write a record for every namespace followed by its entries, and the end record.  Returns the
+ * number of entries written.
+ */
+pub fn write_namespaces<T: CacheStorageStructure, R: CacheReplacementPolicy, W: Write>(out: &mut W, namespaces: &Namespaces<T, R>) -> io::Result<usize> {
+    let mut written = 0;
+
+    for (name, cache) in namespaces.list() {
+        let (mut capacity, mut ttl, mut policy) = (0, 0, String::new());
+        cache.read_each(|shard| {
+            capacity += shard.capacity;
+            ttl = shard.item_lifetime;
+            policy = shard.policy.clone();
+        });
+
+        out.write_all(&[RECORD_NAMESPACE])?;
+        write_bytes(out, name.as_bytes())?;
+        write_u64(out, capacity as u64)?;
+        write_u64(out, ttl)?;
+        write_bytes(out, policy.as_bytes())?;
+
+        let mut result = Ok(());
+        cache.read_each(|shard| {
+            if result.is_ok() {
+                result = write_shard(out, shard).map(|count| written += count);
+            }
+        });
+        result?;
+    }
+
+    out.write_all(&[RECORD_END])?;
+    Ok(written)
+}
+
+/**
+ * Read namespace and entry records up to the end record into the namespaces, returning the number
+ * of entries loaded
+ */
+pub fn read_namespaces<T: CacheStorageStructure, R: CacheReplacementPolicy, I: Read>(input: &mut I, namespaces: &Namespaces<T, R>) -> io::Result<usize> {
+    let now = time::now();
+    let mut loaded = 0;
+
+    // The namespace the entries read belong to, None inside a namespace that was skipped
+    let mut current: Option<Option<Arc<ShardedCache<T, R>>>> = None;
+
+    loop {
+        let mut record = [0; 1];
+        input.read_exact(&mut record)?;
+
+        match record[0] {
+            RECORD_END => return Ok(loaded),
+            RECORD_NAMESPACE => {},
+            RECORD_ENTRY => {
+                let entry = read_entry(input)?;
+                let cache = match current {
+                    Some(ref cache) => cache,
+                    None => return Err(Error::new(ErrorKind::InvalidData, "entry outside of a namespace")),
+                };
+
+                if let Some(ref cache) = *cache {
+                    if !entry.is_expired(now) && cache.shard(&entry.key).insert(entry).is_ok() {
+                        loaded += 1;
+                    }
+                }
+                continue;
+            },
+            _ => return Err(Error::new(ErrorKind::InvalidData, "unknown snapshot record")),
+        }
+
+        let name = read_string(input)?;
+        let capacity = read_u64(input)? as usize;
+        let ttl = read_u64(input)?;
+        let policy = read_string(input)?;
+
+        match namespaces.get(&name) {
+            Some(cache) => {
+                // The policy may have been switched while the server was running
+                let mut switched = false;
+                cache.for_each(|shard| switched |= shard.policy != policy);
+                if switched {
+                    if let Err(e) = namespaces.set_policy(&name, &policy) {
+                        println!("Keeping the replacement policy of namespace {}: {}", name, e);
+                    }
+                }
+            },
+            None => {
+                if let Err(e) = namespaces.create(&name, capacity, &policy, ttl) {
+                    println!("Skipping namespace {} of the snapshot: {}", name, e);
+                }
+            },
+        }
+        current = Some(namespaces.get(&name));
+    }
+}
+
+/**
+ * Write an entry record for every entry of a shard in the order of its policy.  Entries the policy
+ * does not report on go first, oldest access first.  Values are saved as they were set, read back
+ * from the disk tier, which does not survive a restart, and decompressed.  Returns the number of
+ * entries written.
+ */
+fn write_shard<T: CacheStorageStructure, R: CacheReplacementPolicy, W: Write>(out: &mut W, shard: &Cache<T, R>) -> io::Result<usize> {
+    let mut seen = HashSet::new();
+    let ordered: Vec<usize> = shard.replacement_policy.eviction_order().into_iter()
+        .filter(|index| seen.insert(*index))
+        .collect();
+
+    let mut unordered: Vec<(u64, usize)> = shard.storage_structure.indices().into_iter()
+        .filter(|index| !seen.contains(index))
+        .filter_map(|index| shard.storage_structure.get_index(index))
+        .map(|(index, entry)| (entry.last_access, index))
+        .collect();
+    unordered.sort();
+
+    let mut written = 0;
+    for index in unordered.into_iter().map(|(_, index)| index).chain(ordered) {
+        let entry = match shard.storage_structure.get_index(index) {
+            Some((_, entry)) if !shard.is_flushed(&entry) => entry,
+            _ => continue,
+        };
+
+        if let Ok(entry) = shard.materialize(entry) {
+            out.write_all(&[RECORD_ENTRY])?;
+            write_entry(out, &entry)?;
+            written += 1;
+        }
+    }
+    Ok(written)
+}
+
+pub fn write_entry<W: Write>(out: &mut W, entry: &DataEntry) -> io::Result<()> {
+    write_bytes(out, entry.key.item.as_bytes())?;
+
+    write_u64(out, entry.value.len() as u64)?;
+    for chunk in entry.value.chunks.iter() {
+        out.write_all(chunk)?;
+    }
+
+    write_u32(out, entry.flags)?;
+    write_u64(out, entry.expires_at)?;
+    write_u64(out, entry.value.cas)?;
+    write_u64(out, entry.cost)?;
+    write_u64(out, entry.last_access)?;
+
+    write_u32(out, entry.tags.len() as u32)?;
+    for tag in entry.tags.iter() {
+        write_bytes(out, tag.as_bytes())?;
+    }
+    Ok(())
+}
+
+pub fn read_entry<R: Read>(input: &mut R) -> io::Result<DataEntry> {
+    let key = read_string(input)?;
+
+    let len = read_u64(input)? as usize;
+    if len > MAX_ITEM_SIZE_LIMIT {
+        return Err(Error::new(ErrorKind::InvalidData, "value larger than the item size limit"));
+    }
+    let mut bytes = vec![0; len];
+    input.read_exact(&mut bytes)?;
+
+    let mut value = Value::from_bytes(bytes);
+    let flags = read_u32(input)?;
+    let expires_at = read_u64(input)?;
+    value.cas = read_u64(input)?;
+    let cost = read_u64(input)?;
+    let last_access = read_u64(input)?;
+
+    let count = read_u32(input)? as usize;
+    let mut tags = Vec::new();
+    for _ in 0..count {
+        tags.push(read_string(input)?);
+    }
+
+    let mut entry = DataEntry::with_cost(Key::new(key), value, cost);
+    entry.flags = flags;
+    entry.expires_at = expires_at;
+    entry.last_access = last_access;
+    entry.tags = tags;
+    Ok(entry)
+}
+
+pub fn write_bytes<W: Write>(out: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_u32(out, bytes.len() as u32)?;
+    out.write_all(bytes)
+}
+
+pub fn read_string<R: Read>(input: &mut R) -> io::Result<String> {
+    let len = read_u32(input)? as usize;
+    if len > MAX_STRING_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "key longer than any key the cache accepts"));
+    }
+    let mut bytes = vec![0; len];
+    input.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| Error::new(ErrorKind::InvalidData, "key is not valid UTF-8"))
+}
+
+pub fn write_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_be_bytes())
+}
+
+pub fn write_u64<W: Write>(out: &mut W, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_be_bytes())
+}
+
+pub fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+pub fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use cache::namespace::DEFAULT_NAMESPACE;
+    use cache::namespace::tests::namespaces;
+
+    /**
+     * A path in the temporary directory unique to the test process
+     */
+    pub fn temp_path(name: &str) -> String {
+        env::temp_dir().join(format!("memcached-{}-{}", process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    fn key(name: &str) -> Key {
+        Key::new(name.to_string())
+    }
+
+    /**
+     * The keys of every namespace, each shard in the order its policy would evict them
+     */
+    fn keys<T: CacheStorageStructure, R: CacheReplacementPolicy>(namespaces: &Namespaces<T, R>) -> Vec<(String, Vec<String>)> {
+        namespaces.list().into_iter().map(|(name, cache)| {
+            let mut keys = Vec::new();
+            cache.read_each(|shard| {
+                for index in shard.replacement_policy.eviction_order() {
+                    keys.extend(shard.storage_structure.get_index(index).map(|(_, entry)| entry.key.item));
+                }
+            });
+            (name, keys)
+        }).collect()
+    }
+
+    #[test]
+    fn snapshots_round_trip_namespaces_and_entries() {
+        let path = temp_path("snapshot");
+        let saved = namespaces(1024 * 1024);
+        assert!(saved.create("tenant", 512 * 1024, "lfu", 60).is_ok());
+
+        let cache = saved.get(DEFAULT_NAMESPACE).unwrap();
+        for i in 0..20 {
+            let mut entry = DataEntry::new(key(&format!("k{}", i)), Value::new(format!("value {}", i))).with_tags(vec![String::from("tag")]);
+            entry.flags = i;
+            assert!(cache.shard(&entry.key).insert(entry).is_ok());
+        }
+        let mut expired = DataEntry::new(key("expired"), Value::new(String::from("v")));
+        expired.expires_at = 1;
+        assert!(cache.shard(&expired.key).insert(expired).is_ok());
+        let tenant = saved.get("tenant").unwrap();
+        assert!(tenant.shard(&key("t")).set(key("t"), Value::new(String::from("v"))).is_ok());
+
+        assert_eq!(save(&saved, &path).unwrap(), 22);
+        let loaded = namespaces(1024 * 1024);
+        assert_eq!(load(&loaded, &path).unwrap(), 21);
+        fs::remove_file(&path).unwrap();
+
+        // Entries come back in the same eviction order with their metadata
+        let mut expected = keys(&saved);
+        expected[0].1.retain(|key| key != "expired");
+        assert_eq!(keys(&loaded), expected);
+
+        let entry = loaded.get(DEFAULT_NAMESPACE).unwrap().get(key("k7")).unwrap();
+        assert_eq!(entry.value, Value::new(String::from("value 7")));
+        assert_eq!(entry.flags, 7);
+        assert_eq!(entry.tags, vec![String::from("tag")]);
+
+        loaded.get("tenant").unwrap().for_each(|shard| {
+            assert_eq!(shard.capacity, 256 * 1024);
+            assert_eq!(shard.item_lifetime, 60);
+            assert_eq!(shard.policy, "lfu");
+        });
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        let path = temp_path("not-a-snapshot");
+        fs::write(&path, b"RMCSNAP\0\0\0\0\x09").unwrap();
+        let result = load(&namespaces(1024), &path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+
+        assert!(load(&namespaces(1024), &temp_path("missing")).is_err());
+    }
+}
@@ -0,0 +1,29 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
+
+extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+extern "C" fn request(_signum: i32) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/**
+ * Turn SIGINT and SIGTERM into a shutdown request that the server polls for, so it can save its
+ * state before exiting instead of being killed on the spot
+ */
+pub fn install() {
+    unsafe {
+        signal(SIGINT, request);
+        signal(SIGTERM, request);
+    }
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}